@@ -70,8 +70,8 @@ esac
 # Update Variables
 INSTALL_PATH="$INSTALL_DIR/rpi_fanctrl"
 ENV_PATH="$INSTALL_DIR/.env"
-STAT_PATH="$INSTALL_DIR/stats.csv"
-LOG_PATH="$INSTALL_DIR/event.log"
+STAT_PATH="/var/lib/rpi_fanctrl/stats.csv"
+LOG_PATH="/var/log/rpi_fanctrl/event.log"
 UNINSTALL_SCRIPT="$INSTALL_DIR/uninstall.sh"
 
 # Update Bash wrapper script with correct uninstall script directory
@@ -115,11 +115,13 @@ echo "If you record logs and statistics, these will be also saved in the same fo
 echo "Creating .env file with default values..."
 sudo tee "$ENV_PATH" > /dev/null <<EOF
 gpio_pin=18
+temp_source=thermal_zone:0
 off_temp=45.0
 min_temp=48.0
 max_temp=70.0
 min_duty_cycle=0.20
 max_duty_cycle=1.00
+pwm_mode=auto
 pwm_freq=1000
 delay_hot=1.0
 delay_cold=10.0
@@ -195,10 +197,14 @@ Description=Raspberry Pi Fan Control
 After=multi-user.target
 
 [Service]
+Type=notify
+NotifyAccess=main
 ExecStart=$INSTALL_PATH
+ExecReload=/bin/kill -HUP \$MAINPID
 WorkingDirectory=$INSTALL_DIR
 Restart=on-failure
 RestartSec=10
+WatchdogSec=30
 User=root
 
 [Install]
@@ -0,0 +1,224 @@
+use csv::Writer;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::{thread, time::Duration};
+use crate::config::{save_file, save_var, Config};
+use crate::driver::FanDriver;
+use crate::functions::{create_dir, log_event, Level};
+use crate::tach::Tachometer;
+
+
+// Duty cycle steps of the sweep (5 percent).
+const STEP: f32 = 0.05;
+
+// Time the fan gets to settle after each step before its speed is measured.
+const SETTLE_TIME: Duration = Duration::from_secs(3);
+
+// Time over which the fan speed is measured (must be longer than the tachometer window).
+const MEASURE_TIME: Duration = Duration::from_millis(1500);
+
+// Margin added to the measured duty cycles, so that the fan still runs reliably when it is warm, dusty or worn.
+const MARGIN: f32 = 0.05;
+
+// File the measured fan speeds are written to, in the directory of the statistics file (by default /var/lib/rpi_fanctrl).
+const CALIBRATION_FILE: &str = "calibration.csv";
+
+
+// Calibrate the fan.
+// The duty cycle is swept from 0 to 100 percent and back down in steps of 5 percent.
+// On the way up, the lowest duty cycle at which the fan starts from standstill is recorded. On the way down, the lowest duty cycle at which the fan keeps spinning.
+// With a tachometer, the fan is considered spinning above min_rpm and the fan speed is recorded for every step. Without, the user is asked after every step.
+// The recommended settings are written to the configuration source in use: The .env file if one was loaded, otherwise the configuration file.
+// The previous file is kept as .env.bak or config.toml.bak.
+pub fn calibrate(driver: &mut dyn FanDriver, mut tach: Option<&mut Tachometer>, config: &Config) {
+    let (min_rpm, min_temp, max_temp) = (config.tach.stall.min_rpm, config.control.min_temp, config.control.max_temp);
+    let log_option = config.logging.log_option;
+    println!("Calibrating the fan. Make sure that the rpi_fanctrl service is stopped (sudo systemctl stop rpi_fanctrl).");
+    if tach.is_none() {
+        println!("No tachometer defined. After every step, enter 'y' if the fan is spinning and 'n' if it is not.");
+    }
+
+    // Make sure the fan is standing still before the sweep starts.
+    driver.off();
+    thread::sleep(SETTLE_TIME * 2);
+
+    let steps = (1.0 / STEP).round() as u32;
+    let mut start_duty_cycle: Option<f32> = None;
+    let mut sustain_duty_cycle: Option<f32> = None;
+    let mut sweep_up: Vec<(f32, Option<f32>)> = Vec::new(); // ...... (duty cycle, fan speed) on the way up.
+    let mut sweep_down: Vec<(f32, Option<f32>)> = Vec::new(); // .... (duty cycle, fan speed) on the way down.
+
+    // Sweep up.
+    for step in 0..=steps {
+        let duty_cycle = step as f32 * STEP;
+        let (spinning, rpm) = measure(driver, tach.as_deref_mut(), duty_cycle, min_rpm);
+        if spinning && start_duty_cycle.is_none() {
+            start_duty_cycle = Some(duty_cycle);
+        }
+        sweep_up.push((duty_cycle, rpm));
+    }
+
+    // Sweep down, until the fan stops.
+    for step in (0..=steps).rev() {
+        let duty_cycle = step as f32 * STEP;
+        let (spinning, rpm) = measure(driver, tach.as_deref_mut(), duty_cycle, min_rpm);
+        sweep_down.push((duty_cycle, rpm));
+        if !spinning {
+            break;
+        }
+        sustain_duty_cycle = Some(duty_cycle);
+    }
+    driver.off();
+
+    let (Some(start_duty_cycle), Some(sustain_duty_cycle)) = (start_duty_cycle, sustain_duty_cycle) else {
+        log_event(Level::Error, "Calibration failed: The fan did not spin at any duty cycle. Check the wiring and the GPIO pin.", log_option);
+        return;
+    };
+
+    // Write the measured fan speeds.
+    if tach.is_some() {
+        let calibration_file = Path::new(&config.stats.path).with_file_name(CALIBRATION_FILE);
+        write_sweep(&calibration_file, &sweep_up, &sweep_down, log_option);
+    }
+
+    // Recommended settings.
+    // The fan is run just above the lowest duty cycle at which it keeps spinning and kicked just above the duty cycle at which it starts.
+    let min_duty_cycle = (sustain_duty_cycle + MARGIN).min(1.0);
+    let kick_duty_cycle = (start_duty_cycle + 2.0 * MARGIN).min(1.0);
+    let max_rpm: Option<f32> = sweep_up.last().and_then(|&(_, rpm)| rpm);
+    let fan_curve = recommend_curve(&sweep_up, min_duty_cycle, min_temp, max_temp);
+
+    let mut settings: Vec<(&str, String)> = vec![
+        ("min_duty_cycle", format!("{:.2}", min_duty_cycle)),
+        ("kick_duty_cycle", format!("{:.2}", kick_duty_cycle)),
+        ("kick_time", "1.0".to_string()),
+        ("fan_curve", fan_curve),
+    ];
+    if let Some(max_rpm) = max_rpm {
+        settings.push(("fan_max_rpm", format!("{:.0}", max_rpm)));
+    }
+
+    println!();
+    println!("The fan starts at a duty cycle of {:.0} % and keeps spinning down to {:.0} %.", start_duty_cycle * 100.0, sustain_duty_cycle * 100.0);
+    println!("Recommended settings:");
+    for (key, value) in &settings {
+        println!("    {}={}", key, value);
+    }
+
+    // Environmental variables (including the .env file) take precedence over the configuration file, so the .env file is used if one was loaded.
+    let (file, result): (&PathBuf, Result<(), String>) = match &config.env_file {
+        Some(env_file) => (env_file, save_var(env_file, &settings).map_err(|e| e.to_string())),
+        None => (&config.config_file, save_file(&config.config_file, &settings)),
+    };
+    match result {
+        Ok(()) => log_event(Level::Info, &format!("Calibration finished. Settings saved to {} (previous file kept as .bak).", file.display()), log_option),
+        Err(e) => log_event(Level::Error, &format!("Calibration finished, but the settings could not be saved to {}: {}", file.display(), e), log_option),
+    }
+}
+
+
+// Set a duty cycle, let the fan settle and check whether it is spinning.
+// Returns whether the fan is spinning and the measured fan speed (if a tachometer is used).
+fn measure(driver: &mut dyn FanDriver, tach: Option<&mut Tachometer>, duty_cycle: f32, min_rpm: f32) -> (bool, Option<f32>) {
+    driver.set_duty(duty_cycle);
+    match tach {
+        Some(tach) => {
+            thread::sleep(SETTLE_TIME);
+            // Discard the measurement of the settling time and measure again.
+            tach.rpm();
+            thread::sleep(MEASURE_TIME);
+            let rpm = tach.rpm().unwrap_or(0.0);
+            println!("Duty cycle {:>3.0} %: {:>5.0} RPM", duty_cycle * 100.0, rpm);
+            (rpm >= min_rpm, Some(rpm))
+        }
+        None => (ask(&format!("Duty cycle {:>3.0} %: Is the fan spinning? [y/n] ", duty_cycle * 100.0)), None),
+    }
+}
+
+
+// Ask the user a yes/no question on the terminal.
+fn ask(question: &str) -> bool {
+    print!("{}", question);
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    let _ = io::stdin().lock().read_line(&mut answer);
+    answer.trim().to_lowercase().starts_with('y')
+}
+
+
+// Recommend a fan curve from min_temp to max_temp.
+// With measured fan speeds, the duty cycles are chosen so that the fan speed rises linearly with the temperature (most fans are not linear).
+// Without, the straight line from min_duty_cycle to full power is used.
+fn recommend_curve(sweep_up: &[(f32, Option<f32>)], min_duty_cycle: f32, min_temp: f32, max_temp: f32) -> String {
+    let points: Vec<(f32, f32)> = sweep_up.iter()
+        .filter_map(|&(duty_cycle, rpm)| rpm.map(|rpm| (duty_cycle, rpm)))
+        // The duty cycles are multiples of STEP that are not exact as floats, so they are compared with a tolerance of half a step.
+        .filter(|&(duty_cycle, _)| duty_cycle > min_duty_cycle - STEP / 2.0)
+        .collect();
+
+    let duty_cycles: Vec<f32> = match (points.first(), points.last()) {
+        (Some(&(_, min_rpm)), Some(&(_, max_rpm))) if max_rpm > min_rpm => (0..4).map(|k| {
+            // Find the duty cycle for the target fan speed by linear interpolation of the measurement.
+            let target = min_rpm + (max_rpm - min_rpm) * k as f32 / 3.0;
+            let mut duty_cycle = 1.0;
+            for pair in points.windows(2) {
+                let ((d0, r0), (d1, r1)) = (pair[0], pair[1]);
+                if target <= r1 && r1 > r0 {
+                    duty_cycle = d0 + (d1 - d0) * ((target - r0) / (r1 - r0)).clamp(0.0, 1.0);
+                    break;
+                }
+            }
+            duty_cycle
+        }).collect(),
+        _ => (0..4).map(|k| min_duty_cycle + (1.0 - min_duty_cycle) * k as f32 / 3.0).collect(),
+    };
+
+    // Make sure the duty cycles never decrease (the measurement may be noisy).
+    let mut prev = min_duty_cycle;
+    duty_cycles.iter().enumerate().map(|(k, &duty_cycle)| {
+        prev = duty_cycle.max(prev);
+        format!("{:.0}:{:.2}", min_temp + (max_temp - min_temp) * k as f32 / 3.0, prev)
+    }).collect::<Vec<String>>().join(", ")
+}
+
+
+// Write the measured fan speeds to the calibration file.
+fn write_sweep(calibration_file: &Path, sweep_up: &[(f32, Option<f32>)], sweep_down: &[(f32, Option<f32>)], log_option: bool) {
+    if let Err(e) = create_dir(&calibration_file.display().to_string()) {
+        return log_event(Level::Warn, &format!("Failed to write {}: {}", calibration_file.display(), e), log_option);
+    }
+    let result = Writer::from_path(calibration_file).and_then(|mut writer| {
+        writer.write_record(["direction", "duty_cycle", "rpm"])?;
+        for (direction, sweep) in [("up", sweep_up), ("down", sweep_down)] {
+            for &(duty_cycle, rpm) in sweep {
+                writer.write_record([direction.to_string(), format!("{:.2}", duty_cycle), format!("{:.0}", rpm.unwrap_or(0.0))])?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    });
+    if let Err(e) = result {
+        log_event(Level::Warn, &format!("Failed to write {}: {}", calibration_file.display(), e), log_option);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn curve_starts_at_min_duty_cycle() {
+        // The fan spins from 20 percent with a fan speed rising by 100 RPM per step.
+        let sweep_up: Vec<(f32, Option<f32>)> = (0..=20).map(|step| (step as f32 * STEP, Some(if step >= 4 {step as f32 * 100.0} else {0.0}))).collect();
+        // 6 * STEP + MARGIN is slightly above 7 * STEP as floats.
+        let min_duty_cycle = 6.0 * STEP + MARGIN;
+        assert_eq!(recommend_curve(&sweep_up, min_duty_cycle, 40.0, 70.0), "40:0.35, 50:0.57, 60:0.78, 70:1.00");
+    }
+
+    #[test]
+    fn linear_curve_without_fan_speeds() {
+        let sweep_up: Vec<(f32, Option<f32>)> = (0..=20).map(|step| (step as f32 * STEP, None)).collect();
+        assert_eq!(recommend_curve(&sweep_up, 0.4, 40.0, 70.0), "40:0.40, 50:0.60, 60:0.80, 70:1.00");
+    }
+}
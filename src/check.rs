@@ -0,0 +1,66 @@
+use std::path::{Path, PathBuf};
+use crate::config::check_var;
+
+
+// Check the configuration.
+// The configuration is loaded the same way as by the fan control, i.e. from the .env file and the configuration file (--config or the default path).
+// If a path is given, only this file is checked. Every setting is printed with the value that is used and where it came from,
+// followed by all invalid settings, settings that are not used (e.g. typos), settings that were adjusted and settings that use their default values.
+// Returns whether the configuration is valid, i.e. no setting is invalid or unknown.
+// With strict, settings that were adjusted or are expected in the .env file but not defined also make the configuration invalid.
+pub fn check_config(path: Option<&Path>, dir: &Option<PathBuf>, fallback_dirs: &[PathBuf], config_file: Option<&Path>, overrides: &[(String, String)], strict: bool) -> bool {
+    if let Some(path) = path.or(config_file).filter(|path| !path.exists()) {
+        println!("Error: {} does not exist.", path.display());
+        return false;
+    }
+
+    let (config, report) = check_var(path, dir, fallback_dirs, config_file, overrides);
+
+    println!();
+    println!("Settings (origin: cli = command line, profile = selected profile, env = environmental variable, .env = .env file, file = configuration file, default = not defined):");
+    let width = report.settings.iter().map(|setting| setting.key.len()).max().unwrap_or(0);
+    for setting in &report.settings {
+        println!("    {:<width$} = {:<24} ({}){}", setting.key, setting.value, setting.origin, if setting.invalid {" INVALID"} else {""}, width = width);
+    }
+
+    let errors = config.err().map(|e| e.errors).unwrap_or_default();
+    if !errors.is_empty() {
+        println!();
+        println!("Invalid settings (default values are used instead):");
+        for error in &errors {
+            println!("    '{}' {}", error.key, error.reason);
+        }
+    }
+
+    if !report.unknown.is_empty() {
+        println!();
+        println!("Unknown settings (not used, check for typos):");
+        for key in &report.unknown {
+            println!("    '{}'", key);
+        }
+    }
+
+    if !report.adjusted.is_empty() {
+        println!();
+        println!("Adjusted settings:");
+        for adjusted in &report.adjusted {
+            println!("    '{}' {}", adjusted.key, adjusted.reason);
+        }
+    }
+
+    if !report.missing.is_empty() {
+        println!();
+        println!("Not defined (default values are used): {}", report.missing.join(", "));
+    }
+
+    let valid = errors.is_empty() && report.unknown.is_empty() && !(strict && (!report.adjusted.is_empty() || !report.missing.is_empty()));
+    println!();
+    if valid {
+        println!("Configuration OK.");
+    } else if strict {
+        println!("Configuration has {} invalid, {} unknown, {} adjusted and {} missing setting(s).", errors.len(), report.unknown.len(), report.adjusted.len(), report.missing.len());
+    } else {
+        println!("Configuration has {} invalid and {} unknown setting(s).", errors.len(), report.unknown.len());
+    }
+    valid
+}
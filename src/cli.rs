@@ -0,0 +1,268 @@
+use std::path::PathBuf;
+
+
+// Settings that can be defined as environmental variables, in the .env file, in the configuration file or with --set.
+// Listed by --help. See the README for details.
+const SETTINGS: &[(&str, &str)] = &[
+    ("log_option", "Log events to the log file: true or false (default: true)"),
+    ("log_path", "Log file (default: /var/log/rpi_fanctrl/event.log)"),
+    ("log_max_size", "Size in KiB at which the log file is rotated, 0 to disable (default: 1024)"),
+    ("log_max_age", "Age in hours at which the log file is rotated, 0 to disable (default: 0)"),
+    ("log_keep", "Rotated log files that are kept (default: 5)"),
+    ("log_compress", "Compress rotated log files with gzip: true or false (default: false)"),
+    ("log_level", "Least important level of logged events: error, warn, info, debug or trace (default: info)"),
+    ("log_format", "Format of the log file: text or json (default: text)"),
+    ("log_backend", "Destination of logged events: file, journald or syslog (default: file)"),
+    ("gpio_pin", "GPIO pin of the PWM signal: 12, 13, 18 or 19 (default: 18)"),
+    ("pwm_mode", "PWM generation: auto, hardware, software, sysfs or mock (default: auto)"),
+    ("pwm_freq", "Frequency of the PWM signal in Hz: 1 to 1000000 (default: 1000)"),
+    ("pwm_chip", "PWM chip of the sysfs interface (default: 0)"),
+    ("pwm_channel", "PWM channel of the sysfs interface (default: 0)"),
+    ("off_temp", "Temperature in degree C below which the fan turns off (default: 45.0)"),
+    ("min_temp", "Temperature in degree C above which the fan turns on, at least off_temp (default: 50.0)"),
+    ("max_temp", "Temperature in degree C of full fan speed, at least min_temp (default: 70.0)"),
+    ("min_duty_cycle", "Minimum fan speed: 0.00 to 1.00 (default: 0.20)"),
+    ("max_duty_cycle", "Maximum fan speed: min_duty_cycle to 1.00 (default: 1.00)"),
+    ("kick_duty_cycle", "Fan speed of the kick-start: 0.00 to 1.00 (default: 1.00)"),
+    ("kick_time", "Duration of the kick-start in s, 0 to disable: 0.0 to 60.0 (default: 0, disabled)"),
+    ("temp_source", "Temperature source: thermal_zone:<n>, hwmon:<name>:<n>, file:<path>:<scale> or command:<cmd> (default: thermal_zone:0)"),
+    ("temp_sensors", "Comma separated list of sensor names, each defined by temp_source_<name> (default: none, temp_source is used)"),
+    ("temp_source_<name>", "Temperature source of the sensor, same format as temp_source"),
+    ("temp_weight_<name>", "Weight of the sensor for the weighted aggregation (default: 1.0)"),
+    ("temp_offset_<name>", "Offset added to the reading of the sensor in degree C (default: 0.0)"),
+    ("temp_min_<name>", "Own min_temp of the sensor for the normalized aggregation (default: min_temp)"),
+    ("temp_max_<name>", "Own max_temp of the sensor for the normalized aggregation (default: max_temp)"),
+    ("temp_aggregation", "Combination of several sensors: max, weighted or normalized (default: max)"),
+    ("temp_freq", "Temperature readings per s, a divisor of pwm_freq, 0 for every PWM period (default: 10)"),
+    ("delay_hot", "Dampening of rising temperatures in s (default: 1.0)"),
+    ("delay_cold", "Dampening of falling temperatures in s (default: 10.0)"),
+    ("delay_time", "Minimum time between turning the fan on and off in ms (default: 2000)"),
+    ("error_time", "Minimum time between error messages of failed temperature readings in s (default: 60)"),
+    ("control_mode", "Calculation of the fan speed: curve, pid or rpm (default: curve)"),
+    ("fan_curve", "Fan curve \"<temp>:<duty_cycle>, ...\" (default: linear from min_temp/min_duty_cycle to max_temp/max_duty_cycle)"),
+    ("curve_interpolation", "Interpolation of the fan curve: linear, smoothstep or cubic (default: linear)"),
+    ("pid_target", "Target temperature of the pid control mode in degree C (default: 55.0)"),
+    ("pid_kp", "Proportional gain of the pid control mode (default: 0.05)"),
+    ("pid_ki", "Integral gain of the pid control mode (default: 0.005)"),
+    ("pid_kd", "Derivative gain of the pid control mode (default: 0.0)"),
+    ("rpm_curve", "Target fan speeds of the rpm control mode \"<temp>:<rpm>, ...\" (default: fan_curve times fan_max_rpm)"),
+    ("rpm_kp", "Proportional gain of the rpm control mode (default: 0.0001)"),
+    ("rpm_ki", "Integral gain of the rpm control mode (default: 0.0002)"),
+    ("rpm_kd", "Derivative gain of the rpm control mode (default: 0.0)"),
+    ("tach_pin", "GPIO pin of the tach wire of the fan: 0 to 27 (default: none, the fan speed is not measured)"),
+    ("tach_ppr", "Tach pulses per fan revolution (default: 2)"),
+    ("fan_max_rpm", "Fan speed at full power in RPM, 0 if unknown (default: 0)"),
+    ("stall_rpm", "Fan speed in RPM below which a running fan is stalled (default: 100)"),
+    ("stall_ratio", "Share of the expected fan speed below which a running fan is stalled: 0.0 to 1.0 (default: 0.5)"),
+    ("stall_grace", "Time in s a fan may be too slow before it is stalled (default: 10)"),
+    ("stall_kick", "Duration in s a stalled fan is run at full power (default: 2)"),
+    ("stall_retries", "Kicks before a stalled fan is failed (default: 3)"),
+    ("stall_alert", "Command run when the fan failed (default: none)"),
+    ("stat_option", "Save statistics: true or false (default: true)"),
+    ("stat_path", "Statistics file (default: /var/lib/rpi_fanctrl/stats.csv)"),
+    ("stat_max_size", "Size in KiB at which the statistics file is rotated, 0 to disable (default: 1024)"),
+    ("stat_max_age", "Age in hours at which the statistics file is rotated, 0 to disable (default: 0)"),
+    ("stat_keep", "Rotated statistics files that are kept (default: 5)"),
+    ("stat_compress", "Compress rotated statistics files with gzip: true or false (default: false)"),
+    ("stat_freq", "Statistics calculations per s, a divisor of pwm_freq, 0 for every PWM period (default: 10)"),
+    ("stat_min_time", "Minimum time between saved statistics in s: 1 to 31536000 (default: 60)"),
+    ("stat_max_time", "Maximum time between saved statistics in s: stat_min_time to 31536000 (default: 3600)"),
+    ("stat_delta_t", "Temperature difference in degree C that saves statistics before stat_max_time (default: 5.0)"),
+    ("socket_option", "Create the control socket for rpi_fanctrl ctl: true or false (default: true)"),
+    ("socket_path", "Control socket (default: /run/rpi_fanctrl/control.sock)"),
+    ("override_time", "Default and maximum duration in s of a fan speed set with rpi_fanctrl ctl: 1 to 86400 (default: 600)"),
+    ("emergency_temp", "Temperature in degree C that cancels a fan speed set with rpi_fanctrl ctl, at least max_temp (default: 80.0)"),
+    ("metrics_option", "Serve metrics for Prometheus at http://<metrics_address>/metrics: true or false (default: false)"),
+    ("metrics_address", "IP address and port of the metrics endpoint, 0.0.0.0:9101 for every interface (default: 127.0.0.1:9101)"),
+    ("mqtt_option", "Publish the state to an MQTT broker: true or false (default: false)"),
+    ("mqtt_host", "Host of the MQTT broker (default: localhost)"),
+    ("mqtt_port", "Port of the MQTT broker: 1 to 65535 (default: 1883)"),
+    ("mqtt_user", "User name for the MQTT broker (default: none)"),
+    ("mqtt_password", "Password for the MQTT broker (default: none)"),
+    ("mqtt_client_id", "Client id for the MQTT broker (default: rpi_fanctrl_<hostname>)"),
+    ("mqtt_topic", "Base topic of the state and the commands (default: rpi_fanctrl/<hostname>)"),
+    ("mqtt_interval", "Time between publishing the state in s: 1 to 3600 (default: 10)"),
+    ("mqtt_discovery", "Publish Home Assistant discovery messages: true or false (default: true)"),
+    ("mqtt_discovery_prefix", "Discovery prefix of Home Assistant (default: homeassistant)"),
+    ("mqtt_device_name", "Name of the device in Home Assistant (default: hostname)"),
+    ("profile", "Profile of the configuration file ([profiles.<name>]) that is used (default: default)"),
+];
+
+
+// Command line arguments.
+#[derive(Clone, Debug, Default)]
+pub struct Args {
+    pub command: Option<String>, // ........... Subcommand (calibrate, check-config or ctl), none to run the fan control.
+    pub path: Option<PathBuf>, // .............. File to check (check-config only).
+    pub control: Vec<String>, // ............... Command sent to the control socket (ctl only).
+    pub strict: bool, // ....................... Exit with an error on any invalid, unknown, adjusted or undefined setting (check-config only).
+    pub config: Option<PathBuf>, // ............ Configuration file, instead of /etc/rpi_fanctrl/config.toml.
+    pub foreground: bool, // ................... Print every temperature reading and the fan speed to the terminal.
+    pub dry_run: bool, // ...................... Do not access the fan (the mock driver is used) and do not use the tachometer.
+    pub once: bool, // ......................... Run a single control cycle and exit.
+    pub overrides: Vec<(String, String)>, // ... Settings defined with --set, which override all other sources.
+}
+
+impl Args {
+
+    // Parse the command line arguments (without the name of the program).
+    // Returns an error message for unknown or incomplete arguments.
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Args, String> {
+        let mut parsed = Args::default();
+        while let Some(arg) = args.next() {
+            // Options with a value can be given as "--option value" or "--option=value".
+            let (option, inline_value) = match arg.split_once('=') {
+                Some((option, value)) if option.starts_with("--") => (option.to_string(), Some(value.to_string())),
+                _ => (arg.clone(), None),
+            };
+            let mut value = |name: &str| inline_value.clone().or_else(|| args.next()).ok_or_else(|| format!("'{}' requires a value", name));
+            match option.as_str() {
+                "--config" => parsed.config = Some(PathBuf::from(value("--config")?)),
+                "--stats-file" => parsed.overrides.push(("stat_path".to_string(), value("--stats-file")?)),
+                "--log-file" => parsed.overrides.push(("log_path".to_string(), value("--log-file")?)),
+                "--socket" => parsed.overrides.push(("socket_path".to_string(), value("--socket")?)),
+                "--set" => {
+                    let setting = value("--set")?;
+                    let (key, value) = setting.split_once('=').ok_or_else(|| format!("'--set {}' must be in the format key=value", setting))?;
+                    parsed.overrides.push((key.trim().to_string(), value.trim().to_string()));
+                }
+                "--foreground" => parsed.foreground = true,
+                "--dry-run" => parsed.dry_run = true,
+                "--once" => parsed.once = true,
+                "--strict" => parsed.strict = true,
+                "--help" | "-h" => parsed.command = Some("help".to_string()),
+                "calibrate" | "check-config" | "ctl" if parsed.command.is_none() => parsed.command = Some(option),
+                _ if parsed.command.as_deref() == Some("ctl") && !option.starts_with("--") => parsed.control.push(arg),
+                _ if parsed.command.as_deref() == Some("check-config") && parsed.path.is_none() && !option.starts_with('-') => parsed.path = Some(PathBuf::from(option)),
+                _ => return Err(format!("Unknown argument '{}'", arg)),
+            }
+        }
+
+        // Without access to the fan, the mock driver is used.
+        if parsed.dry_run {
+            parsed.overrides.push(("pwm_mode".to_string(), "mock".to_string()));
+        }
+
+        // A test run next to the running fan control must not take over its control socket, its metrics endpoint or its MQTT client
+        // and must not write to its log and statistics files. Events are only printed. The defaults are inserted first, so --set, --log-file
+        // and --stats-file still take precedence.
+        if parsed.dry_run || parsed.once {
+            let overridden = |key: &str| parsed.overrides.iter().any(|(k, _)| k == key);
+            let mut defaults: Vec<(String, String)> = ["socket_option", "metrics_option", "mqtt_option"].iter().map(|key| (key.to_string(), "false".to_string())).collect();
+            if !overridden("log_path") {
+                defaults.push(("log_option".to_string(), "false".to_string()));
+            }
+            if !overridden("stat_path") {
+                defaults.push(("stat_option".to_string(), "false".to_string()));
+            }
+            parsed.overrides.splice(0..0, defaults);
+        }
+        Ok(parsed)
+    }
+}
+
+
+// Print the usage and every setting.
+pub fn print_help() {
+    println!("Usage: rpi_fanctrl [OPTIONS]");
+    println!("       rpi_fanctrl calibrate [OPTIONS]");
+    println!("       rpi_fanctrl check-config [PATH] [--strict] [OPTIONS]");
+    println!("       rpi_fanctrl ctl <COMMAND> [--socket <PATH>]");
+    println!();
+    println!("Commands:");
+    println!("  calibrate ............... Measure the duty cycles at which the fan starts and keeps spinning and save the recommended settings");
+    println!("  check-config ............ Print every setting with its value and origin and list invalid and unknown settings");
+    println!("  ctl status .............. Print the current state of the running fan control as JSON");
+    println!("  ctl set-duty <D> [<S>] .. Run the fan at the duty cycle D (0.0 to 1.0) for S seconds (default: override_time) or until it is released");
+    println!("  ctl off [<S>] ........... Turn the fan off for S seconds (default: override_time) or until it is released");
+    println!("  ctl release ............. Return to the automatic control");
+    println!("  ctl reload .............. Reload the configuration (same as SIGHUP)");
+    println!("  ctl flush-stats ......... Save the current statistics period to the statistics file");
+    println!("  ctl profile <NAME> ...... Switch to a profile of the configuration file (default for none)");
+    println!();
+    println!("Options:");
+    println!("  --config <PATH> ......... Configuration file (default: /etc/rpi_fanctrl/config.toml or RPI_FANCTRL_CONFIG)");
+    println!("  --stats-file <PATH> ..... Statistics file (same as --set stat_path=<PATH>)");
+    println!("  --log-file <PATH> ....... Log file (same as --set log_path=<PATH>)");
+    println!("  --socket <PATH> ......... Control socket (same as --set socket_path=<PATH>)");
+    println!("  --set <KEY>=<VALUE> ..... Override a setting (can be used several times)");
+    println!("  --foreground ............ Print every temperature reading and the fan speed to the terminal");
+    println!("  --dry-run ............... Do not access the fan (same as --set pwm_mode=mock) and do not use the tachometer");
+    println!("  --once .................. Run a single control cycle and exit");
+    println!("                            With --dry-run or --once, the control socket, metrics and MQTT are disabled and the");
+    println!("                            log and statistics files are not written (unless set with --log-file or --stats-file)");
+    println!("  --strict ................ check-config: Exit with an error on any invalid or unknown setting, any setting that was");
+    println!("                            adjusted (e.g. temp_freq to a divisor of pwm_freq) and any setting expected in .env that is not defined");
+    println!("  -h, --help .............. Print this help");
+    println!();
+    println!("Settings (in order of precedence: --set, profile, environmental variables, .env file, configuration file):");
+    let width = SETTINGS.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+    for (key, description) in SETTINGS {
+        println!("  {:<width$}  {}", key, description, width = width);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    // Value of a setting as it is used by the configuration, i.e. the last override of the key.
+    fn setting<'a>(args: &'a Args, key: &str) -> Option<&'a str> {
+        args.overrides.iter().rev().find(|(k, _)| k == key).map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn option_values_inline_or_separate() {
+        let inline = parse(&["--config=/tmp/config.toml", "--stats-file=/tmp/stats.csv", "--set=min_temp=40"]).unwrap();
+        let separate = parse(&["--config", "/tmp/config.toml", "--stats-file", "/tmp/stats.csv", "--set", "min_temp=40"]).unwrap();
+        for args in [inline, separate] {
+            assert_eq!(args.config, Some(PathBuf::from("/tmp/config.toml")));
+            assert_eq!(args.overrides, [("stat_path".to_string(), "/tmp/stats.csv".to_string()), ("min_temp".to_string(), "40".to_string())]);
+        }
+        assert_eq!(parse(&["--config"]).unwrap_err(), "'--config' requires a value");
+        assert!(parse(&["--set", "min_temp"]).is_err());
+    }
+
+    #[test]
+    fn repeated_set() {
+        let args = parse(&["--set", "min_temp=40", "--set", " max_temp = 70 ", "--set", "min_temp=45"]).unwrap();
+        assert_eq!(args.overrides.len(), 3);
+        assert_eq!(setting(&args, "min_temp"), Some("45"));
+        assert_eq!(setting(&args, "max_temp"), Some("70"));
+    }
+
+    #[test]
+    fn subcommands() {
+        let args = parse(&["check-config", "/tmp/.env", "--strict"]).unwrap();
+        assert_eq!((args.command.as_deref(), args.path, args.strict), (Some("check-config"), Some(PathBuf::from("/tmp/.env")), true));
+
+        let args = parse(&["ctl", "set-duty", "0.5", "60", "--socket", "/tmp/rpi_fanctrl.sock"]).unwrap();
+        assert_eq!(args.command.as_deref(), Some("ctl"));
+        assert_eq!(args.control, ["set-duty", "0.5", "60"]);
+        assert_eq!(setting(&args, "socket_path"), Some("/tmp/rpi_fanctrl.sock"));
+
+        assert_eq!(parse(&["calibrate"]).unwrap().command.as_deref(), Some("calibrate"));
+        assert_eq!(parse(&["-h"]).unwrap().command.as_deref(), Some("help"));
+        assert!(parse(&["calibrate", "check-config"]).is_err());
+        assert_eq!(parse(&["--fast"]).unwrap_err(), "Unknown argument '--fast'");
+    }
+
+    #[test]
+    fn set_beats_the_defaults_of_a_test_run() {
+        let args = parse(&["--dry-run", "--once", "--set", "socket_option=true", "--log-file", "/tmp/event.log"]).unwrap();
+        assert_eq!(setting(&args, "pwm_mode"), Some("mock"));
+        assert_eq!(setting(&args, "socket_option"), Some("true"));
+        assert_eq!(setting(&args, "metrics_option"), Some("false"));
+        assert_eq!(setting(&args, "log_path"), Some("/tmp/event.log"));
+        assert_eq!(setting(&args, "log_option"), None);
+        assert_eq!(setting(&args, "stat_option"), Some("false"));
+
+        // Without --dry-run or --once, nothing is overridden.
+        assert!(parse(&["--foreground"]).unwrap().overrides.is_empty());
+    }
+}
@@ -1,71 +1,617 @@
-use std::{env, time::Duration};
-use std::time::SystemTime;
-use dotenvy::{dotenv, from_path};
-use std::path::PathBuf;
-use crate::functions::log_event;
-
-pub fn load_var(dir: &Option<std::path::PathBuf>, fallback_dirs: &Vec<PathBuf>)
-
-// Define output types.
--> (
-    bool, // .................................... log_option
-    u8, // ...................................... gpio_pin
-    f32, f32, f32, // ........................... off_temp, min_temp, max_temp
-    f32, f32, // ................................ min_duty_cycle, max_duty_cycle
-    u64, // ..................................... period_micros
-    u64, // ..................................... temp_cycle
-    f32, f32, f32, f32, // ...................... factor_hot_old, factor_hot_new, factor_cold_old, factor_cold_new
-    Duration, // ................................ delay_duration
-    Duration, // ................................ error_duration
-    bool, u64, u64, u64, f32, SystemTime, // .... stat_option, stat_cycle, stat_min_time, stat_max_time, stat_delta_t, stat_time_start
-    u64, u64, f32, f32, // ...................... i, i_stat, n_on_fan, n_on_temp
-    f32, f32, // ................................ duty_cycle, duty_cycle_old
-    Option<f32>, Option<f32>, Option<f32>, // ... cpu_temp_min, cpu_temp_max, cpu_temp_avg
-    f32, bool, // ............................... fan_state_stat, fan_state_prev
-    Option<f32>, Option<f32>, Option<f32>  // ... fan_speed_avg, fan_speed_min, fan_speed_max
-)
-
-// Function.
-{
+use std::{env, fmt, time::Duration};
+use std::str::FromStr;
+use std::ops::RangeInclusive;
+use dotenvy::from_path_iter;
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use crate::functions::{create_dir, gcd, hostname, Level, LogFormat};
+use crate::sensor::{Aggregation, SensorSpec, SourceSpec};
+use crate::curve::{FanCurve, Interpolation};
+use crate::pid::PidSettings;
+use crate::stall::StallSettings;
+use crate::driver::PwmMode;
+use crate::rotate::Rotation;
+use crate::log_backend::LogBackend;
+
+
+// Default path of the configuration file and the environmental variable to use another one.
+const CONFIG_FILE: &str = "/etc/rpi_fanctrl/config.toml";
+const CONFIG_ENV: &str = "RPI_FANCTRL_CONFIG";
+
+// Default paths of the log file and the statistics file.
+const LOG_PATH: &str = "/var/log/rpi_fanctrl/event.log";
+const STAT_PATH: &str = "/var/lib/rpi_fanctrl/stats.csv";
+
+// Default path of the control socket.
+const SOCKET_PATH: &str = "/run/rpi_fanctrl/control.sock";
+
+
+// Configuration of the fan control.
+// Loaded from the environmental variables (and the .env file) and the configuration file by load_var. On SIGHUP, a new configuration is loaded and swapped into the running control loop.
+// The runtime state of the control loop (see RuntimeState) is kept separately, so that it survives a reload.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub env_file: Option<PathBuf>, // ... Path of the loaded .env file.
+    pub config_file: PathBuf, // ........ Path of the configuration file (which may not exist).
+    pub logging: LoggingConfig,
+    pub pwm: PwmConfig,
+    pub sensor: SensorConfig,
+    pub tach: TachConfig,
+    pub control: ControlConfig,
+    pub stats: StatsConfig,
+    pub socket: SocketConfig,
+    pub metrics: MetricsConfig,
+    pub mqtt: MqttConfig,
+    pub profile: String, // ........... Name of the selected profile, "default" if none.
+    pub profiles: Vec<String>, // ..... Names of the profiles of the configuration file.
+}
+
+
+// Logging of events.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoggingConfig {
+    pub log_option: bool, // ... Whether events are written to the log file.
+    pub log_path: String,
+    pub rotation: Rotation,
+    pub level: Level, // ........ Least important level that is logged.
+    pub format: LogFormat, // ... Format of the log file.
+    pub backend: LogBackend, // ... file, journald or syslog.
+}
+
+
+// Generation of the PWM signal.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PwmConfig {
+    pub gpio_pin: u8,
+    pub mode: PwmMode,
+    pub chip: u32, // .............. Only used by the sysfs driver.
+    pub channel: u32, // ........... Only used by the sysfs driver.
+    pub freq: u64,
+    pub period_micros: u64, // ..... Duration of a single PWM period.
+}
+
+
+// Temperature sensors.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SensorConfig {
+    pub sensors: Vec<SensorSpec>,
+    pub aggregation: Aggregation,
+    pub temp_cycle: u64, // ........... Number of PWM periods between temperature readings.
+    pub error_duration: Duration, // ... Minimum duration between two error messages if the temperature cannot be read.
+}
+
+
+// Tachometer and stall detection.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TachConfig {
+    pub pin: Option<u8>, // ... No tachometer is used if none.
+    pub ppr: u32, // .......... Pulses per revolution.
+    pub stall: StallSettings,
+}
+
+
+// How the fan speed is calculated.
+// curve ... The fan curve maps the temperature to the duty cycle (open loop).
+// pid ..... A PID controller holds the temperature at pid_target (closed loop).
+// rpm ..... rpm_curve maps the temperature to a target fan speed, which is held by a PID controller using the measured fan speed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ControlMode {
+    Curve,
+    Pid,
+    Rpm,
+}
+
+impl FromStr for ControlMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode.trim().to_lowercase().as_str() {
+            "curve" => Ok(ControlMode::Curve),
+            "pid" => Ok(ControlMode::Pid),
+            "rpm" => Ok(ControlMode::Rpm),
+            _ => Err(format!("unknown control mode '{}', must be either curve, pid or rpm", mode)),
+        }
+    }
+}
+
+impl fmt::Display for ControlMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlMode::Curve => write!(f, "curve"),
+            ControlMode::Pid => write!(f, "pid"),
+            ControlMode::Rpm => write!(f, "rpm"),
+        }
+    }
+}
+
+
+// Calculation of the fan speed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ControlConfig {
+    pub mode: ControlMode,
+    pub off_temp: f32,
+    pub min_temp: f32,
+    pub max_temp: f32,
+    pub min_duty_cycle: f32,
+    pub max_duty_cycle: f32,
+    pub kick_duty_cycle: f32,
+    pub kick_duration: Duration,
+    pub fan_curve: FanCurve,
+    pub pid: PidSettings,
+    pub rpm_curve: FanCurve, // ......... Only used if mode is "rpm".
+    pub rpm_pid: PidSettings, // ........ Only used if mode is "rpm".
+    pub delay_duration: Duration, // .... Minimum duration between turning the fan on and off.
+    pub factor_hot_old: f32,
+    pub factor_hot_new: f32,
+    pub factor_cold_old: f32,
+    pub factor_cold_new: f32,
+}
+
+
+// Statistics.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StatsConfig {
+    pub stat_option: bool, // ... Whether statistics are calculated and saved.
+    pub path: String,
+    pub rotation: Rotation,
+    pub cycle: u64, // ......... Number of PWM periods between statistics calculations.
+    pub min_time: u64,
+    pub max_time: u64,
+    pub delta_t: f32,
+}
+
+
+// Control socket for runtime status and commands.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SocketConfig {
+    pub socket_option: bool, // ........ Whether the control socket is created.
+    pub path: String,
+    pub override_duration: Duration, // ... Default and maximum duration of a manually set fan speed.
+    pub emergency_temp: f32, // ......... Temperature in degree C that cancels a manually set fan speed.
+}
+
+
+// Metrics endpoint for Prometheus.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricsConfig {
+    pub metrics_option: bool, // ... Whether /metrics is served.
+    pub address: SocketAddr,
+}
+
+
+// Publishing to an MQTT broker, e.g. for Home Assistant.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MqttConfig {
+    pub mqtt_option: bool, // ........ Whether the state is published.
+    pub host: String,
+    pub port: u16,
+    pub user: Option<String>, // ..... No authentication if none.
+    pub password: Option<String>,
+    pub client_id: String,
+    pub topic: String, // ............ Base topic, e.g. rpi_fanctrl/<hostname>.
+    pub interval: Duration, // ....... Duration between publishing the state.
+    pub discovery: bool, // .......... Whether Home Assistant discovery messages are published.
+    pub discovery_prefix: String,
+    pub device_name: String, // ...... Name of the device in Home Assistant.
+}
+
+
+impl Config {
+
+    // Calculate the timing of the main loop.
+    // The PWM signal is kept running by the fan driver, so the loop only has to wake up when the temperature is read or statistics are calculated.
+    // A loop cycle therefore spans the largest number of PWM periods that divides both the temperature and the statistics cycle.
+    // Returns the duration of a loop cycle in microseconds and the number of loop cycles between temperature readings and statistics calculations.
+    pub fn loop_timing(&self) -> (u64, u64, u64) {
+        let temp_cycle = self.sensor.temp_cycle;
+        let loop_cycle: u64 = gcd(temp_cycle, if self.stats.stat_option {self.stats.cycle} else {temp_cycle});
+        (self.pwm.period_micros * loop_cycle, temp_cycle / loop_cycle, self.stats.cycle / loop_cycle)
+    }
+}
+
+
+// A single invalid setting and the reason why it is invalid.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyError {
+    pub key: String,
+    pub reason: String,
+}
+
+
+// All invalid settings found while loading the configuration.
+// Every invalid setting is replaced by its default value in the fallback configuration, so that the fan can still be controlled.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub errors: Vec<KeyError>,
+    pub fallback: Box<Config>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} invalid setting(s):", self.errors.len())?;
+        for error in &self.errors {
+            write!(f, "\n    '{}' {}", error.key, error.reason)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+
+// A setting as it was read, for checking the configuration.
+#[derive(Clone, Debug)]
+pub struct Setting {
+    pub key: String,
+    pub value: String, // ........... Value that is used (the raw value if it is invalid).
+    pub origin: &'static str, // .... Where the value came from: cli, profile, env, .env, file or default.
+    pub invalid: bool,
+}
+
+
+// Details of a loaded configuration, for checking it (see check_var).
+#[derive(Clone, Debug)]
+pub struct ConfigReport {
+    pub settings: Vec<Setting>, // ... Every setting that was read.
+    pub missing: Vec<String>, // ..... Settings expected in the .env file but not defined.
+    pub adjusted: Vec<KeyError>, // .. Settings whose value was adjusted to a usable one (e.g. temp_freq to a divisor of pwm_freq).
+    pub unknown: Vec<String>, // ..... Settings of the .env file, the configuration file or the command line that are not used (e.g. typos).
+}
+
+
+// Reads the settings from the environmental variables and the configuration file.
+// Instead of stopping at the first invalid setting, all invalid settings are collected and replaced by their default values.
+// Settings that are not defined at all simply use their default values, but the ones expected in the .env file are noted as missing.
+#[derive(Default)]
+struct Settings {
+    errors: Vec<KeyError>,
+    missing: Vec<String>,
+    adjusted: Vec<KeyError>,
+    env: HashMap<String, String>, // ........ Environmental variables of the process (see process_env).
+    file: HashMap<String, String>, // ....... Settings of the configuration file, by the name of the environmental variable.
+    env_file: HashMap<String, String>, // ... Settings of the .env file, which are layered below the environmental variables (the process environment is never changed).
+    overrides: HashMap<String, String>, // .. Settings of the command line (--set), which take precedence over all others.
+    profiles: BTreeMap<String, HashMap<String, String>>, // ... Settings of each profile of the configuration file ([profiles.<name>]).
+    profile: HashMap<String, String>, // .... Settings of the selected profile, which take precedence over the environmental variables.
+    read: Vec<Setting>, // .................. Every setting read so far, in the order they were read.
+}
+
+impl Settings {
+
+    // Get the raw value of a setting.
+    // The command line takes precedence over the selected profile, which takes precedence over environmental variables,
+    // which take precedence over the .env file, which takes precedence over the configuration file.
+    fn var(&mut self, key: &str) -> Option<String> {
+        let (value, origin) = match (self.env.get(key), self.env_file.get(key), self.file.get(key)) {
+            _ if self.overrides.contains_key(key) => (self.overrides.get(key).cloned(), "cli"),
+            _ if self.profile.contains_key(key) => (self.profile.get(key).cloned(), "profile"),
+            (Some(value), _, _) => (Some(value.clone()), "env"),
+            (None, Some(value), _) => (Some(value.clone()), ".env"),
+            (None, None, Some(value)) => (Some(value.clone()), "file"),
+            (None, None, None) => (None, "default"),
+        };
+        if !self.read.iter().any(|setting| setting.key == key) {
+            self.read.push(Setting { key: key.to_string(), value: value.clone().unwrap_or_default(), origin, invalid: false });
+        }
+        value
+    }
+
+    // Note the value that is used for a setting.
+    fn used<T: fmt::Display>(&mut self, key: &str, value: &T) {
+        if let Some(setting) = self.read.iter_mut().find(|setting| setting.key == key) {
+            setting.value = value.to_string();
+        }
+    }
+
+    // Read a setting that is expected in the .env file. If it is not defined, it is noted as missing and the default value is used.
+    fn get<T: FromStr + fmt::Display>(&mut self, key: &str, default: T, reason: &str) -> T {
+        if self.var(key).is_none() {
+            self.missing.push(key.to_string());
+        }
+        self.get_optional(key, default, reason)
+    }
+
+    // Read an optional setting. If it is not defined, the default value is used.
+    fn get_optional<T: FromStr + fmt::Display>(&mut self, key: &str, default: T, reason: &str) -> T {
+        let value = match self.var(key) {
+            Some(value) => value.trim().parse::<T>().unwrap_or_else(|_| self.invalid(key, reason, default)),
+            None => default,
+        };
+        self.used(key, &value);
+        value
+    }
+
+    // Read a setting that has to be within a range.
+    fn get_range<T: FromStr + PartialOrd + fmt::Display + Copy>(&mut self, key: &str, default: T, range: RangeInclusive<T>, expected: bool) -> T {
+        let reason = format!("must be a number between {} and {}", range.start(), range.end());
+        let value = if expected {self.get(key, default, &reason)} else {self.get_optional(key, default, &reason)};
+        if range.contains(&value) {value} else {
+            self.used(key, &default);
+            self.invalid(key, &reason, default)
+        }
+    }
+
+    // Read the optional rotation settings of a file, e.g. log_max_size, log_max_age, log_keep and log_compress.
+    // The size is defined in KiB (1 MiB as the default value) and the age in hours (disabled as the default value).
+    // By default 5 rotated files are kept uncompressed.
+    fn get_rotation(&mut self, prefix: &str) -> Rotation {
+        let max_size: u64 = self.get_optional(&format!("{}_max_size", prefix), 1024, "must be an integer (size in KiB, 0 to disable)");
+        let max_age: u64 = self.get_optional(&format!("{}_max_age", prefix), 0, "must be an integer (age in hours, 0 to disable)");
+        let keep: u32 = self.get_optional(&format!("{}_keep", prefix), 5, "must be an integer");
+        let compress: bool = self.get_optional(&format!("{}_compress", prefix), false, "must be true or false");
+        Rotation { max_size: max_size.saturating_mul(1024), max_age: max_age.saturating_mul(3600), keep, compress }
+    }
+
+    // Note an invalid setting and return the value used instead.
+    fn invalid<T>(&mut self, key: &str, reason: &str, fallback: T) -> T {
+        self.errors.push(KeyError { key: key.to_string(), reason: reason.to_string() });
+        if let Some(setting) = self.read.iter_mut().find(|setting| setting.key == key) {
+            setting.invalid = true;
+        }
+        fallback
+    }
+
+
+    // Note a setting whose value was adjusted to a usable one and return the adjusted value.
+    // Unlike an invalid setting, this is only a warning (the configuration can still be loaded or reloaded).
+    fn adjusted<T: fmt::Display>(&mut self, key: &str, reason: &str, value: T) -> T {
+        self.adjusted.push(KeyError { key: key.to_string(), reason: format!("{}, using {} instead", reason, value) });
+        self.used(key, &value);
+        value
+    }
+
+
+    // Settings of the configuration file, the .env file and the command line that were never read, e.g. due to a typo.
+    fn unknown(&self) -> Vec<String> {
+        let mut unknown: Vec<String> = self.file.keys().chain(self.env_file.keys()).chain(self.overrides.keys()).chain(self.profile.keys())
+            .filter(|key| key.as_str() != CONFIG_ENV && !self.read.iter().any(|setting| &setting.key == *key))
+            .cloned()
+            .collect();
+        unknown.sort();
+        unknown.dedup();
+        unknown
+    }
+
+
+    // Report of the settings that were read (see ConfigReport).
+    fn report(self) -> ConfigReport {
+        ConfigReport { unknown: self.unknown(), missing: self.missing, adjusted: self.adjusted, settings: self.read }
+    }
+
+
+    // Load the configuration file (TOML), if it exists.
+    // If the file cannot be read or parsed, this is noted as an invalid setting and only the environmental variables are used.
+    fn load_file(&mut self, config_file: &Path) {
+        if !config_file.exists() {
+            return;
+        }
+        match fs::read_to_string(config_file).map_err(|e| e.to_string()).and_then(|content| content.parse::<toml::Table>().map_err(|e| e.to_string())) {
+            Ok(table) => {
+                self.add_table(&table);
+                eprintln!("Configuration file loaded successfully from {}.", config_file.display());
+            }
+            Err(e) => self.invalid(&config_file.display().to_string(), &format!("could not be loaded: {}", e.trim()), ()),
+        }
+    }
+
+
+    // Add the settings of a table of the configuration file.
+    // Sections (e.g. [pwm] or [control.pid]) only group the settings, so every setting has the same name as its environmental variable.
+    // Only the list of sensors ([[sensors]]) and the profiles ([profiles.<name>]) are handled separately.
+    fn add_table(&mut self, table: &toml::Table) {
+        for (key, value) in table {
+            match value {
+                toml::Value::Table(profiles) if key == "profiles" => {
+                    for (name, profile) in profiles {
+                        match profile.as_table() {
+                            Some(profile) if name != "default" => {
+                                self.profiles.entry(name.clone()).or_default();
+                                self.add_profile(name, profile);
+                            }
+                            _ => self.invalid(&format!("profiles.{}", name), "must be a table and must not be named default", ()),
+                        }
+                    }
+                }
+                toml::Value::Table(section) => self.add_table(section),
+                toml::Value::Array(sensors) if key == "sensors" => sensors.iter().for_each(|sensor| self.add_sensor(sensor)),
+                value => match toml_value(value) {
+                    Ok(value) => self.add_value(key, value),
+                    Err(e) => self.invalid(key, &e, ()),
+                },
+            }
+        }
+    }
+
+
+    // Add a sensor of the configuration file.
+    // The settings of the sensor are mapped to temp_source_<name>, temp_weight_<name>, temp_offset_<name>, temp_min_<name> and temp_max_<name>.
+    fn add_sensor(&mut self, sensor: &toml::Value) {
+        let Some((name, sensor)) = sensor.as_table().and_then(|sensor| Some((sensor.get("name")?.as_str()?, sensor))) else {
+            return self.invalid("sensors", "every sensor must be a table with a name", ());
+        };
+        let sensors = self.file.entry("temp_sensors".to_string()).or_default();
+        *sensors = if sensors.is_empty() {name.to_string()} else {format!("{},{}", sensors, name)};
+        for (key, value) in sensor.iter().filter(|(key, _)| key.as_str() != "name") {
+            let key = match key.as_str() {
+                "source" => format!("temp_source_{}", name),
+                "weight" => format!("temp_weight_{}", name),
+                "offset" => format!("temp_offset_{}", name),
+                "min_temp" => format!("temp_min_{}", name),
+                "max_temp" => format!("temp_max_{}", name),
+                _ => {
+                    self.invalid(&format!("sensors.{}", key), "must be either name, source, weight, offset, min_temp or max_temp", ());
+                    continue;
+                }
+            };
+            match toml_value(value) {
+                Ok(value) => self.add_value(&key, value),
+                Err(e) => self.invalid(&key, &e, ()),
+            }
+        }
+    }
+
+
+    // Add the settings of a profile of the configuration file, e.g. [profiles.quiet] with max_duty_cycle = 0.6.
+    // Sections within a profile (e.g. [profiles.quiet.control]) only group the settings like the sections of the configuration file.
+    fn add_profile(&mut self, name: &str, table: &toml::Table) {
+        for (key, value) in table {
+            match value {
+                toml::Value::Table(section) => self.add_profile(name, section),
+                value => match toml_value(value) {
+                    Ok(value) => if self.profiles.entry(name.to_string()).or_default().insert(key.clone(), value).is_some() {
+                        self.invalid(&format!("profiles.{}.{}", name, key), "is defined more than once in the profile", ())
+                    },
+                    Err(e) => self.invalid(&format!("profiles.{}.{}", name, key), &e, ()),
+                },
+            }
+        }
+    }
+
+
+    // Add a single setting of the configuration file. Settings may only be defined once.
+    fn add_value(&mut self, key: &str, value: String) {
+        if self.file.insert(key.to_string(), value).is_some() {
+            self.invalid(key, "is defined more than once in the configuration file", ())
+        }
+    }
+}
+
+
+// Convert a value of the configuration file to the format of the environmental variables.
+// Lists of points (e.g. fan_curve = [[45, 0.3], [55, 0.4]]) become "45:0.3, 55:0.4", other lists (e.g. temp_sensors = ["cpu", "nvme"]) become "cpu, nvme".
+fn toml_value(value: &toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(value) => Ok(value.clone()),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Float(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        toml::Value::Array(items) => items.iter().map(|item| match item {
+            toml::Value::Array(point) if point.len() == 2 => Ok(format!("{}:{}", toml_value(&point[0])?, toml_value(&point[1])?)),
+            toml::Value::Array(_) => Err("must be a list of points [<temp>, <value>]".to_string()),
+            item => toml_value(item),
+        }).collect::<Result<Vec<String>, String>>().map(|items| items.join(", ")),
+        _ => Err("must be a string, a number, true or false or a list".to_string()),
+    }
+}
+
+
+// Load the configuration.
+// The .env file is read first (see load_env_file), then the configuration is read from the environmental variables, the .env file and the configuration file.
+// The configuration file is /etc/rpi_fanctrl/config.toml, unless another one is given (--config) or defined by RPI_FANCTRL_CONFIG.
+// Environmental variables (including the .env file) override the settings of the configuration file, the selected profile overrides both and the overrides of the command line (--set) override all of them.
+// Settings that are not defined use their default values.
+// If any setting is invalid, all invalid settings are returned as a ConfigError, which also contains the configuration with the default values in place of the invalid settings.
+// Settings expected in the .env file but not defined and settings that were adjusted are returned in the report.
+pub fn load_var(dir: &Option<std::path::PathBuf>, fallback_dirs: &[PathBuf], config_file: Option<&Path>, overrides: &[(String, String)]) -> (Result<Config, ConfigError>, ConfigReport) {
+    let (config, settings) = load_settings(dir, fallback_dirs, config_file, overrides);
+    (config, settings.report())
+}
+
+
+// Load the .env file and the configuration file (--config or the default path) and read the configuration, see load_var.
+fn load_settings(dir: &Option<std::path::PathBuf>, fallback_dirs: &[PathBuf], config_file: Option<&Path>, overrides: &[(String, String)]) -> (Result<Config, ConfigError>, Settings) {
+    let env = process_env();
+    let env_file = load_env_file(dir, fallback_dirs);
+    let config_file: PathBuf = config_file.map(Path::to_path_buf).unwrap_or_else(|| config_path(&env, env_file.as_ref()));
+    Config::from_env(env, env_file, &config_file, overrides)
+}
+
+
+// Environmental variables of the process.
+// They are read once and passed to Config::from_env like the .env file, so the configuration does not depend on the process environment otherwise.
+// Variables whose name or value is not valid unicode are left out.
+fn process_env() -> HashMap<String, String> {
+    env::vars_os().filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?))).collect()
+}
+
+
+// Load the configuration the same way as load_var (including --config), but also report where every setting came from (see check_config).
+// If a path is given, only this file is loaded: Either as the configuration file (*.toml) or as the .env file.
+pub fn check_var(path: Option<&Path>, dir: &Option<std::path::PathBuf>, fallback_dirs: &[PathBuf], config_file: Option<&Path>, overrides: &[(String, String)]) -> (Result<Config, ConfigError>, ConfigReport) {
+    let (config, settings) = match path {
+        Some(path) if path.extension().is_some_and(|extension| extension == "toml") => Config::from_env(process_env(), None, path, overrides),
+        Some(path) => match read_env_file(path) {
+            Ok(env_file) => Config::from_env(process_env(), Some(env_file), Path::new(""), overrides),
+            Err(e) => {
+                eprintln!("Failed to load .env file from {}: {}", path.display(), e);
+                Config::from_env(process_env(), None, Path::new(""), overrides)
+            }
+        },
+        None => load_settings(dir, fallback_dirs, config_file, overrides),
+    };
+    (config, settings.report())
+}
+
+
+// Path of the configuration file, defined by RPI_FANCTRL_CONFIG in the environment or the .env file.
+fn config_path(env: &HashMap<String, String>, env_file: Option<&EnvFile>) -> PathBuf {
+    env.get(CONFIG_ENV).cloned()
+        .or_else(|| env_file.and_then(|(_, settings)| settings.get(CONFIG_ENV).cloned()))
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(CONFIG_FILE))
+}
+
+
+// Path and settings of a .env file.
+type EnvFile = (PathBuf, HashMap<String, String>);
+
+
+// Read the settings of a .env file.
+// The file is only read into a map, the process environment is never changed (setting environmental variables is unsound while other threads run).
+fn read_env_file(path: &Path) -> Result<EnvFile, dotenvy::Error> {
+    let settings = from_path_iter(path)?.collect::<Result<HashMap<String, String>, dotenvy::Error>>()?;
+    Ok((path.to_path_buf(), settings))
+}
+
+
+// Read the .env file (settings of the process environment take precedence over it, see Settings::var).
+// The .env file is searched in the working directory (and its parents), then in the directory of the binary and then in the fallback directories.
+// Returns the path and the settings of the loaded file.
+fn load_env_file(dir: &Option<std::path::PathBuf>, fallback_dirs: &[PathBuf]) -> Option<EnvFile> {
     // implement this to try to get the .env working after moving to systemd folder?
     // https://docs.rs/dotenv/latest/dotenv/fn.from_path.html
 
     // Load .env file.
     
     let mut dotenv_success = false;
+    let mut env_file: Option<EnvFile> = None; // ... Path and settings of the loaded .env file.
 
     if !dotenv_success {
 
-        let dotenv_result = dotenv();
-        match dotenv_result {
-            Ok(_) => {
-                println!(".env file loaded successfully using 'dotenv()'.");
+        let env_path: Option<PathBuf> = env::current_dir().ok().and_then(|cwd| cwd.ancestors().map(|path| path.join(".env")).find(|path| path.is_file()));
+        match env_path.as_deref().map(read_env_file) {
+            Some(Ok(result)) => {
+                eprintln!(".env file loaded successfully from {}.", result.0.display());
                 dotenv_success = true;
+                env_file = Some(result);
             }
-            Err(e) => {
-                println!("Failed to load .env file using 'dotenv()': {}", e);
+            Some(Err(e)) => {
+                eprintln!("Failed to load .env file from the working directory: {}", e);
+            }
+            None => {
+                eprintln!("Failed to load .env file from the working directory: not found");
             }
         }
     }
 
     if !dotenv_success {
-        match dir {
-            Some(path) => {
-                let env_path: PathBuf = path.join(".env");
-                if env_path.exists() {
-                    let dotenv_result = from_path(&env_path);
-                    match dotenv_result {
-                        Ok(_) => {
-                            println!(".env file loaded successfully from {}.", path.display());
-                            dotenv_success = true;
-                        }
-                        Err(e) => {
-                            println!("Failed to load .env file from {}: {}", path.display(), e);
-                        }
+        if let Some(path) = dir {
+            let env_path: PathBuf = path.join(".env");
+            if env_path.exists() {
+                let dotenv_result = read_env_file(&env_path);
+                match dotenv_result {
+                    Ok(result) => {
+                        eprintln!(".env file loaded successfully from {}.", path.display());
+                        dotenv_success = true;
+                        env_file = Some(result);
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to load .env file from {}: {}", path.display(), e);
                     }
-                } else {}
+                }
             }
-            None => {}
         }
     }
 
@@ -75,15 +621,16 @@ pub fn load_var(dir: &Option<std::path::PathBuf>, fallback_dirs: &Vec<PathBuf>)
             let env_path: PathBuf = path.join(".env");
             // Check if the restart script is located in the directory.
             if env_path.exists() {
-                let dotenv_result = from_path(&env_path);
+                let dotenv_result = read_env_file(&env_path);
                 match dotenv_result {
-                    Ok(_) => {
-                        println!(".env file loaded successfully from {}.", path.display());
+                    Ok(result) => {
+                        eprintln!(".env file loaded successfully from {}.", path.display());
                         dotenv_success = true;
+                        env_file = Some(result);
                         break;
                     }
                     Err(e) => {
-                        println!("Failed to load .env file from {}: {}", path.display(), e);
+                        eprintln!("Failed to load .env file from {}: {}", path.display(), e);
                     }
                 }
             }
@@ -91,286 +638,705 @@ pub fn load_var(dir: &Option<std::path::PathBuf>, fallback_dirs: &Vec<PathBuf>)
     }
 
     if !dotenv_success {
-        println!("Failed to load .env file. Fallback values for environmental variables will be used.");
-    }
-
-
-    // Define whether errors should be logged or not.
-    let log_option: bool = env::var("log_option")
-        .unwrap_or_else(|_| {"true".to_string()})
-        .parse::<bool>()
-        .ok()
-        .filter(|&logoption| [true, false].contains(&logoption))
-        .unwrap_or_else(|| {true});
-
-
-    // Define the GPIO pin used for PWM control. The pin has to be either 12, 13, 18 or 19 with 18 as the default.
-    let gpio_pin: u8 = env::var("gpio_pin")
-        .unwrap_or_else(|_| {log_event("Warning: 'gpio_pin' not found in .env file. Using default: 18", log_option); "18".to_string()})
-        .parse::<u8>()
-        .ok()
-        .filter(|&gpiopin| [12, 13, 18, 19].contains(&gpiopin))
-        .unwrap_or_else(|| {log_event("Warning: Incorrect 'gpio_pin' defined, must be either 12, 13, 18 or 19. Using default: 18", log_option); 18});
-
-
-    // Define the temperature where the fan turns off.
-    // The default value is 45.0 degree C.
-    let off_temp: f32 = env::var("off_temp")
-        .unwrap_or_else(|_| {log_event("Warning: 'off_temp' not found in .env file. Using default: 45.0", log_option); "45.0".to_string()})
-        .parse::<f32>()
-        .unwrap_or_else(|_| {log_event("Warning: Incorrect 'off_temp' defined, must be a number. Using default: 45.0", log_option); 45.0});
-
-
-    // Define the temperature where the fan turns on.
-    // The minimum temperature has to be at least equal to the off temperature with 50.0 degree C as the default value.
-    let min_temp: f32 = env::var("min_temp")
-        .unwrap_or_else(|_| {log_event("Warning: 'min_temp' not found in .env file. Using default: 50.0", log_option); "50.0".to_string()})
-        .parse::<f32>()
-        .unwrap_or_else(|_| {log_event("Warning: Incorrect 'min_temp' defined, must be a number. Using default: 50.0", log_option); 50.0})
-        .max(off_temp);
-
-
-    // Define the temperature where the fan reaches full speed.
-    // The maximum temperature has to be at least equal to the minimum temperature with 70.0 degree C as the default value.
-        let max_temp: f32 = env::var("max_temp")
-        .unwrap_or_else(|_| {log_event("Warning: 'max_temp' not found in .env file. Using default: 70.0", log_option); "70.0".to_string()})
-        .parse::<f32>()
-        .unwrap_or_else(|_| {log_event("Warning: Incorrect 'max_temp' defined, must be a number. Using default: 70.0", log_option);70.0})
-        .max(min_temp);
-
-
-    // Define the minimum speed at which the fan will run.
-    // The minimum fan speed has to be between 0.00 (0 percent) and 1.00 (100 percent) with 0.20 (20 percent) as the default value.
-    let min_duty_cycle: f32 = env::var("min_duty_cycle")
-        .unwrap_or_else(|_| {log_event("Warning: 'min_duty_cycle' not found in .env file. Using default: 0.20", log_option); "0.20".to_string()})
-        .parse::<f32>()
-        .unwrap_or_else(|_| {log_event("Warning: Incorrect 'min_duty_cycle' defined, must be a number. Using default: 0.20", log_option); 0.20})
-        .max(0.00 as f32)
-        .min(1.00 as f32);
-
-
-    // Define the maximum speed at which the fan will run.
-    // The maximum fan speed has to be between the minimum fan speed and 1.00 (100 percent) with 1.00 (100 percent) as the default value.
-    let max_duty_cycle: f32 = env::var("max_duty_cycle")
-        .unwrap_or_else(|_| {log_event("Warning: 'max_duty_cycle' not found in .env file. Using default: 1.00", log_option); "1.00".to_string()})
-        .parse::<f32>()
-        .unwrap_or_else(|_| {log_event("Warning: Incorrect 'max_duty_cycle' defined, must be a number. Using default: 1.00", log_option); 1.00})
-        .max(min_duty_cycle)
-        .min(1.00 as f32);
-
-
-    // Define the frequency of the PWM signal.
-    // The frequency has to be between 1 Hz and 1 MHz (due to further calculations and functions used) with 1 kHz as the default value.
-    let pwm_freq: u64 = env::var("pwm_freq")
-        .unwrap_or_else(|_| {log_event("Warning: 'pwm_freq' not found in .env file. Using default: 1000", log_option); "1000".to_string()})
-        .parse::<u64>()
-        .unwrap_or_else(|_| {log_event("Warning: Incorrect 'pwm_freq' defined, must be an integer . Using default: 1000", log_option); 1000})
-        .max(1 as u64)
-        .min(1000000 as u64);
-
-
-    // Calculate the duration of a single PWM period in microseconds.
-    let period_micros = 1000000 / pwm_freq as u64;
-
-
-    // Define how often the CPU temperature should be checked.
-    // The frequency has to be between 1 Hz and 1 MHz (due to further calculations and functions used).
-    // It also has to be smaller than and a divisor of the PWM frequency with 10 Hz as the default value.
-    let temp_freq: u64 = env::var("temp_freq")
-        .unwrap_or_else(|_| {log_event("Warning: 'temp_freq' not found in .env file. Using default: 10", log_option); "10".to_string()})
-        .parse::<u64>()
-        .unwrap_or_else(|_| {log_event("Warning: Incorrect 'temp_freq' defined, must be an integer number. Using default: 10", log_option); 10})
-        .max(0 as u64)
-        .min(pwm_freq)
-        .min(1000000 as u64);
-    // If temp_freq is off (i.e. 0), the temperature should be read for every PWM cycle.
-    let temp_freq = if temp_freq == 0 {
-        pwm_freq
-    // Ensure minimum frequency of 1 Hz.
-    } else if temp_freq < 1 {
-        1
-    // Ensure that temp_freq is a divisor of pwm_freq, or adjust it to be the largest divisor.
-    } else if pwm_freq % temp_freq != 0 {
-        log_event("Warning: 'temp_freq' is not a divisor of 'pwm_freq'. Using largest valid divisor.", log_option);
-        (1..=temp_freq).rev().find(|&i| pwm_freq % i == 0).unwrap_or(1)
-    } else {temp_freq};
-
-
-    // Calculate how many PWM cycles should pass before the temperature is measured each time.
-    let temp_cycle: u64 = pwm_freq / temp_freq;
-
-
-    // Define the dampening delay for rising temperatures.
-    // The minimum delay is a single temperature reading period (in seconds). In this case, no delay will be applied. The default value is 1.0 s.
-    let delay_hot: f32 = env::var("delay_hot")
-        .unwrap_or_else(|_| {log_event("Warning: 'delay_hot' not found in .env file. Using default: 1.0", log_option); "1.0".to_string()})
-        .parse::<f32>()
-        .unwrap_or_else(|_| {log_event("Warning: Incorrect 'delay_hot' defined, must be a number. Using default: 1.0", log_option); 1.0})
-        .max(1.0 / temp_cycle as f32);
-
-
-    // Define the dampening delay for falling temperatures.
-    // The minimum delay is a single temperature reading period (in seconds). In this case, no delay will be applied. The default value is 10.0 s.
-    let delay_cold: f32 = env::var("delay_cold")
-        .unwrap_or_else(|_| {log_event("Warning: 'delay_cold' not found in .env file. Using default: 10.0", log_option); "10.0".to_string()})
-        .parse::<f32>()
-        .unwrap_or_else(|_| {log_event("Warning: Incorrect 'delay_cold' defined, must be a number. Using default: 10.0", log_option); 10.0})
-        .max(1.0 / temp_cycle as f32);
-
-
-    // Calculate the factors to dampen fan speed changes.
-    // If the delay is at or below a single PWM period, no dampening will be applied.
-    let factor_hot_old = if delay_hot > 1.0 / (temp_freq  as f32) {
-        1.0 - 1.0 / (temp_freq as f32 * delay_hot)
-    } else {0.0 as f32};
-
-    let factor_hot_new = if delay_hot > 1.0 / (temp_freq as f32) {
-        1.0 / (temp_freq as f32 * delay_hot)
-    } else {1.0 as f32};
-
-    let factor_cold_old = if delay_cold > 1.0 / (temp_freq as f32) {
-        1.0 - 1.0 / (temp_freq as f32 * delay_cold)
-    } else {0.0 as f32};
-
-    let factor_cold_new = if delay_cold > 1.0 / (temp_freq as f32) {
-        1.0 / (temp_freq as f32 * delay_cold)
-    } else {1.0 as f32};
-
-
-    // Define the minimum duration between turning the fan on or off.
-    // The minimum duration is 0 ms with 2000 ms as the default value.
-    let delay_time: u64 =  env::var("delay_time")
-        .unwrap_or_else(|_| {log_event("Warning: 'delay_time' not found in .env file. Using default: 2000", log_option); "2000".to_string()})
-        .parse::<u64>()
-        .unwrap_or_else(|_| {log_event("Warning: Incorrect 'delay_time' defined, must be an integer. Using default: 2000", log_option); 2000})
-        .max(0 as u64);
-
-
-    // Convert delay_time to an actual duration.
-    let delay_duration: Duration = Duration::from_millis(delay_time);
-
-
-    // Define the minimum duration between error message outputs if no cpu temperature can be determined.
-    // The minimum duration is 0 s with 60 s as the default value.
-    let error_time: u64 = env::var("error_time")
-        .unwrap_or_else(|_| {log_event("Warning: 'error_time' not found in .env file. Using default: 60", log_option); "60".to_string()})
-        .parse::<u64>()
-        .unwrap_or_else(|_| {log_event("Warning: Incorrect 'error_time' defined, must be an integer. Using default: 60", log_option); 60})
-        .max(0 as u64);
-
-
-    // Convert error_time to an actual duration.
-    let error_duration: Duration = Duration::from_secs(error_time);
-
-
-    // Define whether statistics should be logged or not.
-    let stat_option: bool = env::var("stat_option")
-        .unwrap_or_else(|_| {log_event("Warning: 'stat_option' not found in .env file. Using default: true", log_option); "true".to_string()})
-        .parse::<bool>()
-        .ok()
-        .filter(|&logoption| [true, false].contains(&logoption))
-        .unwrap_or_else(|| {log_event("Warning: Incorrect 'stat_freq' defined, must be true or false. Using default: true", log_option); true});
-
-
-    // Define how often statistics should be calculated.
-    // The frequency has to be between 1 Hz and 1 MHz (due to further calculations and functions used).
-    // It also has to be smaller than and a divisor of the PWM frequency with 10 Hz as the default value.
-    let stat_freq: u64 = env::var("stat_freq")
-        .unwrap_or_else(|_| {log_event("Warning: 'stat_freq' not found in .env file. Using default: 10", log_option); "10".to_string()})
-        .parse::<u64>()
-        .unwrap_or_else(|_| {log_event("Warning: Incorrect 'stat_freq' defined, must be an integer number. Using default: 10", log_option); 10})
-        .max(0 as u64)
-        .min(pwm_freq)
-        .min(1000000 as u64);
-    // If stat_freq is off (i.e. 0), the statistics should be calculated for every PWM cycle.
-    let stat_freq = if stat_freq == 0 {
-        pwm_freq
-    // Ensure minimum frequency of 1 Hz.
-    } else if stat_freq < 1 {
-        1
-    // Ensure that stat_freq is a divisor of pwm_freq, or adjust it to be the largest divisor.
-    } else if pwm_freq % stat_freq != 0 {
-        log_event("Warning: 'stat_freq' is not a divisor of 'pwm_freq'. Using largest valid divisor.", log_option);
-        (1..=stat_freq).rev().find(|&i| pwm_freq % i == 0).unwrap_or(1)
-    } else {stat_freq};
-    // Calculate how many PWM cycles should pass before statistics are calculated each time.
-    let stat_cycle: u64 = pwm_freq / stat_freq;
-
-
-    // Define the minimum duration after which statistics are saved.
-    // The value must be a whole number (integer) representing a duration in seconds, ranging from 1 second to ~ 1 year with 60s as the default value. 
-    let stat_min_time: u64 = env::var("stat_min_time")
-        .unwrap_or_else(|_| {log_event("Warning: 'stat_min_time' not found in .env file. Using default: 60", log_option); "60".to_string()})
-        .parse::<u64>()
-        .unwrap_or_else(|_| {log_event("Warning: Incorrect 'stat_min_time' defined, must be an integer . Using default: 60", log_option); 60})
-        .max(1 as u64)
-        .min(31536000 as u64);
-
-
-    // Define the maximum duration after which statistics are saved.
-    // The value must be a whole number (integer) representing a duration in seconds, ranging from 1 second to ~ 1 year and at least as long as the minimum duration.
-    // The default value is 3600s. 
-    let stat_max_time: u64 = env::var("stat_max_time")
-        .unwrap_or_else(|_| {log_event("Warning: 'stat_max_time' not found in .env file. Using default: 3600", log_option); "3600".to_string()})
-        .parse::<u64>()
-        .unwrap_or_else(|_| {log_event("Warning: Incorrect 'stat_max_time' defined, must be an integer . Using default: 3600", log_option); 3600})
-        .max(1 as u64)
-        .min(31536000 as u64)
-        .max(stat_min_time);
-
-
-    // Define the minimum difference in min and max temperatures for statistics to be saved before the max duration.
-    let stat_delta_t: f32 = env::var("stat_delta_t")
-        .unwrap_or_else(|_| {log_event("Warning: 'stat_delta_t' not found in .env file. Using default: 5.0", log_option); "5.0".to_string()})
-        .parse::<f32>()
-        .unwrap_or_else(|_| {log_event("Warning: Incorrect 'stat_delta_t' defined, must be a number. Using default: 5.0", log_option); 5.0})
-        .max(0.0);
-
-
-    // Define different integers for iterations.
-    let i: u64 = 1; // ............. Counts the total loops of the main function (i.e. +=1 with every PWM cycle).
-                                  // Together with stat_cycle and temp_cycle used to determine when temperatures should be read and when statistics should be calculated.
-    let i_stat: u64 = 1; // ........ Used to count how often statistics where calculated. Used to calculate the on time of the fan.
-    let n_on_fan: f32 = 0.0; // .... Used to count how often the fan was running when calculating statistics.
-                                  // Average fan speed is only calculated for the number of times the fan was running.
-    let n_on_temp: f32 = 0.0; // ... Used to count the number of valid temperature readings for statistics. Average is only calculated for the number of valid readings.
-
-
-    // Define the variable for the fan speed and set it to the max cycle to initially keep the fan to full power.
-    let duty_cycle: f32 = max_duty_cycle;
-    let duty_cycle_old: f32 = max_duty_cycle;
-
-
-    // Define the statistics variables.
-    let cpu_temp_min: Option<f32> = None;
-    let cpu_temp_max: Option<f32> = None;
-    let cpu_temp_avg: Option<f32> = None;
-    let fan_state_stat: f32 = 1.0;
-    let fan_state_prev: bool = false;
-    let fan_speed_avg: Option<f32> = None;
-    let fan_speed_min: Option<f32> = None;
-    let fan_speed_max: Option<f32> = None;
-    let stat_time_start = SystemTime::now();
-    
+        eprintln!("Failed to load .env file. Fallback values for environmental variables will be used.");
+    }
+
+    env_file
+}
+
+
+impl Config {
+
+    // Configuration of the given settings with default values for the rest (without .env and configuration file).
+    // Used by the tests of other modules.
+    #[cfg(test)]
+    pub fn from_settings(settings: &[(&str, &str)]) -> Config {
+        let overrides: Vec<(String, String)> = settings.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+        Config::from_env(HashMap::new(), None, Path::new(""), &overrides).0.unwrap()
+    }
+
+    // Read the configuration from the environmental variables (env), the .env file and the configuration file.
+    // Returns the configuration (or all invalid settings) and the settings as they were read.
+    fn from_env(env: HashMap<String, String>, env_file: Option<EnvFile>, config_file: &Path, overrides: &[(String, String)]) -> (Result<Config, ConfigError>, Settings) {
+        let mut s = Settings { env, overrides: overrides.iter().cloned().collect(), ..Default::default() };
+        s.load_file(config_file);
+        let env_file: Option<PathBuf> = env_file.map(|(path, settings)| {
+            s.env_file = settings;
+            path
+        });
+
+
+        // Define the profile of the configuration file that is used, e.g. "quiet" for [profiles.quiet].
+        // The settings of the profile take precedence over the environmental variables and the rest of the configuration file. The default value is "default" (no profile).
+        let profile: String = s.get_optional("profile", "default".to_string(), "").trim().to_string();
+        let profile: String = match s.profiles.get(&profile) {
+            Some(settings) => {
+                s.profile = settings.clone();
+                profile
+            }
+            None if profile == "default" => profile,
+            None => s.invalid("profile", "must be either default or the name of a profile of the configuration file", "default".to_string()),
+        };
+        s.used("profile", &profile);
+        let profiles: Vec<String> = s.profiles.keys().cloned().collect();
+
+
+        // Define whether errors should be logged or not.
+        let log_option: bool = s.get_optional("log_option", true, "must be true or false");
+
+
+        // Define the log file.
+        // The default value is /var/log/rpi_fanctrl/event.log. The directory is created if it does not exist.
+        let log_path: String = s.get_optional("log_path", LOG_PATH.to_string(), "");
+        let log_path: String = if !log_path.trim().is_empty() {log_path} else {s.invalid("log_path", "must not be empty", LOG_PATH.to_string())};
+
+
+        // Define the rotation of the log file (log_max_size, log_max_age, log_keep and log_compress).
+        let log_rotation: Rotation = s.get_rotation("log");
+
+
+        // Define the least important level of events that are logged: error, warn, info, debug or trace with info as the default value.
+        // At debug level, every control cycle is traced. The level also applies to the terminal.
+        let log_level: Level = s.get_optional("log_level", Level::Info, "must be either error, warn, info, debug or trace");
+
+
+        // Define the format of the log file: text or json (one JSON object per line) with text as the default value.
+        let log_format: LogFormat = s.get_optional("log_format", LogFormat::Text, "must be either text or json");
+
+
+        // Define where events are logged: file (the log file), journald (the systemd journal) or syslog with file as the default value.
+        let log_backend: LogBackend = s.get_optional("log_backend", LogBackend::File, "must be either file, journald or syslog");
+
+
+        // Define the GPIO pin used for PWM control. The pin has to be either 12, 13, 18 or 19 with 18 as the default.
+        let gpio_pin: u8 = s.get("gpio_pin", 18, "must be either 12, 13, 18 or 19");
+        let gpio_pin: u8 = if [12, 13, 18, 19].contains(&gpio_pin) {gpio_pin} else {s.invalid("gpio_pin", "must be either 12, 13, 18 or 19", 18)};
+        s.used("gpio_pin", &gpio_pin);
+
+
+        // Define the GPIO pin connected to the tach wire of the fan (optional).
+        // Any GPIO pin can be used except for the PWM pin. If no pin is defined, the fan speed is not measured.
+        let tach_pin: Option<u8> = match s.var("tach_pin").filter(|tachpin| !tachpin.trim().is_empty()) {
+            Some(tachpin) => match tachpin.trim().parse::<u8>() {
+                Ok(tachpin) if tachpin <= 27 && tachpin != gpio_pin => Some(tachpin),
+                _ => s.invalid("tach_pin", "must be a GPIO pin between 0 and 27 other than 'gpio_pin'", None),
+            },
+            None => None,
+        };
+
+
+        // Define the number of tach pulses per fan revolution.
+        // Most PC style fans (including the Raspberry Pi Active Cooler) output 2 pulses per revolution, which is the default value.
+        let tach_ppr: u32 = s.get_range("tach_ppr", 2, 1..=100, false);
+
+
+        // Define the stall detection (only used if a tach pin is defined).
+        // A running fan is considered stalled if its speed is below stall_rpm, or below stall_ratio times the expected speed for the duty cycle
+        // (fan_max_rpm times the duty cycle, only if fan_max_rpm is defined), for longer than stall_grace seconds.
+        // A stalled fan is kicked at full power for stall_kick seconds up to stall_retries times. If it is still stalled, stall_alert is run.
+        // The default values are 100 RPM, no expected speed, a ratio of 0.5, a grace period of 10 seconds, a kick of 2 seconds, 3 retries and no alert.
+        let stall = StallSettings {
+            min_rpm: s.get_range("stall_rpm", 100.0, 0.0..=100_000.0, false),
+            max_rpm: s.get_range("fan_max_rpm", 0.0, 0.0..=100_000.0, false),
+            ratio: s.get_range("stall_ratio", 0.5, 0.0..=1.0, false),
+            grace: Duration::from_secs(s.get_optional("stall_grace", 10, "must be an integer")),
+            kick: Duration::from_secs(s.get_optional("stall_kick", 2, "must be an integer")),
+            retries: s.get_optional("stall_retries", 3, "must be an integer"),
+            alert: s.var("stall_alert").unwrap_or_default(),
+        };
+
+
+        // Define the temperature where the fan turns off.
+        // The default value is 45.0 degree C.
+        let off_temp: f32 = s.get("off_temp", 45.0, "must be a number");
+
+
+        // Define the temperature where the fan turns on.
+        // The minimum temperature has to be at least equal to the off temperature with 50.0 degree C as the default value.
+        let min_temp: f32 = s.get("min_temp", 50.0, "must be a number");
+        let min_temp: f32 = if min_temp >= off_temp {min_temp} else {s.invalid("min_temp", "must be at least 'off_temp'", off_temp)};
+        s.used("min_temp", &min_temp);
+
+
+        // Define the temperature where the fan reaches full speed.
+        // The maximum temperature has to be at least equal to the minimum temperature with 70.0 degree C as the default value.
+        let max_temp: f32 = s.get("max_temp", 70.0, "must be a number");
+        let max_temp: f32 = if max_temp >= min_temp {max_temp} else {s.invalid("max_temp", "must be at least 'min_temp'", min_temp)};
+        s.used("max_temp", &max_temp);
+
+
+        // Define the sensors the fan is controlled by.
+        // temp_sensors is a comma separated list of sensor names, e.g. "cpu,nvme". For each sensor, the source is defined by temp_source_<name>
+        // (same format as temp_source). Optionally, temp_weight_<name> (default 1.0), temp_offset_<name> (default 0.0)
+        // and the own curve of the sensor temp_min_<name> and temp_max_<name> (default min_temp and max_temp) can be defined.
+        // If temp_sensors is not defined, temp_source is used as the only sensor.
+        let mut sensors: Vec<SensorSpec> = Vec::new();
+        for name in s.var("temp_sensors").unwrap_or_default().split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            let source_key = format!("temp_source_{}", name);
+            let source: SourceSpec = match s.var(&source_key).ok_or_else(|| "not found".to_string()).and_then(|spec| spec.parse::<SourceSpec>()) {
+                Ok(source) => source,
+                Err(e) => {
+                    s.invalid(&source_key, &format!("{}, sensor '{}' is ignored", e, name), ());
+                    continue;
+                }
+            };
+            let weight: f32 = s.get_range(&format!("temp_weight_{}", name), 1.0, 0.0..=1000.0, false);
+            let offset: f32 = s.get_optional(&format!("temp_offset_{}", name), 0.0, "must be a number");
+            let sensor_min_temp: f32 = s.get_optional(&format!("temp_min_{}", name), min_temp, "must be a number");
+            let sensor_max_temp: f32 = s.get_optional(&format!("temp_max_{}", name), max_temp, "must be a number");
+            let sensor_max_temp: f32 = if sensor_max_temp >= sensor_min_temp {sensor_max_temp} else {
+                s.invalid(&format!("temp_max_{}", name), &format!("must be at least 'temp_min_{}'", name), sensor_min_temp)
+            };
+            sensors.push(SensorSpec { name: name.to_string(), source, weight, offset, min_temp: sensor_min_temp, max_temp: sensor_max_temp });
+        }
+        if sensors.is_empty() {
+            // If no sensors are defined, define the single temperature source the fan is controlled by.
+            // Can be a thermal zone (e.g. "thermal_zone:0"), a hwmon sensor (e.g. "hwmon:nvme:1"), a file with a scale factor (e.g. "file:/run/case_temp:0.001")
+            // or the output of a command (e.g. "command:vcgencmd measure_temp"). The default value is "thermal_zone:0" (the CPU of the Raspberry Pi).
+            let temp_source: SourceSpec = match s.var("temp_source") {
+                Some(spec) => spec.parse::<SourceSpec>().unwrap_or_else(|e| s.invalid("temp_source", &e, SourceSpec::default())),
+                None => {
+                    s.missing.push("temp_source".to_string());
+                    SourceSpec::default()
+                }
+            };
+            s.used("temp_source", &temp_source);
+            sensors.push(SensorSpec { name: "cpu".to_string(), source: temp_source, weight: 1.0, offset: 0.0, min_temp, max_temp });
+        }
+
+
+        // Define how the readings of several sensors are combined.
+        // Must be either "max" (highest reading), "weighted" (weighted average) or "normalized" (highest reading relative to the own curve of each sensor).
+        // The default value is "max".
+        let aggregation: Aggregation = s.get_optional("temp_aggregation", Aggregation::Max, "must be either max, weighted or normalized");
+
+
+        // Define the minimum speed at which the fan will run.
+        // The minimum fan speed has to be between 0.00 (0 percent) and 1.00 (100 percent) with 0.20 (20 percent) as the default value.
+        let min_duty_cycle: f32 = s.get_range("min_duty_cycle", 0.20, 0.00..=1.00, true);
+
+
+        // Define the maximum speed at which the fan will run.
+        // The maximum fan speed has to be between the minimum fan speed and 1.00 (100 percent) with 1.00 (100 percent) as the default value.
+        let max_duty_cycle: f32 = s.get_range("max_duty_cycle", 1.00, 0.00..=1.00, true);
+        let max_duty_cycle: f32 = if max_duty_cycle >= min_duty_cycle {max_duty_cycle} else {s.invalid("max_duty_cycle", "must be at least 'min_duty_cycle'", 1.00)};
+        s.used("max_duty_cycle", &max_duty_cycle);
+
+
+        // Define the kick-start of the fan.
+        // Whenever the fan is started, it runs at kick_duty_cycle for kick_time seconds to overcome static friction before the calculated fan speed is used.
+        // The default values are 1.00 (100 percent) and 0 seconds, i.e. the kick-start is disabled (fans that do not start reliably at min_duty_cycle need it, see calibrate).
+        let kick_duty_cycle: f32 = s.get_range("kick_duty_cycle", 1.00, 0.00..=1.00, false);
+        let kick_duration = Duration::from_secs_f32(s.get_range("kick_time", 0.0, 0.0..=60.0, false));
+
+
+        // Define the frequency of the PWM signal.
+        // The frequency has to be between 1 Hz and 1 MHz (due to further calculations and functions used) with 1 kHz as the default value.
+        let pwm_freq: u64 = s.get_range("pwm_freq", 1000, 1..=1000000, true);
+
+
+        // Define the interpolation between the points of the fan curve.
+        // Must be either "linear", "smoothstep" or "cubic" with "linear" as the default value.
+        let curve_interpolation: Interpolation = s.get_optional("curve_interpolation", Interpolation::Linear, "must be either linear, smoothstep or cubic");
+
+
+        // Define the fan curve, i.e. the fan speed for each temperature.
+        // The curve is a list of points "<temp>:<duty_cycle>" with strictly rising temperatures and rising duty cycles, e.g. "45:0.3, 55:0.4, 65:0.7, 75:1.0".
+        // If no curve is defined, the straight line from (min_temp, min_duty_cycle) to (max_temp, max_duty_cycle) is used.
+        let linear_curve = FanCurve::linear(min_temp, max_temp, min_duty_cycle, max_duty_cycle);
+        let fan_curve: FanCurve = match s.var("fan_curve") {
+            Some(curve) => curve.parse::<FanCurve>().unwrap_or_else(|e| s.invalid("fan_curve", &e, linear_curve)),
+            None => linear_curve,
+        }.with_interpolation(curve_interpolation);
+        s.used("fan_curve", &fan_curve);
+
+
+        // Define how the fan speed is calculated.
+        // "curve" uses the fan curve (open loop). "pid" uses a PID controller to hold the temperature at pid_target (closed loop).
+        // "rpm" uses rpm_curve to calculate a target fan speed, which is held by a PID controller using the measured fan speed (requires tach_pin).
+        // In all modes, the fan is turned on above min_temp and turned off below off_temp. The default value is "curve".
+        let control_mode: ControlMode = s.get_optional("control_mode", ControlMode::Curve, "must be either curve, pid or rpm");
+
+
+        // Define the target temperature and the gains of the PID controller.
+        // Only required if control_mode is "pid". The default values are a target of 55.0 degree C,
+        // a proportional gain of 0.05 (i.e. 5 percent fan speed per degree C), an integral gain of 0.005 (per degree C and second) and no derivative gain.
+        let pid = PidSettings {
+            target: s.get_optional("pid_target", 55.0, "must be a number"),
+            kp: s.get_range("pid_kp", 0.05, 0.0..=100.0, false),
+            ki: s.get_range("pid_ki", 0.005, 0.0..=100.0, false),
+            kd: s.get_range("pid_kd", 0.0, 0.0..=100.0, false),
+        };
+
+
+        // Define the curve of target fan speeds (in RPM) for the "rpm" control mode, in the format "<temp>:<rpm>, <temp>:<rpm>, ...".
+        // If no curve is defined, the target speeds are taken from fan_curve times fan_max_rpm.
+        // The "rpm" control mode requires a tach pin and either of the two.
+        let rpm_curve: Option<FanCurve> = match s.var("rpm_curve") {
+            Some(curve) => match FanCurve::parse_rpm(&curve) {
+                Ok(curve) => Some(curve.with_interpolation(curve_interpolation)),
+                Err(e) => s.invalid("rpm_curve", &e, None),
+            },
+            None => None,
+        }.or_else(|| (stall.max_rpm > 0.0).then(|| fan_curve.scaled(stall.max_rpm)));
+        let control_mode: ControlMode = if control_mode == ControlMode::Rpm && (rpm_curve.is_none() || tach_pin.is_none()) {
+            s.invalid("control_mode", "'rpm' requires 'tach_pin' and either 'rpm_curve' or 'fan_max_rpm'", ControlMode::Curve)
+        } else {
+            control_mode
+        };
+        s.used("control_mode", &control_mode);
+        let rpm_curve: FanCurve = rpm_curve.unwrap_or_else(|| fan_curve.clone()); // ... Not used unless control_mode is "rpm".
+
+
+        // Define the gains of the PID controller that holds the target fan speed.
+        // Only required if control_mode is "rpm". The default values are a proportional gain of 0.0001 (i.e. 1 percent duty cycle per 100 RPM),
+        // an integral gain of 0.0002 (per RPM and second) and no derivative gain. The target is set from rpm_curve in every cycle.
+        let rpm_pid = PidSettings {
+            target: 0.0,
+            kp: s.get_range("rpm_kp", 0.0001, 0.0..=1.0, false),
+            ki: s.get_range("rpm_ki", 0.0002, 0.0..=1.0, false),
+            kd: s.get_range("rpm_kd", 0.0, 0.0..=1.0, false),
+        };
+
+
+        // Define how the PWM signal is generated.
+        // "hardware" uses the PWM peripheral of the Raspberry Pi, "software" switches the GPIO pin on and off in software,
+        // "sysfs" uses the generic Linux PWM interface (/sys/class/pwm) of any board and "mock" does not access any hardware at all.
+        // "auto" tries the PWM peripheral of the Raspberry Pi first with software PWM as the fallback. The default value is "auto".
+        let pwm_mode: PwmMode = s.get("pwm_mode", PwmMode::Auto, "must be either auto, hardware, software, sysfs or mock");
+
+
+        // Define the PWM chip and channel used by the sysfs PWM interface (/sys/class/pwm/pwmchip<pwm_chip>/pwm<pwm_channel>).
+        // Only required if pwm_mode is "sysfs". The default values are 0 for both.
+        let pwm_chip: u32 = s.get_optional("pwm_chip", 0, "must be an integer");
+        let pwm_channel: u32 = s.get_optional("pwm_channel", 0, "must be an integer");
+
+
+        // Calculate the duration of a single PWM period in microseconds.
+        let period_micros = 1000000 / pwm_freq;
+
+
+        // Define how often the CPU temperature should be checked.
+        // The frequency has to be between 1 Hz and 1 MHz (due to further calculations and functions used).
+        // It also has to be smaller than and a divisor of the PWM frequency with 10 Hz as the default value.
+        // If temp_freq is off (i.e. 0), the temperature should be read for every PWM cycle.
+        let temp_freq: u64 = s.get("temp_freq", 10, "must be an integer");
+        let temp_freq: u64 = if temp_freq == 0 {
+            pwm_freq
+        } else if temp_freq > pwm_freq || !pwm_freq.is_multiple_of(temp_freq) {
+            // Use the largest divisor of pwm_freq instead.
+            let divisor = (1..=temp_freq.min(pwm_freq)).rev().find(|&i| pwm_freq.is_multiple_of(i)).unwrap_or(1);
+            s.adjusted("temp_freq", "is not a divisor of 'pwm_freq'", divisor)
+        } else {temp_freq};
+
+
+        // Calculate how many PWM cycles should pass before the temperature is measured each time.
+        let temp_cycle: u64 = pwm_freq / temp_freq;
+        s.used("temp_freq", &temp_freq);
+
+
+        // Define the dampening delay for rising temperatures.
+        // The minimum delay is a single temperature reading period (in seconds). In this case, no delay will be applied. The default value is 1.0 s.
+        let delay_hot: f32 = s.get("delay_hot", 1.0_f32, "must be a number");
+        let delay_hot: f32 = if delay_hot >= 1.0 / temp_cycle as f32 {delay_hot} else {s.adjusted("delay_hot", "is shorter than a single temperature reading period", 1.0 / temp_cycle as f32)};
+
+
+        // Define the dampening delay for falling temperatures.
+        // The minimum delay is a single temperature reading period (in seconds). In this case, no delay will be applied. The default value is 10.0 s.
+        let delay_cold: f32 = s.get("delay_cold", 10.0_f32, "must be a number");
+        let delay_cold: f32 = if delay_cold >= 1.0 / temp_cycle as f32 {delay_cold} else {s.adjusted("delay_cold", "is shorter than a single temperature reading period", 1.0 / temp_cycle as f32)};
+
+
+        // Calculate the factors to dampen fan speed changes.
+        // If the delay is at or below a single PWM period, no dampening will be applied.
+        let factor_hot_old = if delay_hot > 1.0 / (temp_freq  as f32) {
+            1.0 - 1.0 / (temp_freq as f32 * delay_hot)
+        } else {0.0};
 
-    // Output arguments
-    (
-        log_option,
-        gpio_pin,
-        off_temp, min_temp, max_temp,
-        min_duty_cycle, max_duty_cycle,
-        period_micros,
-        temp_cycle,
-        factor_hot_old, factor_hot_new, factor_cold_old, factor_cold_new,
-        delay_duration,
-        error_duration,
-        stat_option, stat_cycle, stat_min_time, stat_max_time, stat_delta_t, stat_time_start,
-        i, i_stat, n_on_fan, n_on_temp,
-        duty_cycle, duty_cycle_old,
-        cpu_temp_min, cpu_temp_max, cpu_temp_avg,
-        fan_state_stat, fan_state_prev,
-        fan_speed_avg, fan_speed_min, fan_speed_max
-    ) 
+        let factor_hot_new = if delay_hot > 1.0 / (temp_freq as f32) {
+            1.0 / (temp_freq as f32 * delay_hot)
+        } else {1.0};
+
+        let factor_cold_old = if delay_cold > 1.0 / (temp_freq as f32) {
+            1.0 - 1.0 / (temp_freq as f32 * delay_cold)
+        } else {0.0};
+
+        let factor_cold_new = if delay_cold > 1.0 / (temp_freq as f32) {
+            1.0 / (temp_freq as f32 * delay_cold)
+        } else {1.0};
+
+
+        // Define the minimum duration between turning the fan on or off.
+        // The minimum duration is 0 ms with 2000 ms as the default value.
+        let delay_duration: Duration = Duration::from_millis(s.get("delay_time", 2000, "must be an integer"));
+
+
+        // Define the minimum duration between error message outputs if no cpu temperature can be determined.
+        // The minimum duration is 0 s with 60 s as the default value.
+        let error_duration: Duration = Duration::from_secs(s.get("error_time", 60, "must be an integer"));
+
+
+        // Define whether statistics should be logged or not.
+        let stat_option: bool = s.get("stat_option", true, "must be true or false");
+
+
+        // Define the statistics file.
+        // The default value is /var/lib/rpi_fanctrl/stats.csv. The directory is created if it does not exist.
+        let stat_path: String = s.get_optional("stat_path", STAT_PATH.to_string(), "");
+        let stat_path: String = if !stat_path.trim().is_empty() {stat_path} else {s.invalid("stat_path", "must not be empty", STAT_PATH.to_string())};
+
+
+        // Define the rotation of the statistics file (stat_max_size, stat_max_age, stat_keep and stat_compress).
+        let stat_rotation: Rotation = s.get_rotation("stat");
+
+
+        // Define how often statistics should be calculated.
+        // The frequency has to be between 1 Hz and 1 MHz (due to further calculations and functions used).
+        // It also has to be smaller than and a divisor of the PWM frequency with 10 Hz as the default value.
+        // If stat_freq is off (i.e. 0), the statistics should be calculated for every PWM cycle.
+        let stat_freq: u64 = s.get("stat_freq", 10, "must be an integer");
+        let stat_freq: u64 = if stat_freq == 0 {
+            pwm_freq
+        } else if stat_freq > pwm_freq || !pwm_freq.is_multiple_of(stat_freq) {
+            // Use the largest divisor of pwm_freq instead.
+            let divisor = (1..=stat_freq.min(pwm_freq)).rev().find(|&i| pwm_freq.is_multiple_of(i)).unwrap_or(1);
+            s.adjusted("stat_freq", "is not a divisor of 'pwm_freq'", divisor)
+        } else {stat_freq};
+        // Calculate how many PWM cycles should pass before statistics are calculated each time.
+        let stat_cycle: u64 = pwm_freq / stat_freq;
+        s.used("stat_freq", &stat_freq);
+
+
+        // Define the minimum duration after which statistics are saved.
+        // The value must be a whole number (integer) representing a duration in seconds, ranging from 1 second to ~ 1 year with 60s as the default value.
+        let stat_min_time: u64 = s.get_range("stat_min_time", 60, 1..=31536000, true);
+
+
+        // Define the maximum duration after which statistics are saved.
+        // The value must be a whole number (integer) representing a duration in seconds, ranging from 1 second to ~ 1 year and at least as long as the minimum duration.
+        // The default value is 3600s.
+        let stat_max_time: u64 = s.get_range("stat_max_time", 3600, 1..=31536000, true);
+        let stat_max_time: u64 = if stat_max_time >= stat_min_time {stat_max_time} else {s.invalid("stat_max_time", "must be at least 'stat_min_time'", stat_min_time)};
+        s.used("stat_max_time", &stat_max_time);
+
+
+        // Define the minimum difference in min and max temperatures for statistics to be saved before the max duration.
+        let stat_delta_t: f32 = s.get_range("stat_delta_t", 5.0, 0.0..=1000.0, true);
+
+
+        // Define whether the control socket is created (see "rpi_fanctrl ctl") and its path.
+        // The default path is /run/rpi_fanctrl/control.sock. The directory is created if it does not exist.
+        let socket_option: bool = s.get_optional("socket_option", true, "must be true or false");
+        let socket_path: String = s.get_optional("socket_path", SOCKET_PATH.to_string(), "");
+        let socket_path: String = if !socket_path.trim().is_empty() {socket_path} else {s.invalid("socket_path", "must not be empty", SOCKET_PATH.to_string())};
+
+
+        // Define how long a fan speed set with the control socket lasts at most before the automatic control resumes.
+        // The duration must be between 1 s and 1 day with 600 s as the default value.
+        let override_duration = Duration::from_secs(s.get_range("override_time", 600, 1..=86400, false));
+
+
+        // Define the temperature at which a fan speed set with the control socket is cancelled, so a forgotten override cannot overheat the board.
+        // The temperature must be at least max_temp with 80 degree C (or max_temp if it is higher) as the default value.
+        let emergency_temp: f32 = s.get_optional("emergency_temp", max_temp.max(80.0), "must be a number");
+        let emergency_temp: f32 = if emergency_temp >= max_temp {emergency_temp} else {s.invalid("emergency_temp", "must be at least 'max_temp'", max_temp.max(80.0))};
+        s.used("emergency_temp", &emergency_temp);
+
+
+        // Define whether the metrics are served for Prometheus and the address of the endpoint (http://<address>/metrics).
+        // The address must be an IP address with a port with 127.0.0.1:9101 (only local clients) as the default value. Use 0.0.0.0:9101 to serve every interface.
+        let metrics_option: bool = s.get_optional("metrics_option", false, "must be true or false");
+        let metrics_address: SocketAddr = s.get_optional("metrics_address", SocketAddr::from(([127, 0, 0, 1], 9101)), "must be an IP address with a port (e.g. 127.0.0.1:9101)");
+
+
+        // Define whether the state is published to an MQTT broker (e.g. for Home Assistant) and the broker (mqtt_host and mqtt_port).
+        // The default broker is localhost:1883 without authentication (mqtt_user and mqtt_password).
+        let mqtt_option: bool = s.get_optional("mqtt_option", false, "must be true or false");
+        let mqtt_host: String = s.get_optional("mqtt_host", "localhost".to_string(), "").trim().to_string();
+        let mqtt_host: String = if !mqtt_host.is_empty() {mqtt_host} else {s.invalid("mqtt_host", "must not be empty", "localhost".to_string())};
+        let mqtt_port: u16 = s.get_range("mqtt_port", 1883, 1..=65535, false);
+        let mqtt_user: Option<String> = s.var("mqtt_user").filter(|user| !user.is_empty());
+        let mqtt_password: Option<String> = s.var("mqtt_password").filter(|password| !password.is_empty());
+        if mqtt_password.is_some() {
+            s.used("mqtt_password", &"********");
+        }
+
+
+        // Define the client id and the base topic, under which the state (<mqtt_topic>/state) is published and commands (<mqtt_topic>/set) are received.
+        // The default values are rpi_fanctrl_<hostname> and rpi_fanctrl/<hostname>, so several boards can share a broker.
+        let host_name: String = hostname();
+        let mqtt_client_id: String = s.get_optional("mqtt_client_id", format!("rpi_fanctrl_{}", host_name), "").trim().to_string();
+        let mqtt_client_id: String = if !mqtt_client_id.is_empty() {mqtt_client_id} else {s.invalid("mqtt_client_id", "must not be empty", format!("rpi_fanctrl_{}", host_name))};
+        let mqtt_topic: String = s.get_optional("mqtt_topic", format!("rpi_fanctrl/{}", host_name), "").trim().trim_end_matches('/').to_string();
+        let mqtt_topic: String = if !mqtt_topic.is_empty() && !mqtt_topic.contains(['+', '#']) {mqtt_topic} else {
+            s.invalid("mqtt_topic", "must not be empty and must not contain + or #", format!("rpi_fanctrl/{}", host_name))
+        };
+
+
+        // Define how often the state is published. The interval must be between 1 s and 1 hour with 10 s as the default value.
+        let mqtt_interval = Duration::from_secs(s.get_range("mqtt_interval", 10, 1..=3600, false));
+
+
+        // Define whether discovery messages are published, so the board appears as a device in Home Assistant, and the prefix of their topics.
+        // The default values are true and homeassistant. The device is named after the hostname unless mqtt_device_name is defined.
+        let mqtt_discovery: bool = s.get_optional("mqtt_discovery", true, "must be true or false");
+        let mqtt_discovery_prefix: String = s.get_optional("mqtt_discovery_prefix", "homeassistant".to_string(), "").trim().trim_end_matches('/').to_string();
+        let mqtt_discovery_prefix: String = if !mqtt_discovery_prefix.is_empty() {mqtt_discovery_prefix} else {s.invalid("mqtt_discovery_prefix", "must not be empty", "homeassistant".to_string())};
+        let mqtt_device_name: String = s.get_optional("mqtt_device_name", host_name, "");
+
+
+        // Output configuration.
+        let config = Config {
+            env_file,
+            config_file: config_file.to_path_buf(),
+            logging: LoggingConfig { log_option, log_path, rotation: log_rotation, level: log_level, format: log_format, backend: log_backend },
+            pwm: PwmConfig { gpio_pin, mode: pwm_mode, chip: pwm_chip, channel: pwm_channel, freq: pwm_freq, period_micros },
+            sensor: SensorConfig { sensors, aggregation, temp_cycle, error_duration },
+            tach: TachConfig { pin: tach_pin, ppr: tach_ppr, stall },
+            control: ControlConfig {
+                mode: control_mode,
+                off_temp, min_temp, max_temp,
+                min_duty_cycle, max_duty_cycle,
+                kick_duty_cycle, kick_duration,
+                fan_curve, pid, rpm_curve, rpm_pid,
+                delay_duration,
+                factor_hot_old, factor_hot_new, factor_cold_old, factor_cold_new,
+            },
+            stats: StatsConfig { stat_option, path: stat_path, rotation: stat_rotation, cycle: stat_cycle, min_time: stat_min_time, max_time: stat_max_time, delta_t: stat_delta_t },
+            socket: SocketConfig { socket_option, path: socket_path, override_duration, emergency_temp },
+            metrics: MetricsConfig { metrics_option, address: metrics_address },
+            mqtt: MqttConfig {
+                mqtt_option,
+                host: mqtt_host, port: mqtt_port, user: mqtt_user, password: mqtt_password,
+                client_id: mqtt_client_id, topic: mqtt_topic, interval: mqtt_interval,
+                discovery: mqtt_discovery, discovery_prefix: mqtt_discovery_prefix, device_name: mqtt_device_name,
+            },
+            profile, profiles,
+        };
+        let result = if s.errors.is_empty() {Ok(config)} else {Err(ConfigError { errors: s.errors.clone(), fallback: Box::new(config) })};
+        (result, s)
+    }
+}
+
+
+
+// Save settings to the .env file.
+// Existing lines of the settings are replaced, missing settings are appended. All other lines (including comments) are kept.
+// The previous file is kept as .env.bak.
+pub fn save_var(env_file: &Path, settings: &[(&str, String)]) -> std::io::Result<()> {
+    let content = fs::read_to_string(env_file).unwrap_or_default();
+    fs::write(env_file.with_extension("bak"), &content)?;
+
+    let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+    for (key, value) in settings {
+        let line = format!("{}={}", key, value);
+        match lines.iter().position(|l| l.split_once('=').is_some_and(|(k, _)| k.trim() == *key)) {
+            Some(k) => lines[k] = line,
+            None => lines.push(line),
+        }
+    }
+    fs::write(env_file, lines.join("\n") + "\n")
+}
+
+
+// Save settings to the configuration file (TOML), which is created if it does not exist.
+// Settings already defined in a section (e.g. min_duty_cycle in [control]) are replaced there, missing settings are added at the top of the file.
+// The profiles are left alone and all other lines (including comments) are kept. The previous file is kept as <file>.bak.
+pub fn save_file(config_file: &Path, settings: &[(&str, String)]) -> Result<(), String> {
+    let content = fs::read_to_string(config_file).unwrap_or_default();
+    let mut document = content.parse::<toml_edit::DocumentMut>().map_err(|e| e.to_string().trim().to_string())?;
+    if config_file.exists() {
+        let mut backup = config_file.as_os_str().to_owned();
+        backup.push(".bak");
+        fs::write(backup, &content).map_err(|e| e.to_string())?;
+    } else {
+        create_dir(&config_file.display().to_string())?;
+    }
+
+    for (key, value) in settings {
+        if !replace_value(document.as_table_mut(), key, file_value(value)) {
+            document.insert(key, toml_edit::Item::Value(file_value(value)));
+        }
+    }
+    fs::write(config_file, document.to_string()).map_err(|e| e.to_string())
+}
+
+
+// Replace the value of a setting in a table of the configuration file or in one of its sections, keeping its comments.
+// Returns false if the setting is not defined.
+fn replace_value(table: &mut dyn toml_edit::TableLike, key: &str, value: toml_edit::Value) -> bool {
+    if let Some(toml_edit::Item::Value(old)) = table.get_mut(key) {
+        let decor = old.decor().clone();
+        *old = value;
+        *old.decor_mut() = decor;
+        return true;
+    }
+    table.iter_mut()
+        .filter(|(name, _)| name.get() != "profiles")
+        .filter_map(|(_, item)| item.as_table_like_mut())
+        .any(|section| replace_value(section, key, value.clone()))
+}
+
+
+// Convert a setting in the format of the environmental variables to a value of the configuration file (the reverse of toml_value).
+// Lists of points (e.g. "45:0.3, 55:0.4") become [[45, 0.3], [55, 0.4]], numbers and true or false keep their type.
+fn file_value(value: &str) -> toml_edit::Value {
+    let scalar = |value: &str| -> toml_edit::Value {
+        let value = value.trim();
+        if let Ok(value) = value.parse::<i64>() {
+            value.into()
+        } else if let Ok(value) = value.parse::<f64>() {
+            value.into()
+        } else if let Ok(value) = value.parse::<bool>() {
+            value.into()
+        } else {
+            value.into()
+        }
+    };
+    if !value.contains(':') {
+        return scalar(value);
+    }
+    value.split(',').filter(|point| !point.trim().is_empty()).map(|point| match point.split_once(':') {
+        Some((temp, value)) => toml_edit::Value::Array([scalar(temp), scalar(value)].into_iter().collect()),
+        None => scalar(point),
+    }).collect::<toml_edit::Array>().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Write a configuration file to a directory of its own and return its path.
+    fn config_file(test: &str, content: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rpi_fanctrl_{}_{}", test, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config_file = dir.join("config.toml");
+        fs::write(&config_file, content).unwrap();
+        config_file
+    }
+
+    fn env_file(settings: &[(&str, &str)]) -> Option<EnvFile> {
+        Some((PathBuf::from(".env"), settings.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()))
+    }
+
+    fn origin(settings: &Settings, key: &str) -> &'static str {
+        settings.read.iter().find(|setting| setting.key == key).unwrap().origin
+    }
+
+    #[test]
+    fn precedence_of_the_sources() {
+        let config_file = config_file("precedence", "profile = \"quiet\"\nmin_temp = 50\n\n[control]\nmax_temp = 70\nmax_duty_cycle = 0.9\nmin_duty_cycle = 0.25\n\n[profiles.quiet]\nmax_duty_cycle = 0.6\nmax_temp = 75\n");
+        let env = [("min_duty_cycle", "0.35"), ("max_duty_cycle", "0.7")].iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+        let env_file = env_file(&[("min_duty_cycle", "0.3"), ("max_duty_cycle", "0.8"), ("max_temp", "72"), ("off_temp", "40")]);
+        let overrides = [("max_temp".to_string(), "80".to_string())];
+        let (config, settings) = Config::from_env(env, env_file, &config_file, &overrides);
+        let config = config.unwrap();
+
+        // cli > profile > env > .env > file > default
+        assert_eq!((config.control.max_temp, origin(&settings, "max_temp")), (80.0, "cli"));
+        assert_eq!((config.control.max_duty_cycle, origin(&settings, "max_duty_cycle")), (0.6, "profile"));
+        assert_eq!((config.control.min_duty_cycle, origin(&settings, "min_duty_cycle")), (0.35, "env"));
+        assert_eq!((config.control.off_temp, origin(&settings, "off_temp")), (40.0, ".env"));
+        assert_eq!((config.control.min_temp, origin(&settings, "min_temp")), (50.0, "file"));
+        assert_eq!(origin(&settings, "delay_time"), "default");
+        assert_eq!(config.control.kick_duration, Duration::ZERO);
+        assert_eq!(config.profile, "quiet");
+        assert_eq!(config.env_file, Some(PathBuf::from(".env")));
+        fs::remove_dir_all(config_file.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn invalid_settings_are_collected() {
+        let config_file = config_file("invalid", "min_duty_cycle = \"fast\"\nmax_duty_cycle = 1.5\nmin_temp = 50\n");
+        let (config, settings) = Config::from_env(HashMap::new(), None, &config_file, &[("log_level".to_string(), "loud".to_string())]);
+        let e = config.unwrap_err();
+        let keys: Vec<&str> = e.errors.iter().map(|error| error.key.as_str()).collect();
+        assert!(keys.contains(&"min_duty_cycle") && keys.contains(&"max_duty_cycle") && keys.contains(&"log_level"), "{:?}", keys);
+
+        // The invalid settings are replaced by their default values, the valid ones are kept.
+        assert_eq!(e.fallback.control.min_duty_cycle, 0.2);
+        assert_eq!(e.fallback.control.max_duty_cycle, 1.0);
+        assert_eq!(e.fallback.control.min_temp, 50.0);
+        assert!(settings.read.iter().any(|setting| setting.key == "min_duty_cycle" && setting.invalid));
+        assert!(e.to_string().starts_with(&format!("{} invalid setting(s):", e.errors.len())));
+        fs::remove_dir_all(config_file.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn adjusted_settings_are_reported() {
+        let overrides = [("pwm_freq".to_string(), "1000".to_string()), ("temp_freq".to_string(), "7".to_string())];
+        let (config, settings) = Config::from_env(HashMap::new(), None, Path::new(""), &overrides);
+
+        // An adjusted setting is not invalid, but it is reported with the value that is used.
+        let config = config.unwrap();
+        assert_eq!(config.sensor.temp_cycle, 200);
+        let report = settings.report();
+        assert_eq!(report.adjusted.iter().map(|adjusted| adjusted.key.as_str()).collect::<Vec<&str>>(), ["temp_freq"]);
+        assert!(report.settings.iter().any(|setting| setting.key == "temp_freq" && setting.value == "5" && !setting.invalid));
+    }
+
+    #[test]
+    fn configuration_file_errors() {
+        let config_file = config_file("file_errors", "min_temp = 40\nmin_temp = 45\n");
+        let (config, _) = Config::from_env(HashMap::new(), None, &config_file, &[]);
+        assert!(config.unwrap_err().errors.iter().any(|error| error.key == config_file.display().to_string()));
+
+        fs::write(&config_file, "[profiles.default]\nmin_temp = 40\n\n[control]\nmin_temp = 45\n[[sensors]]\nsource = \"cpu\"\n").unwrap();
+        let (config, settings) = Config::from_env(HashMap::new(), None, &config_file, &[("typo_setting".to_string(), "1".to_string())]);
+        let keys: Vec<String> = config.unwrap_err().errors.into_iter().map(|error| error.key).collect();
+        assert!(keys.contains(&"profiles.default".to_string()) && keys.contains(&"sensors".to_string()), "{:?}", keys);
+        assert_eq!(settings.unknown(), vec!["typo_setting".to_string()]);
+        fs::remove_dir_all(config_file.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn invalid_modes_are_config_errors() {
+        let overrides = [("control_mode".to_string(), "fast".to_string()), ("pwm_mode".to_string(), "laser".to_string())];
+        let e = Config::from_env(HashMap::new(), None, Path::new(""), &overrides).0.unwrap_err();
+        let keys: Vec<&str> = e.errors.iter().map(|error| error.key.as_str()).collect();
+        assert_eq!(keys, vec!["control_mode", "pwm_mode"]);
+        assert_eq!((e.fallback.control.mode, e.fallback.pwm.mode), (ControlMode::Curve, PwmMode::Auto));
+
+        // The rpm mode requires a tachometer.
+        let e = Config::from_env(HashMap::new(), None, Path::new(""), &[("control_mode".to_string(), "rpm".to_string())]).0.unwrap_err();
+        assert_eq!(e.errors[0].key, "control_mode");
+
+        let overrides = [("control_mode".to_string(), " PID ".to_string()), ("pwm_mode".to_string(), "mock".to_string())];
+        let config = Config::from_env(HashMap::new(), None, Path::new(""), &overrides).0.unwrap();
+        assert_eq!((config.control.mode, config.pwm.mode), (ControlMode::Pid, PwmMode::Mock));
+        assert_eq!((config.control.mode.to_string(), config.pwm.mode.to_string()), ("pid".to_string(), "mock".to_string()));
+    }
+
+    #[test]
+    fn config_path_from_the_environment() {
+        let env: HashMap<String, String> = [(CONFIG_ENV.to_string(), "/tmp/env.toml".to_string())].into_iter().collect();
+        let env_file = env_file(&[(CONFIG_ENV, "/tmp/dotenv.toml")]);
+        assert_eq!(config_path(&env, env_file.as_ref()), PathBuf::from("/tmp/env.toml"));
+        assert_eq!(config_path(&HashMap::new(), env_file.as_ref()), PathBuf::from("/tmp/dotenv.toml"));
+        assert_eq!(config_path(&HashMap::new(), None), PathBuf::from(CONFIG_FILE));
+    }
+
+    #[test]
+    fn unknown_profile_is_invalid() {
+        let (config, _) = Config::from_env(HashMap::new(), None, Path::new(""), &[("profile".to_string(), "turbo".to_string())]);
+        assert!(config.unwrap_err().errors.iter().any(|error| error.key == "profile"));
+    }
+
+    #[test]
+    fn save_file_replaces_settings_in_place() {
+        let config_file = config_file("save_file", "");
+        let dir = config_file.parent().unwrap().to_path_buf();
+        fs::write(&config_file, "log_level = \"info\"\n\n[control]\n# Keeps the fan quiet.\nmin_duty_cycle = 0.2 # measured\n\n[profiles.quiet]\nmin_duty_cycle = 0.1\n").unwrap();
+
+        save_file(&config_file, &[("min_duty_cycle", "0.35".to_string()), ("fan_curve", "45:0.30, 55:0.40".to_string())]).unwrap();
+        let content = fs::read_to_string(&config_file).unwrap();
+        let table: toml::Table = content.parse().unwrap();
+        assert!(content.contains("# Keeps the fan quiet.\nmin_duty_cycle = 0.35 # measured"));
+        assert_eq!(table["profiles"]["quiet"]["min_duty_cycle"].as_float(), Some(0.1));
+        assert_eq!(toml_value(&table["fan_curve"]), Ok("45:0.3, 55:0.4".to_string()));
+        assert!(dir.join("config.toml.bak").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
 
 /*
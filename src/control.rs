@@ -0,0 +1,225 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::functions::{create_dir, json_string, log_event, Level};
+
+
+// Time a client may take to send a command and the main loop may take to answer it. Idle clients are disconnected afterwards.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+
+// Commands of the control socket.
+// Every command is a single line (e.g. "set-duty 0.5") and is answered with a single line of JSON.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Status, // ........................... Current temperature, fan speed and fan state.
+    SetDuty(f32, Option<Duration>), // ... Run the fan at a fixed duty cycle until it is released or the duration (default: override_time) passed.
+    Release, // .......................... Return to the automatic control.
+    Reload, // ........................... Reload the configuration (same as SIGHUP).
+    FlushStats, // ....................... Save the current statistics period to the statistics file.
+    Profile(String), // .................. Switch to a profile of the configuration file ("default" for none) and reload the configuration.
+}
+
+impl Command {
+
+    // Parse a command line of the control socket.
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["status"] => Ok(Command::Status),
+            ["set-duty", duty_cycle, duration @ ..] if duration.len() <= 1 => {
+                let duty_cycle = match duty_cycle.parse::<f32>() {
+                    Ok(duty_cycle) if (0.0..=1.0).contains(&duty_cycle) => duty_cycle,
+                    _ => return Err(format!("'{}' must be a number between 0 and 1", duty_cycle)),
+                };
+                let duration = match duration.first().map(|duration| duration.parse::<u64>()) {
+                    Some(Ok(duration)) if duration > 0 => Some(Duration::from_secs(duration)),
+                    Some(_) => return Err(format!("'{}' must be a duration in s", duration[0])),
+                    None => None,
+                };
+                Ok(Command::SetDuty(duty_cycle, duration))
+            }
+            ["off", duration @ ..] if duration.len() <= 1 => Command::parse(&format!("set-duty 0 {}", duration.join(" "))),
+            ["release"] => Ok(Command::Release),
+            ["reload"] => Ok(Command::Reload),
+            ["flush-stats"] => Ok(Command::FlushStats),
+            ["profile", name] => Ok(Command::Profile(name.to_string())),
+            _ => Err(format!("Unknown command '{}'. Commands: status, set-duty <0.0-1.0> [<s>], off [<s>], release, reload, flush-stats, profile <name>", line.trim())),
+        }
+    }
+}
+
+
+// Fan speed set with the control socket, which replaces the automatic control until it expires.
+#[derive(Clone, Copy, Debug)]
+pub struct Override {
+    pub duty_cycle: f32, // ... 0.0 to turn the fan off.
+    pub until: Instant,
+}
+
+impl Override {
+
+    // Set the fan speed for a duration, limited to the maximum duration (override_time).
+    pub fn new(duty_cycle: f32, duration: Option<Duration>, max_duration: Duration) -> Override {
+        Override { duty_cycle, until: Instant::now() + duration.unwrap_or(max_duration).min(max_duration) }
+    }
+
+    // Remaining duration in s (rounded up).
+    pub fn remaining(&self) -> u64 {
+        let remaining = self.until.saturating_duration_since(Instant::now());
+        remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0)
+    }
+
+    // Check if the override expired.
+    pub fn expired(&self) -> bool {
+        Instant::now() >= self.until
+    }
+}
+
+
+// A command of a client together with the channel for the answer of the main loop.
+pub struct Request {
+    pub command: Command,
+    pub reply: mpsc::Sender<String>,
+}
+
+
+// Answer of a successful command, with further JSON fields (e.g. "\"duty_cycle\":0.5").
+pub fn reply_ok(fields: &[String]) -> String {
+    let mut reply = vec!["\"ok\":true".to_string()];
+    reply.extend_from_slice(fields);
+    format!("{{{}}}", reply.join(","))
+}
+
+
+// Answer of a failed command.
+pub fn reply_error(error: &str) -> String {
+    format!("{{\"ok\":false,\"error\":{}}}", json_string(error))
+}
+
+
+// JSON field of an optional number (null if none).
+pub fn json_field(key: &str, value: Option<f32>, precision: usize) -> String {
+    format!("\"{}\":{}", key, value.filter(|value| value.is_finite()).map_or("null".to_string(), |value| format!("{:.*}", precision, value)))
+}
+
+
+// Create the control socket and handle its clients in a separate thread.
+// The commands are forwarded to the main loop, which answers them at the beginning of its next cycle.
+// If another fan control is listening on the socket, it is left alone and an error is returned. A stale socket file (e.g. of a previous run that was killed)
+// is replaced. Only root and the group of the socket may connect. Every client is handled in a thread of its own, so an idle client cannot block the others.
+pub fn start_server(socket_path: &str, tx: mpsc::Sender<Request>, log_option: bool) -> Result<(), String> {
+    create_dir(socket_path)?;
+    if UnixStream::connect(socket_path).is_ok() {
+        return Err(format!("Another fan control is already listening on the control socket {}", socket_path));
+    }
+    if fs::symlink_metadata(socket_path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        let _ = fs::remove_file(socket_path);
+    }
+    let listener = UnixListener::bind(socket_path).map_err(|e| format!("Cannot create the control socket {}: {}", socket_path, e))?;
+    let _ = fs::set_permissions(socket_path, fs::Permissions::from_mode(0o660));
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                if let Err(e) = handle_client(stream, &tx) {
+                    log_event(Level::Warn, &format!("Control socket: {}", e), log_option);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+
+// Answer every command of a client until it closes the connection or does not send a command within TIMEOUT.
+fn handle_client(stream: UnixStream, tx: &mpsc::Sender<Request>) -> Result<(), String> {
+    stream.set_read_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+    let mut writer = stream.try_clone().map_err(|e| e.to_string())?;
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match Command::parse(&line) {
+            Ok(command) => {
+                let (reply_tx, reply_rx) = mpsc::channel();
+                tx.send(Request { command, reply: reply_tx }).map_err(|e| e.to_string())?;
+                reply_rx.recv_timeout(TIMEOUT).unwrap_or_else(|_| reply_error("The fan control did not answer"))
+            }
+            Err(e) => reply_error(&e),
+        };
+        writeln!(writer, "{}", reply).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+
+// Send a command to the control socket of the running fan control and return its answer (client side, "rpi_fanctrl ctl").
+pub fn send_command(socket_path: &str, command: &str) -> Result<String, String> {
+    let mut stream = UnixStream::connect(socket_path).map_err(|e| format!("Cannot connect to {}: {}. Is the fan control running?", socket_path, e))?;
+    stream.set_read_timeout(Some(TIMEOUT * 2)).map_err(|e| e.to_string())?;
+    writeln!(stream, "{}", command).map_err(|e| e.to_string())?;
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply).map_err(|e| format!("No answer from {}: {}", socket_path, e))?;
+    Ok(reply.trim_end().to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Command::parse("status"), Ok(Command::Status));
+        assert_eq!(Command::parse("  set-duty 0.5 "), Ok(Command::SetDuty(0.5, None)));
+        assert_eq!(Command::parse("set-duty 1 30"), Ok(Command::SetDuty(1.0, Some(Duration::from_secs(30)))));
+        assert_eq!(Command::parse("off"), Ok(Command::SetDuty(0.0, None)));
+        assert_eq!(Command::parse("off 60"), Ok(Command::SetDuty(0.0, Some(Duration::from_secs(60)))));
+        assert_eq!(Command::parse("release"), Ok(Command::Release));
+        assert_eq!(Command::parse("reload"), Ok(Command::Reload));
+        assert_eq!(Command::parse("flush-stats"), Ok(Command::FlushStats));
+        assert_eq!(Command::parse("profile quiet"), Ok(Command::Profile("quiet".to_string())));
+    }
+
+    #[test]
+    fn parse_rejects_invalid_commands() {
+        assert!(Command::parse("").is_err());
+        assert!(Command::parse("set-duty").is_err());
+        assert!(Command::parse("set-duty 1.5").is_err());
+        assert!(Command::parse("set-duty -0.1").is_err());
+        assert!(Command::parse("set-duty 0.5 0").is_err());
+        assert!(Command::parse("set-duty 0.5 ten").is_err());
+        assert!(Command::parse("set-duty 0.5 10 20").is_err());
+        assert!(Command::parse("profile").is_err());
+        assert!(Command::parse("stop").is_err());
+    }
+
+    #[test]
+    fn idle_client_does_not_block_others() {
+        let socket_path = std::env::temp_dir().join(format!("rpi_fanctrl_control_{}.sock", std::process::id())).display().to_string();
+        let (tx, rx) = mpsc::channel::<Request>();
+        start_server(&socket_path, tx, false).unwrap();
+
+        // Answer the commands like the main loop.
+        thread::spawn(move || {
+            for request in rx {
+                let _ = request.reply.send(reply_ok(&[]));
+            }
+        });
+
+        let _idle = UnixStream::connect(&socket_path).unwrap();
+        let start = Instant::now();
+        assert_eq!(send_command(&socket_path, "status"), Ok("{\"ok\":true}".to_string()));
+        assert!(start.elapsed() < TIMEOUT);
+        let _ = fs::remove_file(&socket_path);
+    }
+}
@@ -0,0 +1,258 @@
+use std::fmt;
+use std::str::FromStr;
+
+
+// How the duty cycle is interpolated between two points of the fan curve.
+// linear ....... Straight line between the points.
+// smoothstep ... S-shaped transition between the points, flat at each point.
+// cubic ........ Smooth curve through all points (monotone cubic spline, so the duty cycle never decreases with rising temperatures).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interpolation {
+    Linear,
+    Smoothstep,
+    Cubic,
+}
+
+impl FromStr for Interpolation {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode.trim().to_lowercase().as_str() {
+            "linear" => Ok(Interpolation::Linear),
+            "smoothstep" => Ok(Interpolation::Smoothstep),
+            "cubic" => Ok(Interpolation::Cubic),
+            _ => Err(format!("unknown interpolation '{}', must be either linear, smoothstep or cubic", mode)),
+        }
+    }
+}
+
+impl fmt::Display for Interpolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Interpolation::Linear => write!(f, "linear"),
+            Interpolation::Smoothstep => write!(f, "smoothstep"),
+            Interpolation::Cubic => write!(f, "cubic"),
+        }
+    }
+}
+
+
+// Fan curve.
+// List of points (temperature in degree C, duty cycle between 0.0 and 1.0) with rising temperatures and duty cycles.
+// Below the first point, the duty cycle of the first point is used. Above the last point, the duty cycle of the last point is used.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FanCurve {
+    points: Vec<(f32, f32)>,
+    slopes: Vec<f32>, // ... Tangents at the points for cubic interpolation.
+    interpolation: Interpolation,
+}
+
+impl FanCurve {
+
+    // Create a fan curve from a list of points.
+    // The points must contain at least two points with strictly rising temperatures and rising (or equal) duty cycles between 0.0 and 1.0.
+    pub fn new(points: Vec<(f32, f32)>, interpolation: Interpolation) -> Result<FanCurve, String> {
+        FanCurve::with_limit(points, 1.0, interpolation)
+    }
+
+
+    // Create a fan curve from a list of points with rising (or equal) values between 0.0 and max_value.
+    fn with_limit(points: Vec<(f32, f32)>, max_value: f32, interpolation: Interpolation) -> Result<FanCurve, String> {
+        if points.len() < 2 {
+            return Err("at least two points are required".to_string());
+        }
+        for &(temp, value) in &points {
+            if !temp.is_finite() || !(0.0..=max_value).contains(&value) {
+                return Err(format!("point {}:{} is invalid, the value must be between 0 and {}", temp, value, max_value));
+            }
+        }
+        for pair in points.windows(2) {
+            if pair[1].0 <= pair[0].0 {
+                return Err(format!("temperatures must be strictly rising, but {} follows {}", pair[1].0, pair[0].0));
+            }
+            if pair[1].1 < pair[0].1 {
+                return Err(format!("values must be rising, but {} follows {}", pair[1].1, pair[0].1));
+            }
+        }
+        let slopes = monotone_slopes(&points);
+        Ok(FanCurve { points, slopes, interpolation })
+    }
+
+
+    // Parse a curve that maps temperatures to fan speeds in RPM instead of duty cycles, e.g. "45:1200, 60:2500, 75:5000".
+    // The format is the same as for a duty cycle curve (see from_str), but the values may be up to 100000 RPM.
+    pub fn parse_rpm(curve: &str) -> Result<FanCurve, String> {
+        FanCurve::with_limit(parse_points(curve)?, 100_000.0, Interpolation::Linear)
+    }
+
+
+    // Create the straight line between (min_temp, min_duty_cycle) and (max_temp, max_duty_cycle).
+    // If both temperatures are the same, the line becomes a step of 0.01 degree C.
+    pub fn linear(min_temp: f32, max_temp: f32, min_duty_cycle: f32, max_duty_cycle: f32) -> FanCurve {
+        let max_temp = max_temp.max(min_temp + 0.01);
+        let points = vec![(min_temp, min_duty_cycle), (max_temp, max_duty_cycle)];
+        let slopes = monotone_slopes(&points);
+        FanCurve { points, slopes, interpolation: Interpolation::Linear }
+    }
+
+
+    // Change the interpolation of the curve.
+    pub fn with_interpolation(mut self, interpolation: Interpolation) -> FanCurve {
+        self.interpolation = interpolation;
+        self
+    }
+
+
+    // Multiply the values of the curve by a factor, e.g. to turn duty cycles into fan speeds.
+    pub fn scaled(&self, factor: f32) -> FanCurve {
+        let points: Vec<(f32, f32)> = self.points.iter().map(|&(temp, value)| (temp, value * factor)).collect();
+        let slopes = monotone_slopes(&points);
+        FanCurve { points, slopes, interpolation: self.interpolation }
+    }
+
+
+    // Calculate the value (duty cycle or fan speed) for a temperature.
+    pub fn value(&self, temp: f32) -> f32 {
+        let (first, last) = (self.points[0], self.points[self.points.len() - 1]);
+        if temp <= first.0 {
+            return first.1;
+        }
+        if temp >= last.0 {
+            return last.1;
+        }
+
+        // Find the segment the temperature lies in.
+        let k = self.points.windows(2).position(|pair| temp < pair[1].0).unwrap_or(self.points.len() - 2);
+        let (t0, d0) = self.points[k];
+        let (t1, d1) = self.points[k + 1];
+        let h = t1 - t0;
+        let t = (temp - t0) / h;
+
+        match self.interpolation {
+            Interpolation::Linear => d0 + t * (d1 - d0),
+            Interpolation::Smoothstep => d0 + t * t * (3.0 - 2.0 * t) * (d1 - d0),
+            Interpolation::Cubic => {
+                // Cubic Hermite spline.
+                let (h00, h10, h01, h11) = (
+                    2.0 * t.powi(3) - 3.0 * t.powi(2) + 1.0,
+                    t.powi(3) - 2.0 * t.powi(2) + t,
+                    -2.0 * t.powi(3) + 3.0 * t.powi(2),
+                    t.powi(3) - t.powi(2),
+                );
+                (h00 * d0 + h10 * h * self.slopes[k] + h01 * d1 + h11 * h * self.slopes[k + 1]).clamp(d0, d1)
+            }
+        }
+    }
+}
+
+impl FromStr for FanCurve {
+    type Err = String;
+
+    // Parse a list of points in the format "<temp>:<duty_cycle>, <temp>:<duty_cycle>, ...", e.g. "45:0.3, 55:0.4, 65:0.7, 75:1.0".
+    // The curve uses linear interpolation. Use with_interpolation to change it.
+    fn from_str(curve: &str) -> Result<Self, Self::Err> {
+        FanCurve::new(parse_points(curve)?, Interpolation::Linear)
+    }
+}
+
+impl fmt::Display for FanCurve {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let points: Vec<String> = self.points.iter().map(|(temp, duty_cycle)| format!("{}:{}", temp, duty_cycle)).collect();
+        write!(f, "{}", points.join(", "))
+    }
+}
+
+
+// Parse a list of points in the format "<temp>:<value>, <temp>:<value>, ...".
+fn parse_points(curve: &str) -> Result<Vec<(f32, f32)>, String> {
+    let mut points: Vec<(f32, f32)> = Vec::new();
+    for point in curve.split(',').map(str::trim).filter(|point| !point.is_empty()) {
+        let (temp, value) = point.split_once(':').ok_or_else(|| format!("point '{}' must be in the format <temp>:<value>", point))?;
+        let temp = temp.trim().parse::<f32>().map_err(|_| format!("temperature '{}' must be a number", temp.trim()))?;
+        let value = value.trim().parse::<f32>().map_err(|_| format!("value '{}' must be a number", value.trim()))?;
+        points.push((temp, value));
+    }
+    Ok(points)
+}
+
+
+// Calculate the tangents of a monotone cubic spline through the points (Fritsch-Carlson method).
+// This ensures that the interpolated duty cycle does not overshoot between the points.
+fn monotone_slopes(points: &[(f32, f32)]) -> Vec<f32> {
+    let n = points.len();
+    let secants: Vec<f32> = points.windows(2).map(|pair| (pair[1].1 - pair[0].1) / (pair[1].0 - pair[0].0)).collect();
+    let mut slopes: Vec<f32> = vec![0.0; n];
+    slopes[0] = secants[0];
+    slopes[n - 1] = secants[n - 2];
+    for k in 1..n - 1 {
+        slopes[k] = if secants[k - 1] * secants[k] <= 0.0 {0.0} else {(secants[k - 1] + secants[k]) / 2.0};
+    }
+    for k in 0..n - 1 {
+        if secants[k] == 0.0 {
+            slopes[k] = 0.0;
+            slopes[k + 1] = 0.0;
+        } else {
+            let a = slopes[k] / secants[k];
+            let b = slopes[k + 1] / secants[k];
+            let s = a * a + b * b;
+            if s > 9.0 {
+                let tau = 3.0 / s.sqrt();
+                slopes[k] = tau * a * secants[k];
+                slopes[k + 1] = tau * b * secants[k];
+            }
+        }
+    }
+    slopes
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sample the curve in steps of 0.1 degree C from below the first to above the last point.
+    fn samples(curve: &FanCurve) -> Vec<f32> {
+        (300..=900).map(|k| curve.value(k as f32 / 10.0)).collect()
+    }
+
+    #[test]
+    fn values_at_and_beyond_the_points() {
+        let curve: FanCurve = "45:0.3, 55:0.4, 65:0.7, 75:1.0".parse().unwrap();
+        assert_eq!(curve.value(20.0), 0.3);
+        assert_eq!(curve.value(45.0), 0.3);
+        assert_eq!(curve.value(55.0), 0.4);
+        assert!((curve.value(60.0) - 0.55).abs() < 1e-6);
+        assert_eq!(curve.value(75.0), 1.0);
+        assert_eq!(curve.value(90.0), 1.0);
+    }
+
+    #[test]
+    fn interpolation_is_monotone() {
+        // A flat segment between steep ones makes a plain cubic spline overshoot.
+        let points = vec![(40.0, 0.2), (50.0, 0.25), (55.0, 0.8), (65.0, 0.8), (70.0, 1.0)];
+        for interpolation in [Interpolation::Linear, Interpolation::Smoothstep, Interpolation::Cubic] {
+            let curve = FanCurve::new(points.clone(), interpolation).unwrap();
+            let values = samples(&curve);
+            assert!(values.windows(2).all(|pair| pair[1] >= pair[0]), "{} is not monotone", interpolation);
+            assert!(values.iter().all(|value| (0.2..=1.0).contains(value)), "{} leaves the range of the points", interpolation);
+            assert_eq!(curve.value(60.0), 0.8, "{} does not stay flat", interpolation);
+        }
+    }
+
+    #[test]
+    fn invalid_curves_are_rejected() {
+        assert!("45:0.3".parse::<FanCurve>().is_err());
+        assert!("45:0.3, 45:0.4".parse::<FanCurve>().is_err());
+        assert!("45:0.5, 55:0.4".parse::<FanCurve>().is_err());
+        assert!("45:0.3, 55:1.2".parse::<FanCurve>().is_err());
+        assert!("45-0.3, 55:0.4".parse::<FanCurve>().is_err());
+        assert!(FanCurve::parse_rpm("45:1200, 60:2500").is_ok());
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let curve: FanCurve = "45:0.3, 55:0.4".parse().unwrap();
+        assert_eq!(curve.to_string(), "45:0.3, 55:0.4");
+        assert_eq!(curve.to_string().parse::<FanCurve>(), Ok(curve));
+    }
+}
@@ -0,0 +1,278 @@
+use rppal::gpio::{Gpio, OutputPin};
+use rppal::pwm::{Channel, Polarity, Pwm};
+use rppal::system::{DeviceInfo, SoC};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::{thread, time::Duration};
+use crate::functions::{log_event, Level};
+
+
+// How the PWM signal is generated (see init_driver).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PwmMode {
+    Auto,
+    Hardware,
+    Software,
+    Sysfs,
+    Mock,
+}
+
+impl FromStr for PwmMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode.trim().to_lowercase().as_str() {
+            "auto" => Ok(PwmMode::Auto),
+            "hardware" => Ok(PwmMode::Hardware),
+            "software" => Ok(PwmMode::Software),
+            "sysfs" => Ok(PwmMode::Sysfs),
+            "mock" => Ok(PwmMode::Mock),
+            _ => Err(format!("unknown PWM mode '{}', must be either auto, hardware, software, sysfs or mock", mode)),
+        }
+    }
+}
+
+impl fmt::Display for PwmMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PwmMode::Auto => write!(f, "auto"),
+            PwmMode::Hardware => write!(f, "hardware"),
+            PwmMode::Software => write!(f, "software"),
+            PwmMode::Sysfs => write!(f, "sysfs"),
+            PwmMode::Mock => write!(f, "mock"),
+        }
+    }
+}
+
+
+// What a fan driver is able to do.
+pub struct Capability {
+    pub name: &'static str, // ... Name of the driver for log messages.
+    pub hardware_timed: bool, // .. Whether the PWM signal is generated by hardware (i.e. without jitter from the scheduler).
+}
+
+
+// Fan driver.
+// Every way of generating the PWM signal for the fan implements this trait, so that the control loop does not depend on the hardware.
+// All drivers keep the PWM signal running on their own. The control loop only has to set a new duty cycle when it changes.
+pub trait FanDriver {
+
+    // Set the duty cycle of the PWM signal between 0.0 (0 percent) and 1.0 (100 percent).
+    fn set_duty(&mut self, duty_cycle: f32);
+
+    // Turn the fan off.
+    fn off(&mut self) {
+        self.set_duty(0.0);
+    }
+
+    // Report what the driver is able to do.
+    fn capability(&self) -> Capability;
+}
+
+
+// Software PWM using rppal.
+// rppal switches the GPIO pin on and off in a background thread.
+pub struct SoftwarePwm {
+    pin: OutputPin,
+    pwm_freq: u64,
+    duty_cycle: Option<f32>,
+}
+
+impl SoftwarePwm {
+    pub fn new(gpio_pin: u8, pwm_freq: u64) -> Result<SoftwarePwm, String> {
+        let gpio = Gpio::new().map_err(|e| format!("Failed to initialize GPIO: {}", e))?;
+        let pin = gpio.get(gpio_pin).map_err(|e| format!("Failed to access GPIO pin {}: {}", gpio_pin, e))?.into_output();
+        Ok(SoftwarePwm { pin, pwm_freq, duty_cycle: None })
+    }
+}
+
+impl FanDriver for SoftwarePwm {
+    fn set_duty(&mut self, duty_cycle: f32) {
+        // Only restart the PWM signal if the duty cycle changed.
+        if self.duty_cycle == Some(duty_cycle) {
+            return;
+        }
+        // At 0 and 100 percent, the pin is simply set low or high without a PWM thread.
+        if duty_cycle <= 0.0 {
+            let _ = self.pin.clear_pwm();
+            self.pin.set_low();
+        } else if duty_cycle >= 1.0 {
+            let _ = self.pin.clear_pwm();
+            self.pin.set_high();
+        } else if self.pin.set_pwm_frequency(self.pwm_freq as f64, duty_cycle as f64).is_err() {
+            return;
+        }
+        self.duty_cycle = Some(duty_cycle);
+    }
+
+    fn capability(&self) -> Capability {
+        Capability { name: "software PWM", hardware_timed: false }
+    }
+}
+
+
+// Hardware PWM using the PWM peripheral of the Raspberry Pi through rppal.
+pub struct HardwarePwm {
+    pwm: Pwm,
+    duty_cycle: Option<f32>,
+}
+
+impl HardwarePwm {
+    pub fn new(gpio_pin: u8, pwm_freq: u64) -> Result<HardwarePwm, String> {
+        let channel = pwm_channel(gpio_pin).ok_or_else(|| format!("GPIO pin {} has no hardware PWM channel", gpio_pin))?;
+        let pwm = Pwm::with_frequency(channel, pwm_freq as f64, 0.0, Polarity::Normal, true)
+            .map_err(|e| format!("Failed to initialize hardware PWM: {} (is the PWM overlay enabled in config.txt?)", e))?;
+        Ok(HardwarePwm { pwm, duty_cycle: None })
+    }
+}
+
+impl FanDriver for HardwarePwm {
+    fn set_duty(&mut self, duty_cycle: f32) {
+        // Only write the duty cycle if it changed, since every write is a sysfs access.
+        if self.duty_cycle != Some(duty_cycle) && self.pwm.set_duty_cycle(duty_cycle as f64).is_ok() {
+            self.duty_cycle = Some(duty_cycle);
+        }
+    }
+
+    fn capability(&self) -> Capability {
+        Capability { name: "hardware PWM", hardware_timed: true }
+    }
+}
+
+
+// Map a GPIO pin to its hardware PWM channel.
+// On the Raspberry Pi 5 (RP1), each of the four pins has its own channel. On older models, GPIO 12/18 and GPIO 13/19 share a channel.
+pub fn pwm_channel(gpio_pin: u8) -> Option<Channel> {
+    let rp1 = DeviceInfo::new().map(|info| info.soc() == SoC::Bcm2712).unwrap_or(false);
+    match (gpio_pin, rp1) {
+        (12, true) => Some(Channel::Pwm0),
+        (13, true) => Some(Channel::Pwm1),
+        (18, true) => Some(Channel::Pwm2),
+        (19, true) => Some(Channel::Pwm3),
+        (12 | 18, false) => Some(Channel::Pwm0),
+        (13 | 19, false) => Some(Channel::Pwm1),
+        _ => None,
+    }
+}
+
+
+// Hardware PWM using the generic Linux sysfs interface (/sys/class/pwm).
+// This works on any board with a PWM driver, not only on the Raspberry Pi.
+pub struct SysfsPwm {
+    chip: PathBuf,
+    channel: u32,
+    period_nanos: u64,
+    duty_cycle: Option<f32>,
+}
+
+impl SysfsPwm {
+    pub fn new(pwm_chip: u32, pwm_channel: u32, pwm_freq: u64) -> Result<SysfsPwm, String> {
+        let chip = PathBuf::from(format!("/sys/class/pwm/pwmchip{}", pwm_chip));
+        let channel_dir = chip.join(format!("pwm{}", pwm_channel));
+
+        // Export the channel if it is not already exported and wait for udev to set up the files.
+        if !channel_dir.exists() {
+            fs::write(chip.join("export"), pwm_channel.to_string())
+                .map_err(|e| format!("Failed to export channel {} of {}: {}", pwm_channel, chip.display(), e))?;
+            for _ in 0..20 {
+                if channel_dir.join("enable").exists() {break;}
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        let sysfs = SysfsPwm { chip, channel: pwm_channel, period_nanos: 1_000_000_000 / pwm_freq, duty_cycle: None };
+
+        // Set the duty cycle to 0 first in case the new period is shorter than the current duty cycle.
+        let _ = sysfs.write("duty_cycle", 0);
+        sysfs.write("period", sysfs.period_nanos)?;
+        sysfs.write("enable", 1)?;
+        Ok(sysfs)
+    }
+
+    // Write a value to one of the files of the channel.
+    fn write(&self, file: &str, value: u64) -> Result<(), String> {
+        let path = self.chip.join(format!("pwm{}", self.channel)).join(file);
+        fs::write(&path, value.to_string()).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+}
+
+impl FanDriver for SysfsPwm {
+    fn set_duty(&mut self, duty_cycle: f32) {
+        // Only write the duty cycle if it changed.
+        if self.duty_cycle != Some(duty_cycle) && self.write("duty_cycle", (self.period_nanos as f64 * duty_cycle.clamp(0.0, 1.0) as f64) as u64).is_ok() {
+            self.duty_cycle = Some(duty_cycle);
+        }
+    }
+
+    fn capability(&self) -> Capability {
+        Capability { name: "sysfs PWM", hardware_timed: true }
+    }
+}
+
+impl Drop for SysfsPwm {
+    // Disable and unexport the channel when the program ends.
+    fn drop(&mut self) {
+        let _ = self.write("duty_cycle", 0);
+        let _ = self.write("enable", 0);
+        let _ = fs::write(self.chip.join("unexport"), self.channel.to_string());
+    }
+}
+
+
+// In-memory driver without any hardware access.
+// Used to run the control logic on machines without GPIO (e.g. for testing on a laptop).
+#[derive(Default)]
+pub struct MockDriver {
+    pub duty_cycle: f32, // ... Last duty cycle that was set.
+    pub writes: u64, // ....... Number of duty cycle changes.
+}
+
+impl FanDriver for MockDriver {
+    fn set_duty(&mut self, duty_cycle: f32) {
+        if self.duty_cycle != duty_cycle {
+            self.duty_cycle = duty_cycle;
+            self.writes += 1;
+        }
+    }
+
+    fn capability(&self) -> Capability {
+        // There is no PWM signal at all, so there is no jitter either. Reporting false would log the jitter note for software PWM.
+        Capability { name: "mock", hardware_timed: true }
+    }
+}
+
+
+// Set up the fan driver.
+// Depending on pwm_mode, the hardware PWM peripheral ("hardware"), software PWM ("software"), the sysfs interface ("sysfs") or the mock driver ("mock") is used.
+// With "auto", the hardware PWM peripheral is tried first with software PWM as the fallback.
+// If no driver can be set up, the program exits.
+pub fn init_driver(gpio_pin: u8, pwm_mode: PwmMode, pwm_freq: u64, pwm_chip: u32, pwm_channel: u32, log_option: bool) -> Box<dyn FanDriver> {
+
+    let driver: Result<Box<dyn FanDriver>, String> = match pwm_mode {
+        PwmMode::Hardware => HardwarePwm::new(gpio_pin, pwm_freq).map(|d| Box::new(d) as Box<dyn FanDriver>),
+        PwmMode::Software => SoftwarePwm::new(gpio_pin, pwm_freq).map(|d| Box::new(d) as Box<dyn FanDriver>),
+        PwmMode::Sysfs => SysfsPwm::new(pwm_chip, pwm_channel, pwm_freq).map(|d| Box::new(d) as Box<dyn FanDriver>),
+        PwmMode::Mock => Ok(Box::new(MockDriver::default())),
+        PwmMode::Auto => HardwarePwm::new(gpio_pin, pwm_freq).map(|d| Box::new(d) as Box<dyn FanDriver>).or_else(|e| {
+            log_event(Level::Warn, &format!("{}. Using software PWM as fallback.", e), log_option);
+            SoftwarePwm::new(gpio_pin, pwm_freq).map(|d| Box::new(d) as Box<dyn FanDriver>)
+        }),
+    };
+
+    match driver {
+        Ok(driver) => {
+            let capability = driver.capability();
+            log_event(Level::Info, &format!("Using {} to control the fan.", capability.name), log_option);
+            if !capability.hardware_timed {
+                log_event(Level::Info, &format!("Note: The PWM signal of the {} is timed by the CPU and the duty cycle may jitter under load.", capability.name), log_option);
+            }
+            driver
+        }
+        Err(e) => {
+            log_event(Level::Error, &format!("{}. Exiting.", e), log_option);
+            std::process::exit(1);
+        }
+    }
+}
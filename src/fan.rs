@@ -0,0 +1,406 @@
+use std::time::{Instant, SystemTime};
+use crate::config::{Config, ControlMode};
+use crate::control::Override;
+use crate::driver::FanDriver;
+use crate::functions::{log_event, Level};
+use crate::pid::Pid;
+use crate::stall::StallDetector;
+use crate::state::RuntimeState;
+
+
+// Inputs of a single control cycle.
+pub struct Inputs {
+    pub temp: Option<f32>, // ... Temperature of the cycle (the dampened temperature of the last reading in cycles without a reading), none if it could not be read.
+    pub temp_read: bool, // ..... Whether the temperature was read in this cycle.
+    pub rpm: Option<f32>, // .... Measured fan speed, none without a tachometer or a valid measurement.
+    pub tach: bool, // .......... Whether a tachometer is used (only then the stall detection runs).
+}
+
+
+// Control of the fan, i.e. everything that is decided in a single cycle of the control loop.
+// Turns the fan on and off with the hysteresis of min_temp and off_temp and the delay, calculates the duty cycle with the fan curve, the PID controller
+// or the RPM controller, kick-starts the fan, runs a stalled fan at full power and applies a fan speed set with the control socket.
+// The fan driver is passed to every cycle, so the control can be tested with the mock driver.
+pub struct FanControl {
+    pub mode: ControlMode,
+    pub fan_on: bool,
+    pub duty_cycle: f32, // ................... Duty cycle the fan was set to in the last cycle (0.0 if it is off).
+    pub state_change_time: SystemTime, // ..... Time point of the last state change of the fan (on or off).
+    pub kick_until: Option<Instant>, // ....... End of the kick-start, while the fan is kick-started.
+    pub temp_dampened: Option<f32>, // ........ Dampened temperature of the last valid reading.
+    pub manual: Option<Override>, // .......... Fan speed set with the control socket, which replaces the automatic control.
+    pub override_duty_cycle: Option<f32>, // .. Duty cycle of a kick-start or of the stall detection in the last cycle, which overrides the calculated one.
+    pub pid: Pid,
+    pid_time: Instant,
+    pub rpm_pid: Pid,
+    rpm_time: Instant,
+    pub stall: StallDetector,
+}
+
+impl FanControl {
+
+    // Set up the control of a fan that is already running at full power (fan_on) or off.
+    // The time point of the last state change is set back by the delay duration, so that the fan can change its state immediately.
+    pub fn new(config: &Config, mode: ControlMode, fan_on: bool) -> FanControl {
+        let state_change_time = SystemTime::now()
+            .checked_sub(config.control.delay_duration)
+            .unwrap_or_else(|| {log_event(Level::Error, "Failed to subtract delay from current time. Using current time as fallback.", config.logging.log_option);SystemTime::now()});
+        FanControl {
+            mode,
+            fan_on,
+            duty_cycle: if fan_on {1.0} else {0.0},
+            state_change_time,
+            kick_until: None,
+            temp_dampened: None,
+            manual: None,
+            override_duty_cycle: None,
+            // The output of the PID controllers is limited to the min and max speed. The time between updates is measured to calculate the integral and the derivative.
+            pid: Pid::new(config.control.pid.clone(), config.control.min_duty_cycle, config.control.max_duty_cycle),
+            pid_time: Instant::now(),
+            rpm_pid: Pid::reverse(config.control.rpm_pid.clone(), config.control.min_duty_cycle, config.control.max_duty_cycle),
+            rpm_time: Instant::now(),
+            stall: StallDetector::new(config.tach.stall.clone()),
+        }
+    }
+
+
+    // Run a single control cycle and set the duty cycle of the fan.
+    // Returns the duty cycle the fan is run at (0.0 if it is off), which is also kept in duty_cycle.
+    #[allow(clippy::never_loop)]
+    pub fn step(&mut self, driver: &mut dyn FanDriver, config: &Config, state: &mut RuntimeState, inputs: &Inputs) -> f32 {
+        let log_option = config.logging.log_option;
+        let fan_on_prev = self.fan_on;
+
+
+        // If there was no valid temperature reading, set the fan to high.
+        if inputs.temp_read && inputs.temp.is_none() {
+            driver.set_duty(1.0);
+        }
+
+
+        // End a fan speed set with the control socket when it expired or the temperature reached emergency_temp.
+        if let Some(current) = self.manual {
+            if let Some(temp) = inputs.temp.filter(|&temp| temp >= config.socket.emergency_temp) {
+                self.manual = None;
+                log_event(Level::Warn, &format!("The temperature of {:.1} °C reached emergency_temp ({:.1} °C). Cancelling the manually set fan speed and returning to automatic control.",
+                    temp, config.socket.emergency_temp), log_option);
+            } else if current.expired() {
+                self.manual = None;
+                log_event(Level::Info, "The manually set fan speed expired. Returning to automatic control.", log_option);
+            }
+        }
+
+
+        // Check if the fan is stalled (if a tachometer is used).
+        // While a stalled fan is kicked or if it failed, it is run at full power regardless of the calculated fan speed.
+        let mut override_duty_cycle: Option<f32> = None;
+        if inputs.tach {
+            override_duty_cycle = self.stall.update(inputs.rpm, if self.fan_on {self.manual.map_or(state.duty_cycle, |manual| manual.duty_cycle)} else {0.0}, log_option);
+        }
+
+
+        // While the fan is kick-started, it runs at the kick duty cycle (unless the calculated fan speed is higher).
+        if self.kick_until.is_some_and(|until| Instant::now() >= until) {
+            self.kick_until = None;
+        }
+        override_duty_cycle = override_duty_cycle.or(self.kick_until.map(|_| config.control.kick_duty_cycle.max(state.duty_cycle)));
+
+
+        // Check if the current and the previous temperature are the same.
+        // Only returns true if both are some value.
+        let same_temps = self.temp_dampened.zip(inputs.temp).is_some_and(|(old, new)| old == new);
+
+
+        // Only adjust fan speed if there is a valid temperature reading.
+        // Else, the fan was already set to full speed.
+        if let Some(mut temp) = inputs.temp {
+
+
+            // Dampen fan speed changes.
+            // Fast fan speed changes or frequent acceleration and deceleration of the fan should be avoided.
+            // Since fan speed directly correlates to CPU temperature, the change rate of the temperature is dampened.
+            // Only do this if there is a valid value for the previous temperature.
+            if let Some(temp_old) = self.temp_dampened {
+                temp = if same_temps {
+                    // If both temperatures are the same, use the current one without dampening.
+                    temp
+                } else if temp < temp_old {
+                    // If the current temperature is colder than the previous one, dampen using the cold factor.
+                    temp * config.control.factor_cold_new + temp_old * config.control.factor_cold_old
+                } else {
+                    // If the current temperature is hotter than the previous one, dampen using the hot factor.
+                    temp * config.control.factor_hot_new + temp_old * config.control.factor_hot_old
+                };
+            };
+
+            // Assign the dampened temperature as the previous one for the next cycle,
+            self.temp_dampened = Some(temp);
+
+
+            // Calculate duty cycle.
+            // The speed at which the fan should be turning is calculated based on the dampened temperature.
+            state.duty_cycle = if self.mode == ControlMode::Pid {
+                // In PID mode, update the controller with every new temperature reading (even if the temperature did not change).
+                // While the fan is off, the controller is kept in its initial state, so the integral does not wind up without any effect on the temperature.
+                if inputs.temp_read && self.fan_on {
+                    let dt = self.pid_time.elapsed().as_secs_f32();
+                    self.pid_time = Instant::now();
+                    self.pid.update(temp, dt)
+                } else {state.duty_cycle_old}
+            } else if self.mode == ControlMode::Rpm {
+                // In RPM mode, the fan curve gives the target fan speed and the duty cycle is adjusted until the measured fan speed matches it.
+                // While the fan is off, the controller is kept in its initial state.
+                let dt = self.rpm_time.elapsed().as_secs_f32();
+                self.rpm_time = Instant::now();
+                self.rpm_pid.set_target(config.control.rpm_curve.value(temp));
+                match inputs.rpm {
+                    Some(rpm) if self.fan_on => self.rpm_pid.update(rpm, dt),
+                    _ => state.duty_cycle_old,
+                }
+            } else if same_temps {
+                // If the temperatures did not change, do not recalculate the fan speed but use the previous one.
+                state.duty_cycle_old
+            } else {
+                // Use the fan curve to calculate the fan speed, limited to the min and max speed.
+                config.control.fan_curve.value(temp).clamp(config.control.min_duty_cycle, config.control.max_duty_cycle)
+            };
+
+            // Assign the current fan speed as the previous on for the next cycle.
+            state.duty_cycle_old = state.duty_cycle;
+
+
+            // Time since the last state change of the fan.
+            // To avoid frequently turning the fan on and off, the duration since the last state change is calculated.
+            // Not to be confused with on off in the context of regulating fan speed using PWM.
+            let elapsed_time = self.state_change_time.elapsed().unwrap_or_default();
+
+
+            // Adjust the fan speed.
+            // According to the dampened temperature, the current fan state and the duration since the last state change.
+            // The loop is not repeated. It is always stopped with a break signal in its initial iteration.
+            loop {
+
+
+                // Run the fan at the duty cycle set with the control socket.
+                // A stalled fan is still kicked.
+                if let Some(duty_cycle) = self.manual.map(|manual| manual.duty_cycle) {
+                    if self.fan_on != (duty_cycle > 0.0) {
+                        self.state_change_time = SystemTime::now();
+                    }
+                    self.fan_on = duty_cycle > 0.0;
+                    if self.fan_on {driver.set_duty(override_duty_cycle.unwrap_or(duty_cycle))} else {driver.off()};
+                    break;
+                }
+
+
+                // Keep the fan off.
+                // If the fan is not running and the temperature is below the minimum temperature, keep the fan off.
+                if !self.fan_on && temp <= config.control.min_temp {
+                    break;
+                }
+
+
+                // Keep the fan on.
+                // If the fan is running and the temperature is above the off temperature, keep the fan running.
+                // Fan speed is set to the calculated speed based on the dampened temperature.
+                if self.fan_on && temp > config.control.off_temp {
+                    driver.set_duty(override_duty_cycle.unwrap_or(state.duty_cycle));
+                    break;
+                }
+
+
+                // Turn the fan off.
+                // The fan is running but the temperature is below the off temperature.
+                // If the time since the fan was started is above the delay duration, turn the fan off.
+                if self.fan_on && temp <= config.control.off_temp && elapsed_time >= config.control.delay_duration {
+                    self.state_change_time = SystemTime::now();
+                    self.fan_on = false;
+                    driver.off();
+                    self.kick_until = None;
+                    self.pid.reset();
+                    self.rpm_pid.reset();
+                    break;
+                }
+
+
+                // Turn the fan on.
+                // The fan is not running but the temperature is above the minimum temperature.
+                // If the time since the fan was stopped is above the delay duration, turn the fan on.
+                if !self.fan_on && temp > config.control.min_temp && elapsed_time >= config.control.delay_duration {
+                    self.state_change_time = SystemTime::now();
+                    self.fan_on = true;
+                    // Kick-start the fan from standstill, if enabled and the calculated fan speed is lower than the kick duty cycle.
+                    if !config.control.kick_duration.is_zero() && config.control.kick_duty_cycle > state.duty_cycle {
+                        self.kick_until = Some(Instant::now() + config.control.kick_duration);
+                        log_event(Level::Info, &format!("Kick-starting the fan at {:.0} % for {:.1} s.", config.control.kick_duty_cycle * 100.0, config.control.kick_duration.as_secs_f32()), log_option);
+                    }
+                    override_duty_cycle = override_duty_cycle.or(self.kick_until.map(|_| config.control.kick_duty_cycle));
+                    driver.set_duty(override_duty_cycle.unwrap_or(state.duty_cycle));
+                    break;
+                }
+
+
+                // Keep the fan on, due to time delay.
+                // The fan is running and the temperature is below the off temperature.
+                // However, the time since the fan was started is still below the delay duration.
+                // The fan will keep running and the fan speed is set to the calculated speed based on the dampened temperature.
+                if self.fan_on && temp <= config.control.off_temp && elapsed_time < config.control.delay_duration {
+                    driver.set_duty(override_duty_cycle.unwrap_or(state.duty_cycle));
+                    break;
+                }
+
+
+                // Keep the fan off, due to time delay.
+                // The fan is not running and the temperature is above the minimum temperature.
+                // However, the time since the fan was stopped is still below the delay duration.
+                // The fan will not start running but stay off.
+                if !self.fan_on && temp > config.control.min_temp && elapsed_time < config.control.delay_duration {
+                    break;
+                }
+
+
+                // In case none of the other options triggered, break.
+                // This should not be required since all possibilities should be covered by the previous options.
+                break;
+            }
+        }
+
+
+        // When the fan was turned on (automatically or with the control socket), start the controllers from their initial state,
+        // so the first update does not use the time the fan was off.
+        if self.fan_on && !fan_on_prev {
+            self.pid.reset();
+            self.pid_time = Instant::now();
+            self.rpm_pid.reset();
+            self.rpm_time = Instant::now();
+        }
+        self.override_duty_cycle = override_duty_cycle;
+
+
+        // Duty cycle the fan is run at (full power without a temperature, a stalled or kick-started fan, a fan speed set with the control socket or the calculated fan speed).
+        self.duty_cycle = if inputs.temp.is_none() {
+            1.0
+        } else if self.fan_on {
+            override_duty_cycle.or(self.manual.map(|manual| manual.duty_cycle)).unwrap_or(state.duty_cycle)
+        } else {
+            0.0
+        };
+        self.duty_cycle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use crate::driver::MockDriver;
+
+    // Configuration without dampening and without delay, with a fan curve that gives 0.6 at 60 °C.
+    fn config(settings: &[(&str, &str)]) -> Config {
+        let defaults = [("fan_curve", "50:0.4, 60:0.6, 70:1.0"), ("min_temp", "50"), ("off_temp", "45"), ("delay_time", "0"), ("delay_hot", "0"), ("delay_cold", "0"), ("log_option", "false")];
+        Config::from_settings(&[&defaults[..], settings].concat())
+    }
+
+    fn reading(temp: f32) -> Inputs {
+        Inputs { temp: Some(temp), temp_read: true, rpm: None, tach: false }
+    }
+
+    #[test]
+    fn kick_start_then_calculated_duty_cycle() {
+        let config = config(&[("kick_duty_cycle", "1.0"), ("kick_time", "0.05")]);
+        let mut state = RuntimeState::new(config.control.max_duty_cycle);
+        let mut driver = MockDriver::default();
+        let mut control = FanControl::new(&config, ControlMode::Curve, false);
+
+        assert_eq!(control.step(&mut driver, &config, &mut state, &reading(60.0)), 1.0);
+        assert!(control.fan_on);
+        assert_eq!((driver.duty_cycle, driver.writes), (1.0, 1));
+
+        // Still kicked within kick_time.
+        control.step(&mut driver, &config, &mut state, &reading(60.0));
+        assert_eq!((driver.duty_cycle, driver.writes), (1.0, 1));
+
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(control.step(&mut driver, &config, &mut state, &reading(60.0)), 0.6);
+        assert_eq!((driver.duty_cycle, driver.writes), (0.6, 2));
+        assert!(control.kick_until.is_none());
+    }
+
+    #[test]
+    fn manual_duty_cycle_until_it_expires() {
+        let config = config(&[]);
+        let mut state = RuntimeState::new(config.control.max_duty_cycle);
+        let mut driver = MockDriver::default();
+        let mut control = FanControl::new(&config, ControlMode::Curve, true);
+
+        control.manual = Some(Override::new(0.8, None, Duration::from_secs(60)));
+        assert_eq!(control.step(&mut driver, &config, &mut state, &reading(60.0)), 0.8);
+        assert_eq!((driver.duty_cycle, driver.writes), (0.8, 1));
+
+        // Turning the fan off manually overrides the temperature.
+        control.manual = Some(Override::new(0.0, None, Duration::from_secs(60)));
+        assert_eq!(control.step(&mut driver, &config, &mut state, &reading(60.0)), 0.0);
+        assert!(!control.fan_on);
+        assert_eq!((driver.duty_cycle, driver.writes), (0.0, 2));
+
+        // After expiry, the automatic control turns the fan on again.
+        control.manual = Some(Override::new(0.0, Some(Duration::ZERO), Duration::from_secs(60)));
+        assert_eq!(control.step(&mut driver, &config, &mut state, &reading(60.0)), 0.6);
+        assert!(control.manual.is_none() && control.fan_on);
+        assert_eq!((driver.duty_cycle, driver.writes), (0.6, 3));
+    }
+
+    #[test]
+    fn fan_turns_off_below_off_temp_after_the_delay() {
+        let config = config(&[]);
+        let mut state = RuntimeState::new(config.control.max_duty_cycle);
+        let mut driver = MockDriver::default();
+        let mut control = FanControl::new(&config, ControlMode::Curve, true);
+
+        assert_eq!(control.step(&mut driver, &config, &mut state, &reading(60.0)), 0.6);
+
+        // Between off_temp and min_temp, a running fan keeps running.
+        control.step(&mut driver, &config, &mut state, &reading(48.0));
+        assert!(control.fan_on);
+
+        assert_eq!(control.step(&mut driver, &config, &mut state, &reading(40.0)), 0.0);
+        assert!(!control.fan_on);
+        assert_eq!(driver.duty_cycle, 0.0);
+
+        // Between off_temp and min_temp, a stopped fan stays off.
+        let writes = driver.writes;
+        assert_eq!(control.step(&mut driver, &config, &mut state, &reading(48.0)), 0.0);
+        assert_eq!((driver.duty_cycle, driver.writes), (0.0, writes));
+    }
+
+    #[test]
+    fn fan_stays_on_during_the_delay() {
+        let config = config(&[("delay_time", "60000")]);
+        let mut state = RuntimeState::new(config.control.max_duty_cycle);
+        let mut driver = MockDriver::default();
+        let mut control = FanControl::new(&config, ControlMode::Curve, true);
+
+        control.step(&mut driver, &config, &mut state, &reading(60.0));
+        control.state_change_time = SystemTime::now();
+        assert!(control.step(&mut driver, &config, &mut state, &reading(40.0)) > 0.0);
+        assert!(control.fan_on);
+        assert!(driver.duty_cycle > 0.0);
+    }
+
+    #[test]
+    fn full_speed_without_a_temperature() {
+        let config = config(&[]);
+        let mut state = RuntimeState::new(config.control.max_duty_cycle);
+        let mut driver = MockDriver::default();
+        let mut control = FanControl::new(&config, ControlMode::Curve, true);
+
+        control.step(&mut driver, &config, &mut state, &reading(60.0));
+        assert_eq!(control.step(&mut driver, &config, &mut state, &Inputs { temp: None, temp_read: true, rpm: None, tach: false }), 1.0);
+        assert_eq!(driver.duty_cycle, 1.0);
+
+        // Also between the readings.
+        assert_eq!(control.step(&mut driver, &config, &mut state, &Inputs { temp: None, temp_read: false, rpm: None, tach: false }), 1.0);
+        assert_eq!(control.duty_cycle, 1.0);
+    }
+}
@@ -1,68 +1,288 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::path::Path;
 use csv::Writer;
 use std::time::SystemTime;
+use std::sync::RwLock;
 use std::fs::OpenOptions;
-use std::io::Write;
-use chrono::{DateTime, Local};
+use std::io::{self, IsTerminal, Write};
+use std::fmt;
+use std::str::FromStr;
+use chrono::{DateTime, Local, SecondsFormat};
+use crate::config::{LoggingConfig, StatsConfig};
+use crate::state::RuntimeState;
+use crate::rotate::Rotation;
+use crate::log_backend::{send_journal, send_syslog, LogBackend, LogFields};
 
 
-// CPU Temperature Function.
-pub fn read_cpu_temperature() -> Option<f32> {
-    if let Ok(contents) = std::fs::read_to_string("/sys/class/thermal/thermal_zone0/temp") {
-        if let Ok(temp) = contents.trim().parse::<f32>() {
-            let rounded_temp = (temp / 1000.0 * 100.0).round() / 100.0;
-            return Some(rounded_temp);
+// Settings of the logging (before they are set: event.log without rotation, level info, text format).
+static LOGGING: RwLock<Option<LoggingConfig>> = RwLock::new(None);
+
+
+// Latest state of the fan control, added to the events in the journal.
+static LOG_FIELDS: RwLock<Option<LogFields>> = RwLock::new(None);
+
+
+// Level of an event, from the most to the least important.
+// Events less important than log_level are neither printed nor written to the log file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug, // ... Tracing of every control cycle.
+    Trace, // ... Additionally the readings of every sensor.
+}
+
+impl FromStr for Level {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" | "warning" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        })
+    }
+}
+
+
+// Format of the log file. Events are always printed as text.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Text, // ... [<time>] <Level>: <message>
+    Json, // ... One JSON object per line with the fields time, level and message.
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        })
+    }
+}
+
+
+// Set the settings of the logging.
+// The directory of the log file is created if it does not exist. Returns an error message if the log file cannot be written.
+pub fn set_logging(logging: &LoggingConfig) -> Result<(), String> {
+    if let Ok(mut settings) = LOGGING.write() {
+        *settings = Some(logging.clone());
+    }
+    if !logging.log_option || logging.backend != LogBackend::File {
+        return Ok(());
+    }
+    let path = &logging.log_path;
+    create_dir(path)?;
+    logging.rotation.rotate(path)?;
+    OpenOptions::new().append(true).create(true).open(path).map(|_| ()).map_err(|e| format!("Cannot write the log file {}: {}", path, e))
+}
+
+
+// Rotate the log file if it is too large or too old (see Rotation).
+// This is done once per statistics cycle of the control loop (and when the logging is set up) instead of before every event,
+// so that the file system is not queried for every line written to the log file.
+pub fn rotate_log() {
+    let Some(logging) = LOGGING.read().ok().and_then(|settings| settings.clone()) else {
+        return;
+    };
+    if logging.log_option && logging.backend == LogBackend::File {
+        if let Err(e) = logging.rotation.rotate(&logging.log_path) {
+            log_event(Level::Error, &e, logging.log_option);
         }
     }
-    None
 }
 
+
+// Create the directory of a file if it does not exist.
+pub fn create_dir(file_path: &str) -> Result<(), String> {
+    match Path::new(file_path).parent().filter(|dir| !dir.as_os_str().is_empty() && !dir.exists()) {
+        Some(dir) => fs::create_dir_all(dir).map_err(|e| format!("Cannot create the directory {}: {}", dir.display(), e)),
+        None => Ok(()),
+    }
+}
+
+
+// Hostname of the board, e.g. for the default MQTT topic. "raspberrypi" if it cannot be read.
+pub fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname").ok()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "raspberrypi".to_string())
+}
+
+
+// Update the state of the fan control that is added to the events in the journal.
+pub fn set_log_fields(fields: LogFields) {
+    if let Ok(mut log_fields) = LOG_FIELDS.write() {
+        *log_fields = Some(fields);
+    }
+}
+
+
+// Check if events of a level are logged, e.g. to skip formatting the tracing of the control cycle.
+pub fn log_enabled(level: Level) -> bool {
+    let log_level = LOGGING.read().ok().and_then(|settings| settings.as_ref().map(|settings| settings.level)).unwrap_or(Level::Info);
+    level <= log_level
+}
+
+
 // Logging function.
-pub fn log_event(message: &str, log_option: bool) {
-    let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
-    let formatted_message = format!("[{}] {}", timestamp, message);
+pub fn log_event(level: Level, message: &str, log_option: bool) {
+    if !log_enabled(level) {
+        return;
+    }
+
+    let now = Local::now();
+    let prefix = match level {
+        Level::Error => "Error: ",
+        Level::Warn => "Warning: ",
+        Level::Info => "",
+        Level::Debug => "Debug: ",
+        Level::Trace => "Trace: ",
+    };
+    let formatted_message = format!("[{}] {}{}", now.format("%Y-%m-%d %H:%M:%S"), prefix, message);
+    let (log_file, format, backend) = match LOGGING.read().ok().and_then(|settings| settings.clone()) {
+        Some(logging) => (logging.log_path, logging.format, logging.backend),
+        None => ("event.log".to_string(), LogFormat::Text, LogBackend::File),
+    };
+
+    // Send the event to the journal or to syslog.
+    // The event is only printed as well if it could not be sent or if the program runs in a terminal, so it is not logged twice by systemd.
+    if log_option && backend != LogBackend::File {
+        let fields = LOG_FIELDS.read().ok().and_then(|fields| *fields);
+        let sent = match backend {
+            LogBackend::Journald => send_journal(level, message, fields),
+            _ => send_syslog(level, message),
+        };
+        match sent {
+            Ok(()) if !io::stderr().is_terminal() => (),
+            Ok(()) => eprintln!("{}", formatted_message),
+            Err(e) => eprintln!("{} ({})", formatted_message, e),
+        }
+        return;
+    }
 
     eprintln!("{}", formatted_message);
 
     if log_option {
-        if let Ok(mut file) = OpenOptions::new().append(true).create(true).open("event.log") {
-            let _ = writeln!(file, "{}", formatted_message);
+        let line = match format {
+            LogFormat::Text => formatted_message,
+            LogFormat::Json => format!("{{\"time\":{},\"level\":{},\"message\":{}}}",
+                json_string(&now.to_rfc3339_opts(SecondsFormat::Millis, false)), json_string(&level.to_string()), json_string(message)),
+        };
+        if let Ok(mut file) = OpenOptions::new().append(true).create(true).open(log_file) {
+            let _ = writeln!(file, "{}", line);
         }
     }
 }
 
+
+// Quote and escape a string for JSON.
+pub fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Statistics (min, max, avg) of a single value, e.g. the temperature of a sensor or the fan speed in RPM.
+#[derive(Clone, Default)]
+pub struct ValueStat {
+    pub name: String,
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+    pub avg: Option<f32>,
+    pub n: f32, // ... Number of valid values. The average is only calculated for the number of valid values.
+}
+
+impl ValueStat {
+    // Add a value to the statistics. On the first iteration of a statistics period, only the current value is assigned.
+    fn add(&mut self, value: Option<f32>, first: bool) {
+        if first {
+            self.n = if value.is_some() {1.0} else {0.0};
+            self.min = value;
+            self.max = value;
+            self.avg = value;
+        } else {
+            if value.is_some() {self.n += 1.0;}
+            self.min = min_option(self.min, value);
+            self.max = max_option(self.max, value);
+            self.avg = avg_option(self.avg, value, self.n);
+        }
+    }
+
+    // Format min, max and avg for the .csv file.
+    fn to_record(&self) -> [String; 3] {
+        [
+            self.min.map_or("NaN".to_string(), |v| format!("{:.3}", v)),
+            self.max.map_or("NaN".to_string(), |v| format!("{:.3}", v)),
+            self.avg.map_or("NaN".to_string(), |v| format!("{:.3}", v)),
+        ]
+    }
+}
+
+
 // Function to log statistics and update values.
+// The statistics of the current period are kept in the runtime state.
+#[allow(clippy::too_many_arguments)]
 pub fn log_statistics
 (
 // Define input arguments and types.
-    i_stat: &mut u64, n_on_temp: &mut f32, n_on_fan: &mut f32,
-    stat_min_time: u64, stat_max_time: u64, stat_delta_t: f32, stat_int_start: &mut SystemTime,
+    state: &mut RuntimeState,
+    stats: &StatsConfig,
     log_option: bool,
-    duty_cycle: f32,
     cpu_temp_missing: bool,
-    cpu_temp_orig: Option<f32>, cpu_temp_min: &mut Option<f32>, cpu_temp_max: &mut Option<f32>, cpu_temp_avg: &mut Option<f32>,
-    fan_on: bool,
-    fan_state_stat: &mut f32, fan_state_prev: &mut bool,
-    fan_speed_min: &mut Option<f32>, fan_speed_max: &mut Option<f32>, fan_speed_avg: &mut Option<f32>,
-    file_path: &str, shdn_recv: bool, rld_recv: bool
-) -> (
-
-// Define output types.
-    u64, f32, f32, // ........................... i_stat, n_on_temp, n_on_fan
-    SystemTime, // .............................. stat_int_start
-    Option<f32>, Option<f32>, Option<f32>, // ... cpu_temp_min, cpu_temp_max, cpu_temp_avg
-    f32, bool, // ............................... fan_state_stat, fan_state_prev
-    Option<f32>, Option<f32>, Option<f32>, // ... fan_speed_min, fan_speed_max, fan_speed_avg
+    cpu_temp_orig: Option<f32>,
+    fan_on: bool, duty_cycle: f32,
+    sensor_temps: &[Option<f32>], sensor_stats: &mut [ValueStat],
+    rpm: Option<f32>, rpm_stat: Option<&mut ValueStat>,
+    file_path: &str, flush: bool
 )
 // Function.
 {
 
     // Convert the statistics integer to f32 so that it can be used for calculations.
-    let n: f32 = *i_stat as f32;
+    let n: f32 = state.i_stat as f32;
 
 
-    // If the fan is on, use the duty cycle as the fan speed.
+    // If the fan is on, use the duty cycle it is run at as the fan speed (not the calculated one, which may be overridden).
     let fan_speed: Option<f32> = if fan_on { Some(duty_cycle) } else { None };
 
 
@@ -71,61 +291,67 @@ pub fn log_statistics
 
     
     // Check if the fan was either turned on or off.
-    let fan_state_chng: bool = fan_on != *fan_state_prev;
-    *fan_state_prev = fan_on;
+    let fan_state_chng: bool = fan_on != state.fan_state_prev;
+    state.fan_state_prev = fan_on;
 
 
     // Calculate statistics.
-    if *i_stat == 1 {
+    if state.i_stat == 1 {
 
         // Initially only assign the current values.
         // Temperature statistics.
-        if !cpu_temp_missing {*n_on_temp += 1.0;}
-        *cpu_temp_min = cpu_temp_orig;
-        *cpu_temp_max = cpu_temp_orig;
-        *cpu_temp_avg = cpu_temp_orig;
+        if !cpu_temp_missing {state.n_on_temp += 1.0;}
+        state.cpu_temp_min = cpu_temp_orig;
+        state.cpu_temp_max = cpu_temp_orig;
+        state.cpu_temp_avg = cpu_temp_orig;
         // Fan statistics.
-        if fan_on {*n_on_fan += 1.0;}
-        *fan_state_stat = fan_state_num;
-        *fan_speed_min = fan_speed;
-        *fan_speed_max = fan_speed;
-        *fan_speed_avg = fan_speed;
+        if fan_on {state.n_on_fan += 1.0;}
+        state.fan_state_stat = fan_state_num;
+        state.fan_speed_min = fan_speed;
+        state.fan_speed_max = fan_speed;
+        state.fan_speed_avg = fan_speed;
         // Start time of statistic period.
-        *stat_int_start = SystemTime::now();
+        state.stat_time_start = SystemTime::now();
 
     } else {
 
         // For every iteration after the first calculate statistics based on the statistics values and the current values.
         // Temperature statistics.
-        if !cpu_temp_missing {*n_on_temp += 1.0;}
-        *cpu_temp_min = min_option(*cpu_temp_min, cpu_temp_orig);
-        *cpu_temp_max = max_option(*cpu_temp_max, cpu_temp_orig);
-        *cpu_temp_avg = avg_option(*cpu_temp_avg, cpu_temp_orig, *n_on_temp);
+        if !cpu_temp_missing {state.n_on_temp += 1.0;}
+        state.cpu_temp_min = min_option(state.cpu_temp_min, cpu_temp_orig);
+        state.cpu_temp_max = max_option(state.cpu_temp_max, cpu_temp_orig);
+        state.cpu_temp_avg = avg_option(state.cpu_temp_avg, cpu_temp_orig, state.n_on_temp);
         // Fan statistics.
-        if fan_on {*n_on_fan += 1.0;}
-        *fan_state_stat = (*fan_state_stat * (n - 1.0) / n) + (fan_state_num / n);
-        *fan_speed_min = min_option(*fan_speed_min, fan_speed);
-        *fan_speed_max = max_option(*fan_speed_max, fan_speed);
-        *fan_speed_avg = avg_option(*fan_speed_avg, fan_speed, *n_on_fan);
+        if fan_on {state.n_on_fan += 1.0;}
+        state.fan_state_stat = (state.fan_state_stat * (n - 1.0) / n) + (fan_state_num / n);
+        state.fan_speed_min = min_option(state.fan_speed_min, fan_speed);
+        state.fan_speed_max = max_option(state.fan_speed_max, fan_speed);
+        state.fan_speed_avg = avg_option(state.fan_speed_avg, fan_speed, state.n_on_fan);
+    }
+
+    // Statistics of the individual sensors and the measured fan speed.
+    for (stat, &temp) in sensor_stats.iter_mut().zip(sensor_temps) {
+        stat.add(temp, state.i_stat == 1);
     }
+    let rpm_stat: Option<&ValueStat> = rpm_stat.map(|stat| {stat.add(rpm, state.i_stat == 1); &*stat});
 
 
     // Log and reset values when the condition is met
     // First ensure that the duration since the beginning of this stat period can be determined. If not, skip logging.
-    if let Ok(elapsed) = stat_int_start.elapsed() {
+    if let Ok(elapsed) = state.stat_time_start.elapsed() {
     // Log stats if either ...
     if 
     // ... the minimum duration since the last log has past and the observed temperature difference is at least stat_delta_t, ....
-    ((elapsed.as_secs() >= stat_min_time && delta_option(*cpu_temp_max, *cpu_temp_min).unwrap_or(0.0) >= stat_delta_t) | 
+    ((elapsed.as_secs() >= stats.min_time && delta_option(state.cpu_temp_max, state.cpu_temp_min).unwrap_or(0.0) >= stats.delta_t) | 
     // ... the minimum duration since the last log has past and the fan was either turned on or off, ...
-    (elapsed.as_secs() >= stat_min_time && fan_state_chng) |
+    (elapsed.as_secs() >= stats.min_time && fan_state_chng) |
     // ... or the maximum duration since the last log has past.
-    (elapsed.as_secs() >= stat_max_time)) | 
+    (elapsed.as_secs() >= stats.max_time)) | 
     // If a SIGINT/SIGTERM/SIGHUP signal is received, always log stats.
-    (shdn_recv | rld_recv)
+    flush
     {
         // Define time points
-        let stat_int_start_convert: DateTime<Local> = DateTime::from(*stat_int_start);
+        let stat_int_start_convert: DateTime<Local> = DateTime::from(state.stat_time_start);
         let timestamp_start = stat_int_start_convert.format("%Y-%m-%d %H:%M:%S").to_string();
         let timestamp_end = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
 
@@ -134,29 +360,21 @@ pub fn log_statistics
         (
             // Input arguments
             log_option,
-            file_path,
+            file_path, &stats.rotation,
             timestamp_start, timestamp_end,
-            *cpu_temp_min, *cpu_temp_max, *cpu_temp_avg,
-            *fan_state_stat,
-            *fan_speed_min, *fan_speed_max, *fan_speed_avg
+            state.cpu_temp_min, state.cpu_temp_max, state.cpu_temp_avg,
+            state.fan_state_stat,
+            state.fan_speed_min, state.fan_speed_max, state.fan_speed_avg,
+            sensor_stats, rpm_stat
         ) {
             // If statistics where successfully written to the .csv file, reset statistics period.
-            *i_stat = 0;
-            *n_on_fan = 0.0;
-            *n_on_temp = 0.0;
+            state.i_stat = 0;
+            state.n_on_fan = 0.0;
+            state.n_on_temp = 0.0;
         };
         
         
     } }
-
-    // Return arguments
-    (
-        *i_stat, *n_on_temp, *n_on_fan,
-        *stat_int_start,
-        *cpu_temp_min, *cpu_temp_max, *cpu_temp_avg,
-        *fan_state_stat, *fan_state_prev,
-        *fan_speed_min, *fan_speed_max, *fan_speed_avg
-    )
 }
 
 
@@ -209,8 +427,16 @@ fn delta_option (a: Option<f32>, b: Option<f32>) -> Option<f32> {
 }
 
 
+// Return the greatest common divisor of two integers.
+pub fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {a} else {gcd(b, a % b)}
+}
+
+
 // Create the .csv file for the statistics and add the header row.
-pub fn create_csv(file_path: &str, log_option: bool) {
+// If the fan is controlled by more than one sensor, min, max and avg columns are added for every sensor.
+// If a tachometer is used, min, max and avg columns are added for the measured fan speed in RPM.
+pub fn create_csv(file_path: &str, sensor_names: &[String], tach: bool, log_option: bool) {
 
     // Check if the file already exists. If it does, do nothing.
     if !Path::new(file_path).exists() {
@@ -219,7 +445,7 @@ pub fn create_csv(file_path: &str, log_option: bool) {
         let file = match File::create(file_path) {
             Ok(f) => f,
             Err(e) => {
-                log_event(&format!("Failed to create file {}: {}", file_path, e), log_option);
+                log_event(Level::Error, &format!("Failed to create file {}: {}", file_path, e), log_option);
                 return;
             }
         };
@@ -227,30 +453,41 @@ pub fn create_csv(file_path: &str, log_option: bool) {
         let mut wtr = Writer::from_writer(file);
 
         // Define the header row.
-        if let Err(e) = wtr.write_record(&["start", "end", "cpu_temp_min", "cpu_temp_max", "cpu_temp_avg", "fan_state_stat", "fan_speed_min", "fan_speed_max", "fan_speed_avg"]) {
-            log_event(&format!("Failed to write header to CSV: {}", e), log_option);
+        let mut header: Vec<String> = ["start", "end", "cpu_temp_min", "cpu_temp_max", "cpu_temp_avg", "fan_state_stat", "fan_speed_min", "fan_speed_max", "fan_speed_avg"]
+            .iter().map(|column| column.to_string()).collect();
+        if sensor_names.len() > 1 {
+            for name in sensor_names {
+                header.extend([format!("temp_{}_min", name), format!("temp_{}_max", name), format!("temp_{}_avg", name)]);
+            }
+        }
+        if tach {
+            header.extend(["rpm_min".to_string(), "rpm_max".to_string(), "rpm_avg".to_string()]);
+        }
+        if let Err(e) = wtr.write_record(&header) {
+            log_event(Level::Error, &format!("Failed to write header to CSV: {}", e), log_option);
             return;
         }
 
         // Write the header row to the file.
         if let Err(e) = wtr.flush() {
-            log_event(&format!("Failed to flush CSV writer: {}", e), log_option);
-            return;
+            log_event(Level::Error, &format!("Failed to flush CSV writer: {}", e), log_option);
         } 
     }
 }
 
 
 // Write statistics data to the .csv file.
+#[allow(clippy::too_many_arguments)]
 fn append_to_csv
 (
 // Define input arguments and types.
     log_option: bool,
-    file_path: &str,
+    file_path: &str, rotation: &Rotation,
     timestamp_start: String, timestamp_end: String,
     cpu_temp_min: Option<f32>, cpu_temp_max: Option<f32>, cpu_temp_avg: Option<f32>,
     fan_state_stat: f32,
-    fan_speed_min: Option<f32>, fan_speed_max: Option<f32>, fan_speed_avg: Option<f32> 
+    fan_speed_min: Option<f32>, fan_speed_max: Option<f32>, fan_speed_avg: Option<f32>,
+    sensor_stats: &[ValueStat], rpm_stat: Option<&ValueStat>
 ) -> 
 
 // Define output types.
@@ -258,16 +495,22 @@ bool
 
 // Function
 {
+    // Rotate the file if it is too large or too old. A new file is created below.
+    if let Err(e) = rotation.rotate(file_path) {
+        log_event(Level::Error, &e, log_option);
+    }
+
     // Check if the file exists. If not, create it.
     if !Path::new(file_path).exists() {
-        create_csv(file_path, log_option)
+        let sensor_names: Vec<String> = sensor_stats.iter().map(|stat| stat.name.clone()).collect();
+        create_csv(file_path, &sensor_names, rpm_stat.is_some(), log_option)
     };
     
     // Open the file in append mode.
     let file = match OpenOptions::new().append(true).open(file_path) {
         Ok(f) => f,
         Err(e) => {
-            log_event(&format!("Failed to open file {}: {}", file_path, e), log_option);
+            log_event(Level::Error, &format!("Failed to open file {}: {}", file_path, e), log_option);
             return false;
         }
     };
@@ -275,7 +518,7 @@ bool
     let mut wtr = Writer::from_writer(file);
 
     // Create the data row from the stats.
-    let data_row = vec![
+    let mut data_row = vec![
         timestamp_start,
         timestamp_end,
         cpu_temp_min.map_or("NaN".to_string(), |v| format!("{:.3}", v)),
@@ -286,17 +529,54 @@ bool
         fan_speed_max.map_or("NaN".to_string(), |v| format!("{:.3}", v)),
         fan_speed_avg.map_or("NaN".to_string(), |v| format!("{:.3}", v))
     ];
+    if sensor_stats.len() > 1 {
+        for stat in sensor_stats {
+            data_row.extend(stat.to_record());
+        }
+    }
+    if let Some(stat) = rpm_stat {
+        data_row.extend(stat.to_record());
+    }
     if let Err(e) = wtr.write_record(data_row) {
-        log_event(&format!("Failed to write record to CSV: {}", e), log_option);
+        log_event(Level::Error, &format!("Failed to write record to CSV: {}", e), log_option);
         return false;
     }
 
     // Write the data
     if let Err(e) = wtr.flush() {
-        log_event(&format!("Failed to flush CSV writer: {}", e), log_option);
+        log_event(Level::Error, &format!("Failed to flush CSV writer: {}", e), log_option);
         return false;
     }
 
     // If everything worked, return true.
     true
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn rotated_csv_starts_with_header() {
+        let dir = env::temp_dir().join(format!("rpi_fanctrl_csv_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("stats.csv").display().to_string();
+        let rotation = Rotation { max_size: 1, keep: 1, ..Default::default() };
+        let sensor_stats = [ValueStat { name: "cpu".to_string(), ..Default::default() }];
+        let append = || append_to_csv(false, &file, &rotation, "start".to_string(), "end".to_string(),
+            Some(50.0), Some(60.0), Some(55.0), 0.5, Some(0.3), Some(0.6), Some(0.45), &sensor_stats, None);
+
+        assert!(append());
+        assert!(append());
+        for content in [fs::read_to_string(&file).unwrap(), fs::read_to_string(format!("{}.1", file)).unwrap()] {
+            let lines: Vec<&str> = content.lines().collect();
+            assert_eq!(lines.len(), 2);
+            assert!(lines[0].starts_with("start,end,cpu_temp_min"));
+            assert!(lines[1].starts_with("start,end,50.000"));
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,117 @@
+use std::fmt;
+use std::str::FromStr;
+use std::os::unix::net::UnixDatagram;
+use std::sync::OnceLock;
+use chrono::Local;
+use crate::functions::Level;
+
+
+// Socket of the native protocol of the systemd journal.
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+// Socket of the local syslog daemon.
+const SYSLOG_SOCKET: &str = "/dev/log";
+// Identifier of the program in the journal and in syslog.
+const IDENTIFIER: &str = "rpi_fanctrl";
+
+
+// Destination of the logged events.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogBackend {
+    File, // ....... The log file (log_path), events are also printed.
+    Journald, // ... The systemd journal with the fields TEMP, DUTY and FAN_STATE.
+    Syslog, // ..... The local syslog daemon (facility daemon).
+}
+
+impl FromStr for LogBackend {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "file" => Ok(LogBackend::File),
+            "journald" => Ok(LogBackend::Journald),
+            "syslog" => Ok(LogBackend::Syslog),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for LogBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            LogBackend::File => "file",
+            LogBackend::Journald => "journald",
+            LogBackend::Syslog => "syslog",
+        })
+    }
+}
+
+
+// State of the fan control that is added to every event in the journal.
+#[derive(Clone, Copy, Debug)]
+pub struct LogFields {
+    pub temp: Option<f32>, // ... Temperature in degree C, none if it could not be read.
+    pub duty_cycle: f32,
+    pub fan_on: bool,
+}
+
+
+// Syslog severity of a level (also used as PRIORITY in the journal).
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+
+// Unbound socket used to send datagrams to the journal or to syslog. None if it could not be created.
+fn socket() -> Option<&'static UnixDatagram> {
+    static SOCKET: OnceLock<Option<UnixDatagram>> = OnceLock::new();
+    SOCKET.get_or_init(|| UnixDatagram::unbound().ok()).as_ref()
+}
+
+
+// Send an event to the systemd journal using its native protocol.
+// Every field is sent as KEY=value per line. Values with a line break are sent as KEY, followed by the length (64 bit little endian) and the value.
+pub fn send_journal(level: Level, message: &str, fields: Option<LogFields>) -> Result<(), String> {
+    let mut datagram: Vec<u8> = Vec::new();
+    let mut add = |key: &str, value: &str| {
+        if value.contains('\n') {
+            datagram.extend_from_slice(key.as_bytes());
+            datagram.push(b'\n');
+            datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            datagram.extend_from_slice(value.as_bytes());
+        } else {
+            datagram.extend_from_slice(format!("{}={}", key, value).as_bytes());
+        }
+        datagram.push(b'\n');
+    };
+    add("MESSAGE", message);
+    add("PRIORITY", &severity(level).to_string());
+    add("SYSLOG_IDENTIFIER", IDENTIFIER);
+    add("LEVEL", &level.to_string());
+    if let Some(fields) = fields {
+        if let Some(temp) = fields.temp {
+            add("TEMP", &format!("{:.2}", temp));
+        }
+        add("DUTY", &format!("{:.3}", fields.duty_cycle));
+        add("FAN_STATE", if fields.fan_on {"on"} else {"off"});
+    }
+    send(JOURNAL_SOCKET, &datagram)
+}
+
+
+// Send an event to the local syslog daemon (RFC 3164 format, facility daemon).
+pub fn send_syslog(level: Level, message: &str) -> Result<(), String> {
+    let priority = 3 * 8 + severity(level);
+    let datagram = format!("<{}>{} {}[{}]: {}", priority, Local::now().format("%b %e %H:%M:%S"), IDENTIFIER, std::process::id(), message);
+    send(SYSLOG_SOCKET, datagram.as_bytes())
+}
+
+
+// Send a datagram to a socket.
+fn send(path: &str, datagram: &[u8]) -> Result<(), String> {
+    let socket = socket().ok_or_else(|| "Cannot create a socket".to_string())?;
+    socket.send_to(datagram, path).map(|_| ()).map_err(|e| format!("Cannot send to {}: {}", path, e))
+}
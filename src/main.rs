@@ -1,8 +1,8 @@
 // Public crates.
-use rppal::gpio::Gpio;
-use std::{sync::{Arc, Mutex, mpsc}, thread, time};
-use std::time::SystemTime;
-use std::process;
+use std::{sync::{mpsc, Arc, Mutex}, thread, time};
+use std::time::{Instant, SystemTime};
+use std::fs;
+use std::path::Path;
 // use std::process::Command;
 use std::env;
 // use ctrlc;
@@ -12,13 +12,59 @@ use std::path::PathBuf;
 
 // Own crates.
 mod functions;
+mod metrics;
+mod mqtt;
+mod notify;
+mod log_backend;
 mod config;
+mod control;
+mod calibrate;
+mod cli;
+mod check;
+mod curve;
+mod driver;
+mod fan;
+mod pid;
+mod rotate;
+mod sensor;
+mod stall;
+mod state;
+mod tach;
 use functions::*;
-use config::load_var;
+use config::{load_var, Config, ConfigReport, ControlMode};
+use control::{json_field, reply_error, reply_ok, send_command, start_server, Command, Override, Request};
+use state::RuntimeState;
+use sensor::SensorGroup;
+use pid::Pid;
+use tach::{init_tach, Tachometer};
+use stall::StallDetector;
+use driver::init_driver;
+use fan::{FanControl, Inputs};
+use log_backend::LogFields;
+use metrics::{serve_metrics, Metrics};
+use mqtt::{start_client, Discovery};
+use notify::Notifier;
+use calibrate::calibrate;
+use check::check_config;
+use cli::{print_help, Args};
 
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
 
+    // Parse the command line arguments (see rpi_fanctrl --help).
+    let args: Args = match Args::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}. See 'rpi_fanctrl --help'.", e);
+            std::process::exit(2);
+        }
+    };
+    if args.command.as_deref() == Some("help") {
+        print_help();
+        return Ok(());
+    }
+
+
     // Get the directory of the executed binary.
     // This path is required for loading the environmental variables, saving logs and stats.
     let dir = std::env::current_exe()
@@ -37,80 +83,135 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     ];
 
 
+    // Send a command to the running fan control if the program was started with the "ctl" subcommand (e.g. "rpi_fanctrl ctl status") and exit afterwards.
+    // The answer is printed as JSON. If the command failed, the program exits with an error.
+    // The socket is found the same way as by the fan control, i.e. socket_path of --socket, the environmental variables, the .env file or the configuration file.
+    if args.command.as_deref() == Some("ctl") {
+        let command = if args.control.is_empty() {"status".to_string()} else {args.control.join(" ")};
+        let (config, _) = load_var(&dir, &fallback_dirs, args.config.as_deref(), &args.overrides);
+        let socket_path: String = config.map_or_else(|e| e.fallback.socket.path, |config| config.socket.path);
+        match send_command(&socket_path, &command) {
+            Ok(reply) => {
+                println!("{}", reply);
+                if !reply.starts_with("{\"ok\":true") {
+                    std::process::exit(1);
+                }
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+
+    // Check the configuration if the program was started with the "check-config" subcommand (i.e. "rpi_fanctrl check-config [path] [--strict]") and exit afterwards.
+    // With --strict, the program exits with an error if any setting is invalid, unknown, adjusted (e.g. temp_freq to a divisor of pwm_freq) or not defined,
+    // so that typos and unintended values are caught before deploying.
+    if args.command.as_deref() == Some("check-config") {
+        if !check_config(args.path.as_deref(), &dir, &fallback_dirs, args.config.as_deref(), &args.overrides, args.strict) && args.strict {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+
     // Load variables.
-    let(
-        log_option,
-        gpio_pin,
-        off_temp, min_temp, max_temp,
-        min_duty_cycle, max_duty_cycle,
-        period_micros,
-        temp_cycle,
-        factor_hot_old, factor_hot_new, factor_cold_old, factor_cold_new,
-        delay_duration,
-        error_duration,
-        stat_option, stat_cycle, stat_min_time, stat_max_time, stat_delta_t, mut stat_time_start,
-        mut i, mut i_stat, mut n_on_fan, mut n_on_temp,
-        mut duty_cycle, mut duty_cycle_old,
-        mut cpu_temp_min, mut cpu_temp_max, mut cpu_temp_avg,
-        mut fan_state_stat, mut fan_state_prev,
-        mut fan_speed_avg, mut fan_speed_min, mut fan_speed_max
-    ) = load_var(&dir, &fallback_dirs);
-
-    log_event("Fan control initialized.", log_option);
+    // If any setting is invalid, all invalid settings are logged and their default values are used instead.
+    let (config, report) = load_var(&dir, &fallback_dirs, args.config.as_deref(), &args.overrides);
+    let config_error: Option<String> = config.as_ref().err().map(|e| format!("Using default values for {}", e));
+    let mut config: Config = config.unwrap_or_else(|e| *e.fallback);
+
+
+    // Set up the logging and the log file (the directory is created if it does not exist).
+    // If the log file cannot be written, events are only printed (and logged by systemd).
+    if let Err(e) = set_logging(&config.logging) {
+        log_event(Level::Error, &format!("{}. Events are not written to the log file.", e), false);
+    }
+    if let Some(e) = config_error {
+        log_event(Level::Warn, &e, config.logging.log_option);
+    }
+    log_report(&report, config.logging.log_option);
+
+
+    // Define the runtime state of the control loop.
+    // The fan speed is set to the max cycle to initially keep the fan to full power.
+    let mut state = RuntimeState::new(config.control.max_duty_cycle);
+
+    log_event(Level::Info, "Fan control initialized.", config.logging.log_option);
 
     
     // If the directory of the binary could not be determined, log it.
-    if dir.is_none() {log_event("Could not get the directory of the binary.", log_option);};
+    if dir.is_none() {log_event(Level::Warn, "Could not get the directory of the binary.", config.logging.log_option);};
+
+
+    // Set up the temperature sensors.
+    let mut sensors = SensorGroup::open(&config.sensor.sensors, config.sensor.aggregation, config.control.min_temp, config.control.max_temp);
+    log_event(Level::Info, &format!("Reading temperatures from {} (aggregation: {}).", sensors.describe(), config.sensor.aggregation), config.logging.log_option);
+
+
+    // Set up the tachometer if a tach pin is defined.
+    // With --dry-run, no GPIO pin is claimed, so the tachometer is skipped as well (and the fan speed is not measured).
+    let tach_pin = |config: &Config| config.tach.pin.filter(|_| !args.dry_run);
+    if args.dry_run && config.tach.pin.is_some() {
+        log_event(Level::Info, "Dry run: The tachometer is not used.", config.logging.log_option);
+    }
+    let mut tach: Option<Tachometer> = init_tach(tach_pin(&config), config.tach.ppr, config.logging.log_option);
+    let mut rpm: Option<f32> = None;
+
 
+    // Calibrate the fan if the program was started with the "calibrate" subcommand (i.e. "rpi_fanctrl calibrate") and exit afterwards.
+    if args.command.as_deref() == Some("calibrate") {
+        let mut driver = init_driver(config.pwm.gpio_pin, config.pwm.mode, config.pwm.freq, config.pwm.chip, config.pwm.channel, config.logging.log_option);
+        calibrate(driver.as_mut(), tach.as_mut(), &config);
+        return Ok(());
+    }
 
-    // In case a SIGHUP signal is received use this variable to "force" systemd to restart by exiting with a non 0 exit code. 
-    let mut restart = false;
+
+    // Define the statistics of the individual sensors and the measured fan speed.
+    let mut sensor_stats: Vec<ValueStat> = sensors.names().into_iter().map(|name| ValueStat { name, ..Default::default() }).collect();
+    let mut rpm_stat: ValueStat = ValueStat { name: "rpm".to_string(), ..Default::default() };
 
 
     // Create the stats file if statistics are logged.
-    let file_path = "stats.csv";
-    if stat_option {
+    if config.stats.stat_option {
+        // Create the directory if it does not exist.
+        if let Err(e) = create_dir(&config.stats.path) {
+            log_event(Level::Error, &format!("{}. Statistics are not saved.", e), config.logging.log_option);
+        }
         // Create the .csv file and add the header (will only be performed if the file does not already exist).
-        create_csv(file_path, log_option);
+        create_csv(&config.stats.path, &sensors.names(), tach.is_some(), config.logging.log_option);
     }
 
 
-    // Set GPIO pin.
-    let gpio = Gpio::new().unwrap_or_else(|_| {log_event("Warning: Failed to initialize GPIO. Exiting.", log_option); std::process::exit(1);});
-    let pin = gpio.get(gpio_pin).unwrap_or_else(|_| {log_event(&format!("Warning: Failed to access GPIO pin {}. Exiting.", gpio_pin), log_option);std::process::exit(1);}).into_output();
-    let pin = Arc::new(Mutex::new(pin));
-    
+    // Set up the fan driver and immediately start the fan on full power. Set the initial fan state accordingly.
+    let mut driver = init_driver(config.pwm.gpio_pin, config.pwm.mode, config.pwm.freq, config.pwm.chip, config.pwm.channel, config.logging.log_option);
+    driver.set_duty(1.0);
 
-    // Immediately start the fan on full power and set the initial fan state.
-    if let Ok(mut pin) = pin.lock() {
-        pin.set_high();
-    }
-    let mut fan_on = true;
 
+    // Set up the control of the fan (see FanControl), including how the fan speed is calculated (see effective_control_mode).
+    let mut control = FanControl::new(&config, effective_control_mode(&config, tach.is_some()), true);
 
-    // Set the initial time point of the last fan state.
-    // The delay duration is subtracted so that the fan can start immediately.
-    let mut state_change_time = SystemTime::now()
-        .checked_sub(delay_duration)
-        .unwrap_or_else(|| {log_event("Error: Failed to subtract delay from current time. Using current time as fallback.", log_option);SystemTime::now()});
+
+    // Define the duration of a single loop cycle and how many loop cycles pass between temperature readings and statistics calculations.
+    let (mut loop_micros, mut temp_cycle, mut stat_cycle) = config.loop_timing();
 
 
     // Set the initial time point of the last cpu temperature error.
     // The delay duration is subtracted so that the first error message could be sent immediately.
-    let mut last_error_time = SystemTime::now().checked_sub(error_duration).unwrap_or_else(SystemTime::now);
+    let mut last_error_time = SystemTime::now().checked_sub(config.sensor.error_duration).unwrap_or_else(SystemTime::now);
 
 
     // Define the temperature variable and get the initial CPU temperature.
     let mut cpu_temp_missing = false;
-    let mut cpu_temp = read_cpu_temperature();
+    let mut cpu_temp = sensors.read();
     cpu_temp = match cpu_temp {
         Some(temp) => Some(temp),
         None => {
-            if let Ok(mut pin) = pin.lock() {
-                pin.set_high();
-            }
-            if last_error_time.elapsed().unwrap_or_default() >= error_duration {
-                log_event("Failed to read CPU temperature. Turning fan on at full power.", log_option);
+            driver.set_duty(1.0);
+            if last_error_time.elapsed().unwrap_or_default() >= config.sensor.error_duration {
+                log_event(Level::Error, &format!("Failed to read temperature from {}. Turning fan on at full power.", sensors.failed()), config.logging.log_option);
                 last_error_time = SystemTime::now();
             }
             None
@@ -118,11 +219,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
 
 
-    // Define variables for comparison of current temperature with previous temperature.
-    let mut cpu_temp_old = cpu_temp;
-    let mut same_temps = true;
-        _ = same_temps;   
-    if cpu_temp == None{
+    // The first reading is the initial dampened temperature.
+    control.temp_dampened = cpu_temp;
+    if cpu_temp.is_none() {
         cpu_temp_missing = true
     }
 
@@ -133,7 +232,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 
     // Setup handler for the SIGINT/SIGTERM/SIGHUP signals.
-    let mut signals = Signals::new(&[SIGINT, SIGTERM, SIGHUP])?;
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP])?;
     let handle = signals.handle();
     
 
@@ -157,10 +256,54 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     });
 
 
+    // Set up the control socket (see "rpi_fanctrl ctl").
+    // Its thread forwards the commands of the clients, which are answered at the beginning of each loop cycle.
+    // The socket file is only removed on exit if it was created by this process.
+    let (txc, rxc) = mpsc::channel::<Request>();
+    let mut socket_created = false;
+    if config.socket.socket_option {
+        match start_server(&config.socket.path, txc.clone(), config.logging.log_option) {
+            Ok(()) => socket_created = true,
+            Err(e) => log_event(Level::Error, &format!("{}. Runtime commands are not available.", e), config.logging.log_option),
+        }
+    }
+    let start_time = Instant::now();
+    let mut profile_override: Option<(String, String)> = None; // ... Profile selected with the control socket, which takes precedence over the command line.
+    let mut profile_loaded: Option<(String, String)> = None; // ..... Profile selected with the control socket that the running configuration was loaded with.
+
+
+    // Set up the metrics endpoint for Prometheus (if metrics_option is set to true) and the MQTT client (if mqtt_option is set to true).
+    // Both read the current values of the fan control from the shared metrics. Commands received over MQTT are answered like the ones of the control socket.
+    let metrics: Option<Arc<Mutex<Metrics>>> = (config.metrics.metrics_option || config.mqtt.mqtt_option).then(|| Arc::new(Mutex::new(Metrics::default())));
+    if let Some(metrics) = metrics.as_ref().filter(|_| config.metrics.metrics_option) {
+        match serve_metrics(config.metrics.address, Arc::clone(metrics), config.logging.log_option) {
+            Ok(()) => log_event(Level::Info, &format!("Serving metrics at http://{}/metrics.", config.metrics.address), config.logging.log_option),
+            Err(e) => log_event(Level::Error, &format!("{}. Metrics are not available.", e), config.logging.log_option),
+        }
+    }
+    if let Some(metrics) = metrics.as_ref().filter(|_| config.mqtt.mqtt_option) {
+        let discovery = Discovery { tach: tach.is_some(), profiles: config.profiles.clone() };
+        start_client(config.mqtt.clone(), Arc::clone(metrics), txc.clone(), discovery, config.logging.log_option);
+        log_event(Level::Info, &format!("Publishing to the MQTT broker {}:{} (topic: {}).", config.mqtt.host, config.mqtt.port, config.mqtt.topic), config.logging.log_option);
+    }
+    drop(txc);
+    let mut fan_on_prev = control.fan_on; // ... Fan state of the previous loop cycle, to detect when the fan is turned on or off.
+
+
+    // Tell systemd that the fan driver and the sensors are set up (only with Type=notify, see Notifier).
+    // If WatchdogSec= is set, the watchdog is pinged from the control loop.
+    let mut notifier = Notifier::from_env();
+    notifier.ready(&status_line(cpu_temp, control.fan_on, control.duty_cycle, rpm));
+    if let Some(interval) = notifier.watchdog_interval() {
+        log_event(Level::Debug, &format!("Pinging the systemd watchdog every {:.1} s.", interval.as_secs_f32()), config.logging.log_option);
+    }
+
+
     // Check CPU temperature and adjust fan speed accordingly.
     // This loop runs the whole time while the program is running.
-    // Each loop cycle should be one PWM period + calculation time long.
+    // Each loop cycle should be one loop period (see loop_micros) + calculation time long.
     loop {
+        let cycle_start = Instant::now();
 
 
         // Check if the shutdown flag was sent.
@@ -170,279 +313,371 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
         // Check if the reload flag was sent.
         // If yes, save stats (if logged) and reload environmental variables.
-        let rld_recv: bool = rxr.try_recv().unwrap_or(false);
+        let mut rld_recv: bool = rxr.try_recv().unwrap_or(false);
+
+
+        // Answer the commands of the control socket.
+        // A reload is handled like a SIGHUP signal. If the statistics are flushed, they are saved like on a SIGHUP signal.
+        let mut flush_recv: bool = false;
+        while let Ok(request) = rxc.try_recv() {
+            let reply = match request.command {
+                Command::Status => reply_ok(&[
+                    json_field("temp", cpu_temp, 2),
+                    json_field("temp_dampened", control.temp_dampened, 2),
+                    json_field("duty_cycle", Some(control.duty_cycle), 3),
+                    format!("\"fan_on\":{}", control.fan_on),
+                    json_field("manual_duty", control.manual.map(|manual| manual.duty_cycle), 3),
+                    json_field("manual_remaining", control.manual.map(|manual| manual.remaining() as f32), 0),
+                    json_field("rpm", rpm, 0),
+                    format!("\"control_mode\":{}", json_string(&control.mode.to_string())),
+                    format!("\"profile\":{}", json_string(&config.profile)),
+                    format!("\"since_state_change\":{}", control.state_change_time.elapsed().unwrap_or_default().as_secs()),
+                    format!("\"uptime\":{}", start_time.elapsed().as_secs()),
+                ]),
+                Command::SetDuty(_, _) if cpu_temp.is_some_and(|temp| temp >= config.socket.emergency_temp) => {
+                    reply_error(&format!("The temperature is above emergency_temp ({:.1} °C)", config.socket.emergency_temp))
+                }
+                Command::SetDuty(duty_cycle, duration) => {
+                    let new_manual = Override::new(duty_cycle, duration, config.socket.override_duration);
+                    control.manual = Some(new_manual);
+                    log_event(Level::Info, &format!("Fan speed set to {:.0} % for {} s with the control socket. The automatic control resumes afterwards or at {:.1} °C (emergency_temp).",
+                        duty_cycle * 100.0, new_manual.remaining(), config.socket.emergency_temp), config.logging.log_option);
+                    reply_ok(&[json_field("manual_duty", Some(duty_cycle), 3), json_field("manual_remaining", Some(new_manual.remaining() as f32), 0)])
+                }
+                Command::Release => {
+                    if control.manual.take().is_some() {
+                        log_event(Level::Info, "Fan speed released with the control socket. Returning to automatic control.", config.logging.log_option);
+                    }
+                    reply_ok(&[])
+                }
+                Command::Reload => {
+                    rld_recv = true;
+                    reply_ok(&[])
+                }
+                Command::FlushStats if config.stats.stat_option => {
+                    flush_recv = true;
+                    reply_ok(&[])
+                }
+                Command::FlushStats => reply_error("Statistics are disabled (stat_option)"),
+                Command::Profile(name) if name != "default" && !config.profiles.contains(&name) => {
+                    reply_error(&format!("Unknown profile '{}'. Profiles: default{}", name, config.profiles.iter().map(|profile| format!(", {}", profile)).collect::<String>()))
+                }
+                Command::Profile(name) => {
+                    log_event(Level::Info, &format!("Switching to profile '{}'.", name), config.logging.log_option);
+                    profile_override = Some(("profile".to_string(), name.clone()));
+                    rld_recv = true;
+                    reply_ok(&[format!("\"profile\":{}", json_string(&name))])
+                }
+            };
+            let _ = request.reply.send(reply);
+        }
 
 
         // Read temperature for the first cycle and then every n-th cycle.
         // This is implemented to optimize performance.
-        if i % temp_cycle == 0 || i == 1 {
-            cpu_temp = read_cpu_temperature();
+        let temp_read: bool = state.i.is_multiple_of(temp_cycle) || state.i == 1;
+        if temp_read {
+            cpu_temp = sensors.read();
             cpu_temp = match cpu_temp {
-                Some(temp) => Some(temp),
+                // If only some of the sensors could not be read, use the remaining ones.
+                Some(temp) => {
+                    if sensors.readings.contains(&None) && last_error_time.elapsed().unwrap_or_default() >= config.sensor.error_duration {
+                        log_event(Level::Warn, &format!("Failed to read temperature from {}. Using the remaining sensors.", sensors.failed()), config.logging.log_option);
+                        last_error_time = SystemTime::now();
+                    }
+                    Some(temp)
+                }
                 // If there was no valid temperature reading, set the fan to high.
                 None => {
-                    if let Ok(mut pin) = pin.lock() {
-                        pin.set_high();
-                    }
-                    if last_error_time.elapsed().unwrap_or_default() >= error_duration {
-                        log_event("Failed to read CPU temperature. Turning fan on at full power.", log_option);
+                    if last_error_time.elapsed().unwrap_or_default() >= config.sensor.error_duration {
+                        log_event(Level::Error, &format!("Failed to read temperature from {}. Turning fan on at full power.", sensors.failed()), config.logging.log_option);
                         last_error_time = SystemTime::now();
                     }
                     None
                 }
             };
             // Check if temperature could be read.
-            if cpu_temp == None{
-                cpu_temp_missing = true
-            } else {
-                cpu_temp_missing = false
-            }
+            cpu_temp_missing = cpu_temp.is_none();
         // For every cycle where the temperature is not read, use the previous value.
         } else {
-            cpu_temp = cpu_temp_old;
+            cpu_temp = control.temp_dampened;
         }
 
 
-        // Check if the current and the previous temperature are the same.
-        // Only returns true if both are some value. 
-        same_temps = cpu_temp_old.zip(cpu_temp).map_or(false, |(old, new)| old == new);
+        // Measure the fan speed if a tachometer is used.
+        if let Some(tach) = tach.as_mut() {
+            rpm = tach.rpm();
+        }
 
 
-        // The cpu temperature before dampening is used for statistics.
+        // The temperature before dampening is used for statistics.
         let cpu_temp_orig = cpu_temp;
 
-        
-        // Only adjust fan speed if there is a valid cpu temperature reading.
-        // Else, the fan was already set to full speed.
-        // Temperature check will be repeated in one PWM period.
-        if let Some(mut cpu_temp) = cpu_temp {
-            
-
-            // Dampen fan speed changes.
-            // Fast fan speed changes or frequent acceleration and deceleration of the fan should be avoided.
-            // Since fan speed directly correlates to CPU temperature, the change rate of the temperature is dampened.
-            // Only do this if there is a valid value for cpu_temp_old.
-            if let Some(cpu_temp_old) = cpu_temp_old {
-                cpu_temp = if same_temps {
-                    // If both temperatures are the same, use the current one without dampening.
-                    cpu_temp
-                } else if cpu_temp < cpu_temp_old {
-                    // If the current temperature is colder than the previous one, dampen using the cold factor.
-                    cpu_temp * factor_cold_new + cpu_temp_old * factor_cold_old
-                } else {
-                    // If the current temperature is hotter than the previous one, dampen using the hot factor.
-                    cpu_temp * factor_hot_new + cpu_temp_old * factor_hot_old
-                };
-            };
-
-            // Assign the dampened cpu temperature as the previous one for the next cycle,
-            cpu_temp_old = Some(cpu_temp);
-
-
-            // Calculate duty cycle.
-            // The speed at which the fan should be turning is calculated based on the dampened CPU temperature.
-            duty_cycle = if same_temps {
-                // If the temperatures did not change, do not recalculate the fan speed but use the previous one.
-                duty_cycle_old
-            } else if cpu_temp <= min_temp {
-                // Below the min temperature, use the min speed.
-                min_duty_cycle
-            } else if cpu_temp >= max_temp {
-                // Above the max temperature, use the max speed.
-                max_duty_cycle
-            } else if cpu_temp > min_temp && cpu_temp < max_temp && min_duty_cycle < max_duty_cycle {
-                // Between min and max temperature, use linear interpolation to calculate the fan speed.
-                min_duty_cycle + (cpu_temp - min_temp) * (max_duty_cycle - min_duty_cycle) / (max_temp - min_temp)
-            } else {
-                // As a fallback, go full speed. This Option should not be required.
-                max_duty_cycle
-            };
-
-            // Assign the current fan speed as the previous on for the next cycle.
-            duty_cycle_old = duty_cycle;
-
-
-            // Calculate active and inactive times.
-            // Using PWM, the fan is either turned on or turned off.
-            // To regulate fan speed, the fan is turned on and off rapidly.
-            // E.g.: For a PWM frequency of 1 kHz, the fan is turned on and off 1000 times per second.
-            // Tu run the fan at 50 percent, the on time and the off time is the same.
-            // E.g.: For a PWM frequency of 1 kHz, this means that fan is on for 500 micro s and off for 500 micro s.
-            let active_time = (duty_cycle * period_micros as f32) as u64;
-            let inactive_time = period_micros - active_time;
-
-
-            // Time since the last state change of the fan.
-            // To avoid frequently turning the fan on and off, the duration since the last state change is calculated.
-            // Not to be confused with on off in the context of regulating fan speed using PWM.
-            let elapsed_time = state_change_time.elapsed().unwrap_or_default();
 
+        // Turn the fan on or off and set its speed (see FanControl).
+        // The result is the duty cycle the fan is run at (a stalled or kick-started fan, a fan speed set with the control socket or the calculated fan speed).
+        let duty_cycle_applied: f32 = control.step(driver.as_mut(), &config, &mut state, &Inputs { temp: cpu_temp, temp_read, rpm, tach: tach.is_some() });
 
-            // Adjust the fan speed.
-            // According to the dampened CPU temperature, the current fan state and the duration since the last state change.
-            // The loop is not repeated. It is always stopped with a break signal in its initial iteration.
-            loop {
 
-
-                // Keep the fan off.
-                // If the fan is not running and the temperature is below the minimum temperature, keep the fan off.
-                if !fan_on && cpu_temp <= min_temp {
-                    thread::sleep(time::Duration::from_micros(period_micros));
-                    break;
-                }
-
-
-                // Keep the fan on.
-                // If the fan is running and the temperature is above the off temperature, keep the fan running.
-                // Fan speed is set to the calculated speed based on the dampened temperature.
-                if fan_on && cpu_temp > off_temp {
-                    fan_on = true;
-                    if let Ok(mut pin) = pin.lock() {
-                        pin.set_low();
-                    }
-                    thread::sleep(time::Duration::from_micros(inactive_time));
-                    if let Ok(mut pin) = pin.lock() {
-                                pin.set_high();
-                            }
-                    thread::sleep(time::Duration::from_micros(active_time));
-                    break;
-                }
-
-
-                // Turn the fan off.
-                // The fan is running but the temperature is below the off temperature.
-                // If the time since the fan was started is above the delay duration, turn the fan off.
-                if fan_on && cpu_temp <= off_temp && elapsed_time >= delay_duration {
-                    state_change_time = SystemTime::now();
-                    fan_on = false;
-                    if let Ok(mut pin) = pin.lock() {
-                        pin.set_low();
-                    }                    
-                    thread::sleep(time::Duration::from_micros(period_micros));
-                    break;
-                }
+        // Update the state of the fan control that is added to the events in the journal.
+        if temp_read {
+            set_log_fields(LogFields { temp: cpu_temp_orig, duty_cycle: duty_cycle_applied, fan_on: control.fan_on });
+        }
 
 
-                // Turn the fan on.
-                // The fan is not running but the temperature is above the minimum temperature.
-                // If the time since the fan was stopped is above the delay duration, turn the fan on.
-                if !fan_on && cpu_temp > min_temp && elapsed_time >= delay_duration  {
-                    state_change_time = SystemTime::now();
-                    fan_on = true;
-                    if let Ok(mut pin) = pin.lock() {
-                        pin.set_low();
-                    }
-                    thread::sleep(time::Duration::from_micros(inactive_time));
-                    if let Ok(mut pin) = pin.lock() {
-                                pin.set_high();
-                            }
-                    thread::sleep(time::Duration::from_micros(active_time));
-                    break;
-                }
-
-
-                // Keep the fan on, due to time delay.
-                // The fan is running and the temperature is below the off temperature.
-                // However, the time since the fan was started is still below the delay duration.
-                // The fan will keep running and the fan speed is set to the calculated speed based on the dampened temperature.
-                if fan_on && cpu_temp <= off_temp && elapsed_time < delay_duration {
-                    fan_on = true;
-                    if let Ok(mut pin) = pin.lock() {
-                        pin.set_low();
-                    }
-                    thread::sleep(time::Duration::from_micros(inactive_time));
-                    if let Ok(mut pin) = pin.lock() {
-                        pin.set_high();
-                    }
-                    thread::sleep(time::Duration::from_micros(active_time)); 
-                    break;
+        // Update the metrics (if metrics_option is set to true).
+        // A loop cycle overran if its calculation took longer than the loop period.
+        if let Some(metrics) = &metrics {
+            if let Ok(mut metrics) = metrics.lock() {
+                if temp_read {
+                    let names = sensors.names();
+                    metrics.count_failures(&names, &sensors.readings);
+                    metrics.sensors = names.into_iter().zip(sensors.readings.iter().copied()).collect();
                 }
+                metrics.temp = cpu_temp_orig;
+                metrics.temp_dampened = cpu_temp_orig.and(control.temp_dampened);
+                metrics.duty_cycle = duty_cycle_applied;
+                metrics.rpm = rpm;
+                metrics.fan_on = control.fan_on;
+                metrics.manual_duty = control.manual.map(|manual| manual.duty_cycle);
+                metrics.profile.clone_from(&config.profile);
+                metrics.fan_state_changes += u64::from(control.fan_on != fan_on_prev);
+                metrics.loop_overruns += u64::from(cycle_start.elapsed() > time::Duration::from_micros(loop_micros));
+            }
+        }
+        fan_on_prev = control.fan_on;
+
+
+        // Trace the control cycle after every temperature reading (debug level), including the reading of every sensor (trace level).
+        if temp_read && log_enabled(Level::Debug) {
+            log_event(Level::Debug, &format!("Temperature: {} | Dampened: {} | Duty cycle: {:.3} | Fan: {}{}",
+                cpu_temp_orig.map_or("n/a".to_string(), |temp| format!("{:.2} °C", temp)),
+                cpu_temp_orig.and(control.temp_dampened).map_or("n/a".to_string(), |temp| format!("{:.2} °C", temp)),
+                state.duty_cycle,
+                if control.fan_on {format!("on at {:.3}", duty_cycle_applied)} else {"off".to_string()},
+                rpm.map_or(String::new(), |rpm| format!(" ({:.0} RPM)", rpm))), config.logging.log_option);
+            if log_enabled(Level::Trace) {
+                let readings: Vec<String> = sensors.names().iter().zip(&sensors.readings)
+                    .map(|(name, temp)| format!("{}: {}", name, temp.map_or("n/a".to_string(), |temp| format!("{:.2} °C", temp)))).collect();
+                log_event(Level::Trace, &format!("Sensors: {} | Override: {}", readings.join(", "),
+                    control.override_duty_cycle.map_or("none".to_string(), |duty_cycle| format!("{:.3}", duty_cycle))), config.logging.log_option);
+            }
+        }
 
 
-                // Keep the fan off, due to time delay.
-                // The fan is not running and the temperature is above the minimum temperature.
-                // However, the time since the fan was stopped is still below the delay duration.
-                // The fan will not start running but stay off.
-                if !fan_on && cpu_temp > min_temp && elapsed_time < delay_duration  {
-                    /* fan_on = false;
-                    if let Ok(mut pin) = pin.lock() {
-                        pin.set_low();
-                    } */
-                    thread::sleep(time::Duration::from_micros(period_micros));
-                    break;
-                }
+        // Print the temperature and the fan speed to the terminal.
+        // With --foreground after every temperature reading, with --once after the single control cycle.
+        // After every temperature reading, the same line is sent to systemd as the status of the service, if it changed.
+        if temp_read || args.once {
+            let status = status_line(cpu_temp_orig, control.fan_on, duty_cycle_applied, rpm);
+            if args.foreground || args.once {
+                println!("{}", status);
+            }
+            notifier.status(&status);
+        }
 
 
-                // In case none of the other options triggered, wait and break.
-                // This should not be required since all possibilities should be covered by the previous options.
-                thread::sleep(time::Duration::from_micros(period_micros));
-                break;
-            }
+        // Ping the systemd watchdog, which restarts the service if the control loop hangs.
+        notifier.watchdog();
 
 
-        // If there was no valid temperature reading, wait for one period.    
-        } else {thread::sleep(time::Duration::from_micros(period_micros));}
+        // Wait for one loop period.
+        // The fan driver keeps the PWM signal running in the meantime.
+        // If there was no valid temperature reading, the fan was already set to full speed.
+        thread::sleep(time::Duration::from_micros(loop_micros));
 
 
         // Log statistics if the option is set to true.
         // Statistics are logged on the first iteration of each stats period and there each nth iteration.
         // If a SIGINT/SIGTERM/SIGHUP signal is received, stats are always logged and written to teh .csv file.
-        if stat_option && (i % stat_cycle == 0 || i_stat == 1 || (shdn_recv | rld_recv)) {
-            i_stat += 1;
+        if config.stats.stat_option && (state.i.is_multiple_of(stat_cycle) || state.i_stat == 1 || (shdn_recv | rld_recv | flush_recv)) {
+            state.i_stat += 1;
 
             log_statistics(
                 
                 // Input arguments
-                &mut i_stat, &mut n_on_temp, &mut n_on_fan,
-                stat_min_time, stat_max_time, stat_delta_t, &mut stat_time_start,
-                log_option,
-                duty_cycle,
+                &mut state, &config.stats,
+                config.logging.log_option,
                 cpu_temp_missing,
-                cpu_temp_orig, &mut cpu_temp_min, &mut cpu_temp_max, &mut cpu_temp_avg,
-                fan_on,
-                &mut fan_state_stat, &mut fan_state_prev,
-                &mut fan_speed_min, &mut fan_speed_max, &mut fan_speed_avg,
-                file_path, shdn_recv, rld_recv
+                cpu_temp_orig,
+                control.fan_on, duty_cycle_applied,
+                &sensors.readings, &mut sensor_stats,
+                rpm, if tach.is_some() {Some(&mut rpm_stat)} else {None},
+                &config.stats.path, shdn_recv | rld_recv | flush_recv
             );
         }
-        i += 1;
+
+        // Rotate the log file once per statistics cycle (see rotate_log).
+        if state.i.is_multiple_of(stat_cycle) {
+            rotate_log();
+        }
+        state.i += 1;
+
+
+        // With --once, leave the main loop after a single control cycle.
+        if args.once {
+            break;
+        }
 
 
         // If a SIGINT/SIGTERM/SIGHUP signal was received, leave the main loop.
         // For a SIGINT/SIGTERM signal, nothing more is required, the program will shut down.
         if shdn_recv {
-            log_event("SIGINT/SIGTERM signal received. Turning fan off and exiting program.", log_option);
+            notifier.stopping();
+            log_event(Level::Info, "SIGINT/SIGTERM signal received. Turning fan off and exiting program.", config.logging.log_option);
             break;
         }
 
+        // If a SIGHUP signal was received, reload the environmental variables and swap the new configuration into the running control loop.
+        // The sensors, the tachometer, the fan driver and the controllers are only set up again if their settings changed.
+        // The fan state, the dampened temperature and the statistics are kept.
         if rld_recv {
-            log_event("SIGHUP signal received. Restarting program to reload environmental variables. ", log_option);
-            restart = true;
-            break;
+            notifier.reloading();
+            log_event(Level::Info, "SIGHUP signal or reload command received. Reloading environmental variables.", config.logging.log_option);
+            let overrides: Vec<(String, String)> = args.overrides.iter().cloned().chain(profile_override.clone()).collect();
+            let (new_config, report) = load_var(&dir, &fallback_dirs, args.config.as_deref(), &overrides);
+            // If any setting is invalid, keep the running configuration.
+            let mut new_config: Config = match new_config {
+                Ok(new_config) => {
+                    profile_loaded = profile_override.clone();
+                    new_config
+                }
+                Err(e) => {
+                    log_event(Level::Error, &format!("Keeping the previous configuration due to {}", e), config.logging.log_option);
+                    // A profile switch that failed is undone, so that the next reload does not fail again because of it.
+                    if profile_override != profile_loaded {
+                        log_event(Level::Warn, &format!("Staying on profile '{}'.", config.profile), config.logging.log_option);
+                        profile_override = profile_loaded.clone();
+                    }
+                    notifier.ready(&status_line(cpu_temp_orig, control.fan_on, duty_cycle_applied, rpm));
+                    continue;
+                }
+            };
+            let log_option = new_config.logging.log_option;
+            if new_config.logging != config.logging {
+                if let Err(e) = set_logging(&new_config.logging) {
+                    log_event(Level::Error, &format!("{}. Events are not written to the log file.", e), false);
+                }
+            }
+            log_report(&report, log_option);
+            let csv_columns = (sensors.names(), tach.is_some());
+
+            if (&new_config.sensor.sensors, new_config.sensor.aggregation, new_config.control.min_temp, new_config.control.max_temp) != (&config.sensor.sensors, config.sensor.aggregation, config.control.min_temp, config.control.max_temp) {
+                sensors = SensorGroup::open(&new_config.sensor.sensors, new_config.sensor.aggregation, new_config.control.min_temp, new_config.control.max_temp);
+                sensor_stats = sensors.names().into_iter().map(|name| ValueStat { name, ..Default::default() }).collect();
+                log_event(Level::Info, &format!("Reading temperatures from {} (aggregation: {}).", sensors.describe(), new_config.sensor.aggregation), log_option);
+            }
+
+            // The previous tachometer and fan driver are released first, since the new ones may use the same GPIO pins.
+            if (new_config.tach.pin, new_config.tach.ppr) != (config.tach.pin, config.tach.ppr) {
+                drop(tach.take());
+                rpm = None;
+                tach = init_tach(tach_pin(&new_config), new_config.tach.ppr, log_option);
+            }
+
+            if (new_config.pwm.gpio_pin, new_config.pwm.mode, new_config.pwm.freq, new_config.pwm.chip, new_config.pwm.channel) != (config.pwm.gpio_pin, config.pwm.mode, config.pwm.freq, config.pwm.chip, config.pwm.channel) {
+                drop(driver);
+                driver = init_driver(new_config.pwm.gpio_pin, new_config.pwm.mode, new_config.pwm.freq, new_config.pwm.chip, new_config.pwm.channel, log_option);
+                if control.duty_cycle > 0.0 {driver.set_duty(control.duty_cycle)} else {driver.off()};
+            }
+
+            if (&new_config.control.pid, new_config.control.min_duty_cycle, new_config.control.max_duty_cycle) != (&config.control.pid, config.control.min_duty_cycle, config.control.max_duty_cycle) {
+                control.pid = Pid::new(new_config.control.pid.clone(), new_config.control.min_duty_cycle, new_config.control.max_duty_cycle);
+            }
+            if (&new_config.control.rpm_pid, new_config.control.min_duty_cycle, new_config.control.max_duty_cycle) != (&config.control.rpm_pid, config.control.min_duty_cycle, config.control.max_duty_cycle) {
+                control.rpm_pid = Pid::reverse(new_config.control.rpm_pid.clone(), new_config.control.min_duty_cycle, new_config.control.max_duty_cycle);
+            }
+            if new_config.tach.stall != config.tach.stall {
+                control.stall = StallDetector::new(new_config.tach.stall.clone());
+            }
+
+            // The control socket keeps running, since a client may be connected to it.
+            if new_config.metrics != config.metrics {
+                log_event(Level::Warn, "Changes of metrics_option and metrics_address take effect after a restart.", log_option);
+                new_config.metrics = config.metrics.clone();
+            }
+            if new_config.mqtt != config.mqtt {
+                log_event(Level::Warn, "Changes of the MQTT settings take effect after a restart.", log_option);
+                new_config.mqtt = config.mqtt.clone();
+            }
+            if (new_config.socket.socket_option, &new_config.socket.path) != (config.socket.socket_option, &config.socket.path) {
+                log_event(Level::Warn, "Changes of socket_option and socket_path take effect after a restart.", log_option);
+                new_config.socket.socket_option = config.socket.socket_option;
+                new_config.socket.path = config.socket.path.clone();
+            }
+
+            control.mode = effective_control_mode(&new_config, tach.is_some());
+            (loop_micros, temp_cycle, stat_cycle) = new_config.loop_timing();
+
+            // If the columns of the statistics file changed, keep the previous file as a backup and start a new one.
+            // If stat_path changed, the statistics continue in the new file.
+            if new_config.stats.stat_option {
+                let file_path: &str = &new_config.stats.path;
+                if let Err(e) = create_dir(file_path) {
+                    log_event(Level::Error, &format!("{}. Statistics are not saved.", e), log_option);
+                }
+                if file_path == config.stats.path && csv_columns != (sensors.names(), tach.is_some()) && Path::new(file_path).exists() {
+                    let backup = format!("{}.bak", file_path);
+                    match fs::rename(file_path, &backup) {
+                        Ok(()) => log_event(Level::Info, &format!("The columns of the statistics file changed. The previous file was moved to {}.", backup), log_option),
+                        Err(e) => log_event(Level::Error, &format!("Failed to move {} to {}: {}", file_path, backup, e), log_option),
+                    }
+                }
+                create_csv(file_path, &sensors.names(), tach.is_some(), log_option);
+            }
+
+            config = new_config;
+            log_event(Level::Info, "Environmental variables reloaded.", log_option);
+            notifier.ready(&status_line(cpu_temp_orig, control.fan_on, duty_cycle_applied, rpm));
         }
     }
 
     
     // When the program leaves the main loop, it will shut down.
     // Ensure that the fan is off.
-    if let Ok(mut pin) = pin.lock() {
-        pin.set_low();
-    }
-    thread::sleep(time::Duration::from_micros(period_micros));
+    driver.off();
+    thread::sleep(time::Duration::from_micros(config.pwm.period_micros));
 
     
-    // Clean up the signal_hook handle.
+    // Clean up the signal_hook handle and the control socket.
     handle.close();
+    if socket_created {
+        let _ = fs::remove_file(&config.socket.path);
+    }
 
 
-    // In case a SIGHUP signal is received the program is exited with a non 0 exit code to "force" the restart by systemd.
-    // This is a very bad implementation but I could not get it to work any other way :(
-    if restart {
-        log_event("Exiting program with exit(1). Program should be restarted by systemd.", log_option);
-        process::exit(1);
-    }
-    
     // Program ends ... as all things must.
-    log_event("Exiting program. Thank you and goodbye! Hope to see you again soon!", log_option);
+    log_event(Level::Info, "Exiting program. Thank you and goodbye! Hope to see you again soon!", config.logging.log_option);
     Ok(())
-}
\ No newline at end of file
+}
+
+
+// Log the settings that are not defined and the ones that were adjusted (see ConfigReport).
+fn log_report(report: &ConfigReport, log_option: bool) {
+    if !report.missing.is_empty() {
+        log_event(Level::Warn, &format!("Not defined, using default values for: {}", report.missing.join(", ")), log_option);
+    }
+    for adjusted in &report.adjusted {
+        log_event(Level::Warn, &format!("'{}' {}.", adjusted.key, adjusted.reason), log_option);
+    }
+}
+
+
+// Temperature and fan speed as a single line, printed with --foreground and sent to systemd as the status of the service.
+fn status_line(temp: Option<f32>, fan_on: bool, duty_cycle: f32, rpm: Option<f32>) -> String {
+    format!("Temperature: {} | Fan: {}{}",
+        temp.map_or("n/a".to_string(), |temp| format!("{:.1} °C", temp)),
+        if fan_on {format!("on at {:.0} %", duty_cycle * 100.0)} else {"off".to_string()},
+        rpm.map_or(String::new(), |rpm| format!(" ({:.0} RPM)", rpm)))
+}
+
+
+// The "rpm" control mode requires the measured fan speed. Without a working tachometer, the fan curve is used instead.
+fn effective_control_mode(config: &Config, tach: bool) -> ControlMode {
+    if config.control.mode == ControlMode::Rpm && !tach {
+        log_event(Level::Warn, "The 'rpm' control mode requires a working tachometer. Using control mode: curve", config.logging.log_option);
+        ControlMode::Curve
+    } else {
+        config.control.mode
+    }
+}
@@ -0,0 +1,154 @@
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use crate::functions::{log_event, Level};
+
+
+// Time a client may take to send its request or to read the answer.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+
+// Current values of the fan control, served at /metrics in the Prometheus text format.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    pub sensors: Vec<(String, Option<f32>)>, // ... Name and latest reading of each sensor.
+    pub temp: Option<f32>, // ..................... Temperature of all sensors before dampening.
+    pub temp_dampened: Option<f32>,
+    pub duty_cycle: f32, // ....................... Duty cycle the fan is run at, 0.0 while it is off.
+    pub rpm: Option<f32>, // ...................... Only if a tachometer is used.
+    pub fan_on: bool,
+    pub manual_duty: Option<f32>, // .............. Duty cycle set with the control socket or MQTT, none while automatic.
+    pub profile: String, // ....................... Name of the selected profile.
+    pub fan_state_changes: u64,
+    pub sensor_failures: Vec<(String, u64)>, // ... Failed readings of each sensor.
+    pub loop_overruns: u64, // .................... Loop cycles whose calculation took longer than the loop period.
+}
+
+impl Metrics {
+
+    // Count the failed readings of the sensors after a temperature reading.
+    pub fn count_failures(&mut self, names: &[String], readings: &[Option<f32>]) {
+        for (name, reading) in names.iter().zip(readings) {
+            match self.sensor_failures.iter_mut().find(|(sensor, _)| sensor == name) {
+                Some((_, failures)) => *failures += u64::from(reading.is_none()),
+                None => self.sensor_failures.push((name.clone(), u64::from(reading.is_none()))),
+            }
+        }
+    }
+
+
+    // Render the metrics in the Prometheus text format.
+    // Values that are unknown (e.g. a failed sensor) are left out.
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, Option<f64>)]| {
+            let _ = writeln!(out, "# HELP rpi_fanctrl_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE rpi_fanctrl_{} {}", name, kind);
+            for (labels, value) in samples {
+                if let Some(value) = value.filter(|value| value.is_finite()) {
+                    let _ = writeln!(out, "rpi_fanctrl_{}{} {}", name, labels, value);
+                }
+            }
+        };
+        let sensor = |name: &str| format!("{{sensor=\"{}\"}}", name.replace('\\', "\\\\").replace('"', "\\\""));
+
+        metric("sensor_temperature_celsius", "gauge", "Latest temperature reading of each sensor including its offset.",
+            &self.sensors.iter().map(|(name, temp)| (sensor(name), temp.map(f64::from))).collect::<Vec<_>>());
+        metric("temperature_celsius", "gauge", "Temperature of all sensors before dampening.", &[(String::new(), self.temp.map(f64::from))]);
+        metric("temperature_dampened_celsius", "gauge", "Dampened temperature the fan speed is calculated from.", &[(String::new(), self.temp_dampened.map(f64::from))]);
+        metric("duty_cycle", "gauge", "Duty cycle the fan is run at (0 to 1).", &[(String::new(), Some(f64::from(self.duty_cycle)))]);
+        metric("fan_rpm", "gauge", "Measured fan speed in RPM.", &[(String::new(), self.rpm.map(f64::from))]);
+        metric("fan_on", "gauge", "Whether the fan is running (1) or off (0).", &[(String::new(), Some(f64::from(u8::from(self.fan_on))))]);
+        metric("manual_override", "gauge", "Whether the fan speed is set manually (1) or automatic (0).", &[(String::new(), Some(f64::from(u8::from(self.manual_duty.is_some()))))]);
+        metric("fan_state_changes_total", "counter", "Number of times the fan was turned on or off.", &[(String::new(), Some(self.fan_state_changes as f64))]);
+        metric("sensor_read_failures_total", "counter", "Number of failed temperature readings of each sensor.",
+            &self.sensor_failures.iter().map(|(name, failures)| (sensor(name), Some(*failures as f64))).collect::<Vec<_>>());
+        metric("loop_overruns_total", "counter", "Number of control loop cycles whose calculation took longer than the loop period.", &[(String::new(), Some(self.loop_overruns as f64))]);
+        out
+    }
+}
+
+
+// Serve the metrics over HTTP in a separate thread.
+// Every connection is answered in a short-lived thread of its own, so a slow or idle client cannot block the others.
+pub fn serve_metrics(address: SocketAddr, metrics: Arc<Mutex<Metrics>>, log_option: bool) -> Result<(), String> {
+    let listener = TcpListener::bind(address).map_err(|e| format!("Cannot listen on {}: {}", address, e))?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let metrics = Arc::clone(&metrics);
+            thread::spawn(move || {
+                if let Err(e) = handle_client(stream, &metrics) {
+                    log_event(Level::Debug, &format!("Metrics endpoint: {}", e), log_option);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+
+// Answer a single HTTP request. Only GET /metrics is served.
+fn handle_client(stream: TcpStream, metrics: &Mutex<Metrics>) -> Result<(), String> {
+    stream.set_read_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).map_err(|e| e.to_string())?;
+    // Skip the headers, so the connection is not reset before the client read the answer.
+    let mut header = String::new();
+    while reader.read_line(&mut header).is_ok_and(|n| n > 0) && !header.trim().is_empty() {
+        header.clear();
+    }
+    let mut request = request_line.split_whitespace();
+    let (status, content_type, body) = match (request.next(), request.next().map(|path| path.split('?').next().unwrap_or(path))) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = metrics.lock().map(|metrics| metrics.render()).map_err(|e| e.to_string())?;
+            ("200 OK", "text/plain; version=0.0.4; charset=utf-8", body)
+        }
+        (Some("GET"), Some("/")) => ("200 OK", "text/plain; charset=utf-8", "rpi_fanctrl: see /metrics\n".to_string()),
+        (Some("GET"), _) => ("404 Not Found", "text/plain; charset=utf-8", "Not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "text/plain; charset=utf-8", "Method not allowed\n".to_string()),
+    };
+    write!(&stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, content_type, body.len(), body)
+        .map_err(|e| e.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_exposition_text() {
+        let metrics = Metrics {
+            sensors: vec![("cpu".to_string(), Some(52.5)), ("nvme \"0\"".to_string(), None)],
+            temp: Some(52.5),
+            temp_dampened: None,
+            duty_cycle: 0.25,
+            fan_on: true,
+            fan_state_changes: 3,
+            sensor_failures: vec![("cpu".to_string(), 0), ("nvme \"0\"".to_string(), 2)],
+            ..Default::default()
+        };
+        let text = metrics.render();
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert!(lines.contains(&"# HELP rpi_fanctrl_duty_cycle Duty cycle the fan is run at (0 to 1)."));
+        assert!(lines.contains(&"# TYPE rpi_fanctrl_duty_cycle gauge"));
+        assert!(lines.contains(&"rpi_fanctrl_duty_cycle 0.25"));
+        assert!(lines.contains(&"rpi_fanctrl_sensor_temperature_celsius{sensor=\"cpu\"} 52.5"));
+        assert!(lines.contains(&"rpi_fanctrl_fan_on 1"));
+        assert!(lines.contains(&"rpi_fanctrl_manual_override 0"));
+        assert!(lines.contains(&"# TYPE rpi_fanctrl_fan_state_changes_total counter"));
+        assert!(lines.contains(&"rpi_fanctrl_fan_state_changes_total 3"));
+        assert!(lines.contains(&"rpi_fanctrl_sensor_read_failures_total{sensor=\"nvme \\\"0\\\"\"} 2"));
+
+        // Unknown values are left out, but the metric is still described.
+        assert!(lines.contains(&"# TYPE rpi_fanctrl_temperature_dampened_celsius gauge"));
+        assert!(!lines.iter().any(|line| line.starts_with("rpi_fanctrl_temperature_dampened_celsius") || line.starts_with("rpi_fanctrl_fan_rpm")));
+        assert!(!lines.iter().any(|line| line.starts_with("rpi_fanctrl_sensor_temperature_celsius{sensor=\"nvme")));
+    }
+}
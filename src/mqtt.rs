@@ -0,0 +1,335 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::config::MqttConfig;
+use crate::control::{json_field, reply_error, Command, Request};
+use crate::functions::{json_string, log_event, Level};
+use crate::metrics::Metrics;
+
+
+// Keep alive interval of the connection in s. A ping is sent every half of it, the broker has to answer it within TIMEOUT.
+const KEEP_ALIVE: u16 = 60;
+// Time the broker may take to answer and to send the rest of a packet.
+const TIMEOUT: Duration = Duration::from_secs(10);
+// Minimum and maximum time between attempts to connect to the broker.
+const RECONNECT_MIN: Duration = Duration::from_secs(5);
+const RECONNECT_MAX: Duration = Duration::from_secs(300);
+
+
+// Types of the MQTT 3.1.1 control packets that are used (upper 4 bits of the first byte).
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82; // ... Including the required flags.
+const PINGREQ: u8 = 0xC0;
+const PINGRESP: u8 = 0xD0;
+
+
+// What the Home Assistant discovery announces in addition to the temperature, the duty cycle and the fan state.
+#[derive(Clone, Debug)]
+pub struct Discovery {
+    pub tach: bool, // ............ Whether the fan speed in RPM is measured.
+    pub profiles: Vec<String>, // ... Names of the profiles of the configuration file.
+}
+
+
+// Publish the state of the fan control to an MQTT broker in a separate thread.
+// Every mqtt_interval, the state is published as JSON to <mqtt_topic>/state. Commands of the control socket (e.g. "set-duty 0.5 600" or "profile quiet")
+// can be sent to <mqtt_topic>/set and are answered on <mqtt_topic>/result. <mqtt_topic>/availability is "online" while connected and "offline" otherwise.
+// If the connection is lost, the client reconnects with an increasing delay.
+pub fn start_client(mqtt: MqttConfig, metrics: Arc<Mutex<Metrics>>, tx: mpsc::Sender<Request>, discovery: Discovery, log_option: bool) {
+    thread::spawn(move || {
+        let mut delay = RECONNECT_MIN;
+        loop {
+            let mut healthy = false;
+            if let Err(e) = session(&mqtt, &metrics, &tx, &discovery, &mut healthy, log_option) {
+                // A connection whose pings were answered was working, so the next attempt is made soon.
+                if healthy {
+                    delay = RECONNECT_MIN;
+                }
+                log_event(Level::Warn, &format!("MQTT broker {}:{}: {}. Reconnecting in {} s.", mqtt.host, mqtt.port, e, delay.as_secs()), log_option);
+            }
+            thread::sleep(delay);
+            delay = (delay * 2).min(RECONNECT_MAX);
+        }
+    });
+}
+
+
+// Connect to the broker and publish the state until the connection fails.
+// healthy is set once the broker answered a ping, i.e. the connection was working.
+fn session(mqtt: &MqttConfig, metrics: &Mutex<Metrics>, tx: &mpsc::Sender<Request>, discovery: &Discovery, healthy: &mut bool, log_option: bool) -> Result<(), String> {
+    let address = (mqtt.host.as_str(), mqtt.port).to_socket_addrs().map_err(|e| e.to_string())?
+        .next().ok_or_else(|| "Cannot resolve the host".to_string())?;
+    let mut stream = TcpStream::connect_timeout(&address, TIMEOUT).map_err(|e| e.to_string())?;
+    stream.set_read_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+    let availability = format!("{}/availability", mqtt.topic);
+    let command_topic = format!("{}/set", mqtt.topic);
+
+    // Connect with availability "offline" as the last will, which the broker publishes if the connection is lost.
+    let mut flags: u8 = 0x02 | 0x04 | 0x20; // ... Clean session, last will, retain the last will.
+    let mut payload = string(&mqtt.client_id);
+    payload.extend(string(&availability));
+    payload.extend(string("offline"));
+    if let Some(user) = &mqtt.user {
+        flags |= 0x80;
+        payload.extend(string(user));
+        if let Some(password) = &mqtt.password {
+            flags |= 0x40;
+            payload.extend(string(password));
+        }
+    }
+    let mut variable_header = string("MQTT");
+    variable_header.extend([4, flags]);
+    variable_header.extend(KEEP_ALIVE.to_be_bytes());
+    variable_header.extend(payload);
+    send(&mut stream, CONNECT, &variable_header)?;
+    match read_packet(&mut stream)? {
+        (CONNACK, body) if body.get(1) == Some(&0) => (),
+        (CONNACK, body) => return Err(format!("Connection refused (return code {})", body.get(1).copied().unwrap_or(0))),
+        _ => return Err("Unexpected answer to the connection request".to_string()),
+    }
+    log_event(Level::Info, &format!("Connected to the MQTT broker {}:{} (topic: {}).", mqtt.host, mqtt.port, mqtt.topic), log_option);
+
+    publish(&mut stream, &availability, "online", true)?;
+    if mqtt.discovery {
+        for (topic, config) in discovery_configs(mqtt, discovery) {
+            publish(&mut stream, &topic, &config, true)?;
+        }
+    }
+    let mut subscribe = 1_u16.to_be_bytes().to_vec();
+    subscribe.extend(string(&command_topic));
+    subscribe.push(0);
+    send(&mut stream, SUBSCRIBE, &subscribe)?;
+
+    let mut last_state: Option<Instant> = None;
+    let mut last_ping = Instant::now();
+    let mut ping_sent: Option<Instant> = None; // ... Time of the ping that was not answered yet.
+    loop {
+        // Publish the state every mqtt_interval.
+        if last_state.is_none_or(|time| time.elapsed() >= mqtt.interval) {
+            let state = metrics.lock().map(|metrics| state_json(&metrics)).map_err(|e| e.to_string())?;
+            publish(&mut stream, &format!("{}/state", mqtt.topic), &state, false)?;
+            last_state = Some(Instant::now());
+        }
+
+        // Keep the connection alive with a ping every half keep alive interval, regardless of the published messages.
+        // Since messages are published with QoS 0, the answers to the pings are the only way to tell that the broker is still there.
+        if ping_sent.is_some_and(|time| time.elapsed() >= TIMEOUT) {
+            return Err("The broker did not answer".to_string());
+        }
+        if ping_sent.is_none() && last_ping.elapsed() >= Duration::from_secs(u64::from(KEEP_ALIVE / 2)) {
+            send(&mut stream, PINGREQ, &[])?;
+            last_ping = Instant::now();
+            ping_sent = Some(last_ping);
+        }
+
+        // Wait for packets of the broker for up to 1 s. Only published commands are handled, other packets (e.g. answers to pings) are skipped.
+        stream.set_read_timeout(Some(Duration::from_secs(1))).map_err(|e| e.to_string())?;
+        let mut first = [0_u8; 1];
+        match stream.read(&mut first) {
+            Ok(0) => return Err("Connection closed by the broker".to_string()),
+            Ok(_) => (),
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(e.to_string()),
+        }
+        stream.set_read_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+        let body = read_body(&mut stream)?;
+        if first[0] & 0xF0 == PINGRESP {
+            ping_sent = None;
+            *healthy = true;
+            continue;
+        }
+        if first[0] & 0xF0 != PUBLISH {
+            continue;
+        }
+        let Some((topic, payload)) = parse_publish(first[0], &body) else {
+            continue;
+        };
+        if topic != command_topic {
+            continue;
+        }
+
+        // Forward the command to the main loop like a command of the control socket and publish its answer.
+        let reply = match Command::parse(&payload) {
+            Ok(command) => {
+                let (reply_tx, reply_rx) = mpsc::channel();
+                tx.send(Request { command, reply: reply_tx }).map_err(|e| e.to_string())?;
+                reply_rx.recv_timeout(TIMEOUT).unwrap_or_else(|_| reply_error("The fan control did not answer"))
+            }
+            Err(e) => reply_error(&e),
+        };
+        publish(&mut stream, &format!("{}/result", mqtt.topic), &reply, false)?;
+    }
+}
+
+
+// State of the fan control as JSON.
+fn state_json(metrics: &Metrics) -> String {
+    format!("{{{},{},{},\"fan_on\":{},{},{},\"profile\":{}}}",
+        json_field("temp", metrics.temp, 2),
+        json_field("temp_dampened", metrics.temp_dampened, 2),
+        json_field("duty_cycle", Some(metrics.duty_cycle), 3),
+        metrics.fan_on,
+        json_field("rpm", metrics.rpm, 0),
+        json_field("manual_duty", metrics.manual_duty, 3),
+        json_string(&metrics.profile))
+}
+
+
+// Home Assistant discovery messages (topic and config) that add the fan control as a device with its sensors and controls.
+fn discovery_configs(mqtt: &MqttConfig, discovery: &Discovery) -> Vec<(String, String)> {
+    let node_id: String = mqtt.client_id.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' {c} else {'_'}).collect();
+    let device = format!("{{\"identifiers\":[{}],\"name\":{},\"manufacturer\":\"rpi_fanctrl\",\"model\":\"Raspberry Pi fan control\",\"sw_version\":{}}}",
+        json_string(&node_id), json_string(&mqtt.device_name), json_string(env!("CARGO_PKG_VERSION")));
+    let common = |object: &str, name: &str| format!("\"name\":{},\"unique_id\":{},\"availability_topic\":{},\"device\":{}",
+        json_string(name), json_string(&format!("{}_{}", node_id, object)), json_string(&format!("{}/availability", mqtt.topic)), device);
+    let state_topic = json_string(&format!("{}/state", mqtt.topic));
+    let command_topic = json_string(&format!("{}/set", mqtt.topic));
+    let topic = |component: &str, object: &str| format!("{}/{}/{}/{}/config", mqtt.discovery_prefix, component, node_id, object);
+
+    let mut configs = vec![
+        (topic("sensor", "temperature"), format!("{{{},\"state_topic\":{},\"value_template\":\"{{{{ value_json.temp }}}}\",\"unit_of_measurement\":\"°C\",\"device_class\":\"temperature\",\"state_class\":\"measurement\"}}",
+            common("temperature", "Temperature"), state_topic)),
+        (topic("sensor", "temperature_dampened"), format!("{{{},\"state_topic\":{},\"value_template\":\"{{{{ value_json.temp_dampened }}}}\",\"unit_of_measurement\":\"°C\",\"device_class\":\"temperature\",\"state_class\":\"measurement\",\"entity_category\":\"diagnostic\"}}",
+            common("temperature_dampened", "Dampened temperature"), state_topic)),
+        (topic("sensor", "duty_cycle"), format!("{{{},\"state_topic\":{},\"value_template\":\"{{{{ (value_json.duty_cycle * 100) | round(0) }}}}\",\"unit_of_measurement\":\"%\",\"state_class\":\"measurement\",\"icon\":\"mdi:fan\"}}",
+            common("duty_cycle", "Fan speed"), state_topic)),
+        (topic("binary_sensor", "fan"), format!("{{{},\"state_topic\":{},\"value_template\":\"{{{{ 'ON' if value_json.fan_on else 'OFF' }}}}\",\"device_class\":\"running\"}}",
+            common("fan", "Fan"), state_topic)),
+        (topic("number", "manual_duty"), format!("{{{},\"state_topic\":{},\"value_template\":\"{{{{ ((value_json.manual_duty or 0) * 100) | round(0) }}}}\",\"command_topic\":{},\"command_template\":\"set-duty {{{{ value / 100 }}}}\",\"min\":0,\"max\":100,\"step\":1,\"unit_of_measurement\":\"%\",\"mode\":\"slider\",\"icon\":\"mdi:fan-chevron-up\"}}",
+            common("manual_duty", "Manual fan speed"), state_topic, command_topic)),
+        (topic("button", "release"), format!("{{{},\"command_topic\":{},\"payload_press\":\"release\",\"icon\":\"mdi:fan-auto\"}}",
+            common("release", "Automatic fan control"), command_topic)),
+    ];
+    if discovery.tach {
+        configs.push((topic("sensor", "rpm"), format!("{{{},\"state_topic\":{},\"value_template\":\"{{{{ value_json.rpm }}}}\",\"unit_of_measurement\":\"RPM\",\"state_class\":\"measurement\",\"icon\":\"mdi:fan\"}}",
+            common("rpm", "Fan RPM"), state_topic)));
+    }
+    if !discovery.profiles.is_empty() {
+        let options: Vec<String> = std::iter::once("default").chain(discovery.profiles.iter().map(String::as_str)).map(json_string).collect();
+        configs.push((topic("select", "profile"), format!("{{{},\"state_topic\":{},\"value_template\":\"{{{{ value_json.profile }}}}\",\"command_topic\":{},\"command_template\":\"profile {{{{ value }}}}\",\"options\":[{}],\"icon\":\"mdi:tune\"}}",
+            common("profile", "Profile"), state_topic, command_topic, options.join(","))));
+    }
+    configs
+}
+
+
+// Encode a string with its length.
+fn string(s: &str) -> Vec<u8> {
+    let mut encoded = (s.len() as u16).to_be_bytes().to_vec();
+    encoded.extend_from_slice(s.as_bytes());
+    encoded
+}
+
+
+// Send a packet with its remaining length (7 bits per byte, the highest bit marks that another byte follows).
+fn send(stream: &mut impl Write, first: u8, body: &[u8]) -> Result<(), String> {
+    let mut packet = vec![first];
+    let mut length = body.len();
+    loop {
+        let byte = (length % 128) as u8;
+        length /= 128;
+        packet.push(if length > 0 {byte | 0x80} else {byte});
+        if length == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    stream.write_all(&packet).map_err(|e| e.to_string())
+}
+
+
+// Publish a message (QoS 0).
+fn publish(stream: &mut impl Write, topic: &str, payload: &str, retain: bool) -> Result<(), String> {
+    let mut body = string(topic);
+    body.extend_from_slice(payload.as_bytes());
+    send(stream, PUBLISH | u8::from(retain), &body)
+}
+
+
+// Read a whole packet, returning its type and its body.
+fn read_packet(stream: &mut impl Read) -> Result<(u8, Vec<u8>), String> {
+    let mut first = [0_u8; 1];
+    stream.read_exact(&mut first).map_err(|e| e.to_string())?;
+    Ok((first[0] & 0xF0, read_body(stream)?))
+}
+
+
+// Read the remaining length and the body of a packet after its first byte.
+fn read_body(stream: &mut impl Read) -> Result<Vec<u8>, String> {
+    let mut length: usize = 0;
+    for shift in 0..4 {
+        let mut byte = [0_u8; 1];
+        stream.read_exact(&mut byte).map_err(|e| e.to_string())?;
+        length |= usize::from(byte[0] & 0x7F) << (7 * shift);
+        if byte[0] & 0x80 == 0 {
+            let mut body = vec![0_u8; length];
+            stream.read_exact(&mut body).map_err(|e| e.to_string())?;
+            return Ok(body);
+        }
+    }
+    Err("Invalid packet length".to_string())
+}
+
+
+// Get the topic and the payload of a published message.
+// Messages with QoS 1 or 2 contain a packet identifier after the topic.
+fn parse_publish(first: u8, body: &[u8]) -> Option<(String, String)> {
+    let length = usize::from(u16::from_be_bytes([*body.first()?, *body.get(1)?]));
+    let topic = String::from_utf8(body.get(2..2 + length)?.to_vec()).ok()?;
+    let start = 2 + length + if first & 0x06 != 0 {2} else {0};
+    let payload = String::from_utf8(body.get(start..)?.to_vec()).ok()?;
+    Some((topic, payload))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn string_is_prefixed_with_its_length() {
+        assert_eq!(string("MQTT"), vec![0, 4, b'M', b'Q', b'T', b'T']);
+        assert_eq!(string(""), vec![0, 0]);
+    }
+
+    #[test]
+    fn send_encodes_the_remaining_length() {
+        for (length, encoded) in [(0, vec![0x00]), (127, vec![0x7F]), (128, vec![0x80, 0x01]), (16_383, vec![0xFF, 0x7F]), (16_384, vec![0x80, 0x80, 0x01])] {
+            let mut packet: Vec<u8> = Vec::new();
+            send(&mut packet, PINGREQ, &vec![0; length]).unwrap();
+            assert_eq!(packet[0], PINGREQ);
+            assert_eq!(packet[1..1 + encoded.len()], encoded[..], "remaining length {}", length);
+            assert_eq!(packet.len(), 1 + encoded.len() + length);
+        }
+    }
+
+    #[test]
+    fn publish_round_trip() {
+        let mut packet: Vec<u8> = Vec::new();
+        publish(&mut packet, "rpi_fanctrl/state", "{\"temp\":45.0}", true).unwrap();
+        assert_eq!(packet[0], PUBLISH | 0x01);
+        let (first, body) = read_packet(&mut packet.as_slice()).unwrap();
+        assert_eq!(first, PUBLISH);
+        assert_eq!(parse_publish(packet[0], &body), Some(("rpi_fanctrl/state".to_string(), "{\"temp\":45.0}".to_string())));
+    }
+
+    #[test]
+    fn parse_publish_skips_the_packet_identifier() {
+        let mut body = string("rpi_fanctrl/set");
+        body.extend([0x00, 0x07]);
+        body.extend(b"release");
+        assert_eq!(parse_publish(PUBLISH | 0x02, &body), Some(("rpi_fanctrl/set".to_string(), "release".to_string())));
+        assert_eq!(parse_publish(PUBLISH, &[0x00]), None);
+    }
+
+    #[test]
+    fn read_body_rejects_invalid_lengths() {
+        assert!(read_body(&mut [0x80_u8, 0x80, 0x80, 0x80, 0x01].as_slice()).is_err());
+        assert!(read_body(&mut [0x05_u8, 0x01].as_slice()).is_err());
+        assert_eq!(read_body(&mut [0x00_u8].as_slice()), Ok(Vec::new()));
+    }
+}
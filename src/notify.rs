@@ -0,0 +1,83 @@
+use std::env;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::{Duration, Instant};
+
+
+// Notifications to systemd (sd_notify protocol), used if the service is started with Type=notify.
+// systemd passes the socket in NOTIFY_SOCKET and, if WatchdogSec= is set, the watchdog interval in WATCHDOG_USEC.
+// Without these variables (e.g. when started from a terminal), nothing is sent.
+pub struct Notifier {
+    socket: Option<(UnixDatagram, SocketAddr)>,
+    watchdog: Option<Duration>, // ... Interval between watchdog pings (half of WatchdogSec=), none if the watchdog is disabled.
+    last_ping: Instant,
+    status: String, // ................ Last status that was sent, so an unchanged status is not sent again.
+}
+
+impl Notifier {
+
+    // Read the socket and the watchdog interval passed by systemd.
+    // The variables are read once at startup.
+    pub fn from_env() -> Notifier {
+        let socket = env::var("NOTIFY_SOCKET").ok().and_then(|path| {
+            // A leading @ denotes a socket in the abstract namespace.
+            let address = match path.strip_prefix('@') {
+                Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+                None => SocketAddr::from_pathname(&path),
+            }.ok()?;
+            Some((UnixDatagram::unbound().ok()?, address))
+        });
+        // WATCHDOG_PID is only set if the watchdog applies to another process than the main process.
+        let watchdog = env::var("WATCHDOG_USEC").ok()
+            .filter(|_| env::var("WATCHDOG_PID").map_or(true, |pid| pid.trim() == std::process::id().to_string()))
+            .and_then(|usec| usec.trim().parse::<u64>().ok())
+            .filter(|&usec| usec > 0)
+            .map(|usec| Duration::from_micros(usec / 2));
+        Notifier { socket, watchdog, last_ping: Instant::now(), status: String::new() }
+    }
+
+    // Interval between watchdog pings, none if the watchdog is disabled.
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog.filter(|_| self.socket.is_some())
+    }
+
+    // Send a notification, e.g. "READY=1". Several assignments are separated by line breaks.
+    fn send(&self, state: &str) {
+        if let Some((socket, address)) = &self.socket {
+            let _ = socket.send_to_addr(state.as_bytes(), address);
+        }
+    }
+
+    // Tell systemd that the fan control is running (after the fan driver and the sensors were set up, and after a reload).
+    pub fn ready(&mut self, status: &str) {
+        self.status = status.to_string();
+        self.send(&format!("READY=1\nSTATUS={}", status));
+    }
+
+    // Update the status shown by "systemctl status", if it changed.
+    pub fn status(&mut self, status: &str) {
+        if self.status != status {
+            self.status = status.to_string();
+            self.send(&format!("STATUS={}", status));
+        }
+    }
+
+    // Tell systemd that the configuration is being reloaded. It is completed with ready.
+    pub fn reloading(&self) {
+        self.send("RELOADING=1\nSTATUS=Reloading the configuration");
+    }
+
+    // Tell systemd that the fan control is shutting down.
+    pub fn stopping(&self) {
+        self.send("STOPPING=1\nSTATUS=Turning the fan off and exiting");
+    }
+
+    // Ping the watchdog if half of its interval passed since the last ping.
+    // Called from every cycle of the control loop, so a control loop that hangs is restarted by systemd.
+    pub fn watchdog(&mut self) {
+        if self.watchdog.is_some_and(|interval| self.last_ping.elapsed() >= interval) {
+            self.last_ping = Instant::now();
+            self.send("WATCHDOG=1");
+        }
+    }
+}
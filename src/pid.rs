@@ -0,0 +1,124 @@
+// Settings of a PID controller.
+// The error is the difference between the measured value and the target, so a positive error (i.e. too hot) results in a higher output (for a reverse acting controller, the sign is inverted).
+#[derive(Clone, Debug, PartialEq)]
+pub struct PidSettings {
+    pub target: f32, // ... Target value (setpoint).
+    pub kp: f32, // ....... Proportional gain (output per unit of error).
+    pub ki: f32, // ....... Integral gain (output per unit of error and second).
+    pub kd: f32, // ....... Derivative gain (output per unit of change per second).
+}
+
+
+// PID controller with anti-windup.
+// The output is limited to min_output ... max_output.
+// To prevent windup, the integral is only extended while the output is not saturated (or the error drives it out of saturation),
+// and it is limited so that it can never push the output beyond its limits on its own.
+pub struct Pid {
+    settings: PidSettings,
+    min_output: f32,
+    max_output: f32,
+    direction: f32, // ... 1.0 for a direct acting controller, -1.0 for a reverse acting one.
+    integral: f32,
+    prev_value: Option<f32>,
+}
+
+impl Pid {
+
+    pub fn new(settings: PidSettings, min_output: f32, max_output: f32) -> Pid {
+        Pid { settings, min_output, max_output, direction: 1.0, integral: 0.0, prev_value: None }
+    }
+
+
+    // Create a reverse acting controller, i.e. a measured value below the target results in a higher output.
+    // Used to control the fan speed, where a too slow fan needs a higher duty cycle.
+    pub fn reverse(settings: PidSettings, min_output: f32, max_output: f32) -> Pid {
+        Pid { direction: -1.0, ..Pid::new(settings, min_output, max_output) }
+    }
+
+
+    // Change the target value.
+    // Since the derivative is calculated from the measured value, this does not cause a kick of the output.
+    pub fn set_target(&mut self, target: f32) {
+        self.settings.target = target;
+    }
+
+
+    // Reset the state of the controller, e.g. after the fan was turned off.
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.prev_value = None;
+    }
+
+
+    // Calculate the output for the measured value, dt seconds after the last update.
+    pub fn update(&mut self, value: f32, dt: f32) -> f32 {
+        let error = self.direction * (value - self.settings.target);
+
+        // The derivative is calculated from the measured value instead of the error, so that a change of the target does not cause a kick.
+        let derivative = match self.prev_value {
+            Some(prev_value) if dt > 0.0 => self.direction * (value - prev_value) / dt,
+            _ => 0.0,
+        };
+        self.prev_value = Some(value);
+
+        let proportional = self.settings.kp * error;
+        let output = proportional + self.integral + self.settings.kd * derivative;
+
+        // Only integrate if the output is not saturated, or if the error drives the output out of saturation.
+        let saturated_high = output >= self.max_output && error > 0.0;
+        let saturated_low = output <= self.min_output && error < 0.0;
+        if !saturated_high && !saturated_low {
+            self.integral += self.settings.ki * error * dt;
+        }
+        self.integral = self.integral.clamp(self.min_output, self.max_output);
+
+        (proportional + self.integral + self.settings.kd * derivative).clamp(self.min_output, self.max_output)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> PidSettings {
+        PidSettings { target: 60.0, kp: 0.05, ki: 0.01, kd: 0.0 }
+    }
+
+    #[test]
+    fn output_is_limited() {
+        let mut pid = Pid::new(settings(), 0.2, 1.0);
+        assert_eq!(pid.update(90.0, 1.0), 1.0);
+        let mut pid = Pid::new(settings(), 0.2, 1.0);
+        assert_eq!(pid.update(30.0, 1.0), 0.2);
+    }
+
+    #[test]
+    fn integral_does_not_wind_up() {
+        // Far too hot for a long time: The output is saturated, so the integral must not keep growing.
+        let mut pid = Pid::new(settings(), 0.2, 1.0);
+        for _ in 0..1000 {
+            assert_eq!(pid.update(80.0, 1.0), 1.0);
+        }
+        assert!(pid.integral <= 1.0);
+
+        // Once the temperature drops below the target, the output must leave saturation right away instead of unwinding first.
+        assert!(pid.update(55.0, 1.0) < 1.0);
+    }
+
+    #[test]
+    fn reverse_acting() {
+        let mut pid = Pid::reverse(PidSettings { target: 2000.0, kp: 0.001, ki: 0.0, kd: 0.0 }, 0.0, 1.0);
+        assert!((pid.update(1500.0, 1.0) - 0.5).abs() < 1e-6);
+        assert_eq!(pid.update(2500.0, 1.0), 0.0);
+    }
+
+    #[test]
+    fn reset_clears_the_state() {
+        let mut pid = Pid::new(PidSettings { kd: 1.0, ..settings() }, 0.0, 1.0);
+        pid.update(65.0, 1.0);
+        pid.reset();
+        assert_eq!(pid.integral, 0.0);
+        assert_eq!(pid.prev_value, None);
+    }
+}
@@ -0,0 +1,181 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+
+// Rotation of a file that grows without bound, i.e. the log file and the statistics file.
+// The file is renamed to <file>.1 (<file>.1.gz if compressed), older files are renamed to <file>.2, <file>.3 and so on.
+// Since the files are opened for every write, the next write simply creates a new file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Rotation {
+    pub max_size: u64, // ... Size in bytes at which the file is rotated, 0 to disable.
+    pub max_age: u64, // .... Age in s at which the file is rotated, 0 to disable.
+    pub keep: u32, // ....... Number of rotated files that are kept, 0 to delete the file instead.
+    pub compress: bool, // .. Whether rotated files are compressed with gzip.
+}
+
+impl Rotation {
+
+    // Check if the file has to be rotated.
+    // The age is determined by the creation time of the file. If the file system does not provide it, the file is only rotated by size.
+    fn due(&self, path: &Path) -> bool {
+        let Ok(metadata) = fs::metadata(path) else {
+            return false;
+        };
+        let too_large = self.max_size > 0 && metadata.len() >= self.max_size;
+        let too_old = self.max_age > 0 && metadata.created().ok()
+            .and_then(|created| created.elapsed().ok())
+            .is_some_and(|age| age.as_secs() >= self.max_age);
+        too_large || too_old
+    }
+
+
+    // Rotate the file if it is too large or too old.
+    // Returns whether the file was rotated or an error message.
+    pub fn rotate(&self, file_path: &str) -> Result<bool, String> {
+        let path = Path::new(file_path);
+        if !self.due(path) {
+            return Ok(false);
+        }
+        let generation = |n: u32, gz: bool| format!("{}.{}{}", file_path, n, if gz {".gz"} else {""});
+
+        // Without generations to keep, the file is simply deleted.
+        if self.keep == 0 {
+            fs::remove_file(path).map_err(|e| format!("Failed to delete {}: {}", file_path, e))?;
+            return Ok(true);
+        }
+
+        // Delete the oldest generation and every generation beyond it (e.g. if the number of generations was reduced).
+        for n in self.keep.. {
+            let files: Vec<String> = [generation(n, false), generation(n, true)].into_iter().filter(|file| Path::new(file).exists()).collect();
+            if files.is_empty() && n > self.keep {
+                break;
+            }
+            for file in files {
+                fs::remove_file(&file).map_err(|e| format!("Failed to delete {}: {}", file, e))?;
+            }
+        }
+
+        // Move every other generation one up. Both compressed and uncompressed files are moved, since compression may have been changed.
+        for n in (1..self.keep).rev() {
+            for gz in [false, true] {
+                if Path::new(&generation(n, gz)).exists() {
+                    fs::rename(generation(n, gz), generation(n + 1, gz))
+                        .map_err(|e| format!("Failed to move {} to {}: {}", generation(n, gz), generation(n + 1, gz), e))?;
+                }
+            }
+        }
+
+        // Move the current file and compress it if requested.
+        fs::rename(path, generation(1, false)).map_err(|e| format!("Failed to move {} to {}: {}", file_path, generation(1, false), e))?;
+        if self.compress {
+            compress(&generation(1, false), &generation(1, true)).map_err(|e| format!("Failed to compress {}: {}", generation(1, false), e))?;
+            fs::remove_file(generation(1, false)).map_err(|e| format!("Failed to delete {}: {}", generation(1, false), e))?;
+        }
+        Ok(true)
+    }
+}
+
+
+// Compress a file with gzip.
+fn compress(source: &str, target: &str) -> io::Result<()> {
+    let mut input = File::open(source)?;
+    let mut encoder = GzEncoder::new(File::create(target)?, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::env;
+    use std::io::Read;
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration;
+
+    // Empty directory of its own for every test.
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rpi_fanctrl_rotate_{}_{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let dir = temp_dir("size");
+        let file = dir.join("event.log").display().to_string();
+        let rotation = Rotation { max_size: 10, keep: 3, ..Default::default() };
+
+        fs::write(&file, "short").unwrap();
+        assert_eq!(rotation.rotate(&file), Ok(false));
+        fs::write(&file, "long enough to rotate").unwrap();
+        assert_eq!(rotation.rotate(&file), Ok(true));
+        assert!(!Path::new(&file).exists());
+        assert_eq!(fs::read_to_string(format!("{}.1", file)).unwrap(), "long enough to rotate");
+
+        // A missing file is not rotated.
+        assert_eq!(rotation.rotate(&file), Ok(false));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rotates_by_age() {
+        let dir = temp_dir("age");
+        let file = dir.join("event.log").display().to_string();
+        fs::write(&file, "old").unwrap();
+        // Skip the test if the file system does not provide the creation time.
+        if fs::metadata(&file).unwrap().created().is_err() {
+            return fs::remove_dir_all(dir).unwrap();
+        }
+        let rotation = Rotation { max_age: 1, keep: 1, ..Default::default() };
+        assert_eq!(rotation.rotate(&file), Ok(false));
+        thread::sleep(Duration::from_millis(1100));
+        assert_eq!(rotation.rotate(&file), Ok(true));
+        assert_eq!(fs::read_to_string(format!("{}.1", file)).unwrap(), "old");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_only_the_newest_generations() {
+        let dir = temp_dir("keep");
+        let file = dir.join("stats.csv").display().to_string();
+        for n in 1..=4 {
+            fs::write(format!("{}.{}", file, n), format!("generation {}", n)).unwrap();
+        }
+        fs::write(&file, "current").unwrap();
+
+        let rotation = Rotation { max_size: 1, keep: 2, ..Default::default() };
+        assert_eq!(rotation.rotate(&file), Ok(true));
+        assert_eq!(fs::read_to_string(format!("{}.1", file)).unwrap(), "current");
+        assert_eq!(fs::read_to_string(format!("{}.2", file)).unwrap(), "generation 1");
+        assert!(!Path::new(&format!("{}.3", file)).exists() && !Path::new(&format!("{}.4", file)).exists());
+
+        // Without generations to keep, the file is deleted.
+        fs::write(&file, "current").unwrap();
+        assert_eq!(Rotation { max_size: 1, keep: 0, ..Default::default() }.rotate(&file), Ok(true));
+        assert!(!Path::new(&file).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn compresses_rotated_files() {
+        let dir = temp_dir("gzip");
+        let file = dir.join("event.log").display().to_string();
+        fs::write(&file, "compressed").unwrap();
+
+        let rotation = Rotation { max_size: 1, keep: 2, compress: true, ..Default::default() };
+        assert_eq!(rotation.rotate(&file), Ok(true));
+        assert!(!Path::new(&format!("{}.1", file)).exists());
+        let mut content = String::new();
+        GzDecoder::new(File::open(format!("{}.1.gz", file)).unwrap()).read_to_string(&mut content).unwrap();
+        assert_eq!(content, "compressed");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,521 @@
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+// Time a command source may take to output the temperature before it is killed and the reading counts as failed.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(2);
+
+
+// Temperature source.
+// Every sensor the fan can be controlled by implements this trait.
+pub trait TemperatureSource {
+
+    // Read the current temperature in degree C. Returns none if no valid temperature could be read.
+    fn read(&mut self) -> Option<f32>;
+
+    // Describe the source for log messages.
+    fn name(&self) -> String;
+}
+
+
+// Round a temperature to two decimals.
+fn round_temp(temp: f32) -> f32 {
+    (temp * 100.0).round() / 100.0
+}
+
+
+// Read a number from a file and scale it to degree C.
+fn read_scaled(path: &PathBuf, scale: f32) -> Option<f32> {
+    let contents = fs::read_to_string(path).ok()?;
+    let value = contents.trim().parse::<f32>().ok()?;
+    Some(round_temp(value * scale))
+}
+
+
+// Thermal zone of the kernel (/sys/class/thermal/thermal_zone<n>/temp).
+// The values are in millidegree C. Zone 0 is the SoC on the Raspberry Pi.
+pub struct ThermalZone {
+    path: PathBuf,
+}
+
+impl TemperatureSource for ThermalZone {
+    fn read(&mut self) -> Option<f32> {
+        read_scaled(&self.path, 0.001)
+    }
+
+    fn name(&self) -> String {
+        self.path.display().to_string()
+    }
+}
+
+
+// hwmon sensor of the kernel (/sys/class/hwmon/hwmon<n>/temp<input>_input).
+// The hwmon device is identified by its name (e.g. "nvme" or "cpu_thermal") or by its directory (e.g. "hwmon2").
+// Since the numbering of the hwmon devices can change between boots, the device is looked up by name until it is found.
+// If a reading fails (e.g. the device was removed and came back under another number), it is looked up again at the next reading.
+// The values are in millidegree C.
+pub struct Hwmon {
+    device: String,
+    input: u32,
+    path: Option<PathBuf>,
+}
+
+impl Hwmon {
+    // Find the temp<input>_input file of the hwmon device.
+    fn find(&self) -> Option<PathBuf> {
+        for entry in fs::read_dir("/sys/class/hwmon").ok()?.flatten() {
+            let dir = entry.path();
+            let name = fs::read_to_string(dir.join("name")).unwrap_or_default();
+            if name.trim() == self.device || entry.file_name().to_string_lossy() == self.device {
+                let path = dir.join(format!("temp{}_input", self.input));
+                if path.exists() {
+                    return Some(path);
+                }
+            }
+        }
+        None
+    }
+}
+
+impl TemperatureSource for Hwmon {
+    fn read(&mut self) -> Option<f32> {
+        if self.path.is_none() {
+            self.path = self.find();
+        }
+        let temp = read_scaled(self.path.as_ref()?, 0.001);
+        if temp.is_none() {
+            self.path = None;
+        }
+        temp
+    }
+
+    fn name(&self) -> String {
+        format!("hwmon {} temp{}", self.device, self.input)
+    }
+}
+
+
+// Any file containing a single number, e.g. a case sensor exposed by a driver.
+// The number is multiplied by the scale factor to get degree C.
+pub struct FileSource {
+    path: PathBuf,
+    scale: f32,
+}
+
+impl TemperatureSource for FileSource {
+    fn read(&mut self) -> Option<f32> {
+        read_scaled(&self.path, self.scale)
+    }
+
+    fn name(&self) -> String {
+        self.path.display().to_string()
+    }
+}
+
+
+// Output of a command, e.g. "vcgencmd measure_temp" or "smartctl".
+// The temperature in degree C is taken from the output of the command (see temperature_in).
+// The command is run by sh and blocks the control loop, so it is killed if it does not finish within COMMAND_TIMEOUT.
+pub struct CommandSource {
+    command: String,
+}
+
+impl TemperatureSource for CommandSource {
+    fn read(&mut self) -> Option<f32> {
+        let mut child = Command::new("sh").arg("-c").arg(&self.command)
+            .stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::null())
+            .spawn().ok()?;
+        let deadline = Instant::now() + COMMAND_TIMEOUT;
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+                _ => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return None;
+                }
+            }
+        };
+        if !status.success() {
+            return None;
+        }
+        let mut stdout = String::new();
+        child.stdout.take()?.read_to_string(&mut stdout).ok()?;
+        let value = temperature_in(&stdout)?;
+        Some(round_temp(value))
+    }
+
+    fn name(&self) -> String {
+        format!("command '{}'", self.command)
+    }
+}
+
+
+// Find the temperature in the output of a command.
+// The first number followed by a unit of degree C (°C, 'C, C or Celsius, optionally after a space) is used,
+// e.g. 48.3 in "temp=48.3'C" or 45.0 in "Core 0: +45.0°C  (high = +80.0°C)". If no number is followed by a unit, the last number is used,
+// e.g. 38 in "Temperature of nvme0: 38". Numbers may have a sign and decimals.
+fn temperature_in(text: &str) -> Option<f32> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut last: Option<f32> = None;
+    let mut i = 0;
+    while i < chars.len() {
+        let signed = (chars[i] == '-' || chars[i] == '+') && chars.get(i + 1).is_some_and(char::is_ascii_digit);
+        if !signed && !chars[i].is_ascii_digit() {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        i += 1;
+        while i < chars.len() && (chars[i].is_ascii_digit() || (chars[i] == '.' && chars.get(i + 1).is_some_and(char::is_ascii_digit))) {
+            i += 1;
+        }
+        let Ok(number) = chars[start..i].iter().collect::<String>().parse::<f32>() else {
+            continue;
+        };
+        // A single C only counts as a unit if it is not the start of a word (e.g. not "0 Current").
+        let unit: String = chars[i..].iter().skip_while(|c| **c == ' ').take(8).collect();
+        let celsius = unit.starts_with("Celsius") || (unit.starts_with('C') && !unit.chars().nth(1).is_some_and(char::is_alphabetic));
+        if unit.starts_with(['°', '\'', '℃']) || celsius {
+            return Some(number);
+        }
+        last = Some(number);
+    }
+    last
+}
+
+
+// Definition of a temperature source as it is written in the configuration.
+// Possible formats:
+// thermal_zone:<n> ............. Thermal zone n, e.g. "thermal_zone:0".
+// hwmon:<device>:<input> ....... Input of a hwmon device, e.g. "hwmon:nvme:1".
+// file:<path>:<scale> .......... Number in a file multiplied by scale, e.g. "file:/run/case_temp:0.001". The scale is optional (default 1.0).
+// command:<command> ............ Temperature in the output of a command (see temperature_in), e.g. "command:vcgencmd measure_temp".
+#[derive(Clone, Debug, PartialEq)]
+pub enum SourceSpec {
+    ThermalZone(u32),
+    Hwmon(String, u32),
+    File(PathBuf, f32),
+    Command(String),
+}
+
+impl SourceSpec {
+    // Create the temperature source.
+    pub fn open(&self) -> Box<dyn TemperatureSource> {
+        match self {
+            SourceSpec::ThermalZone(zone) => Box::new(ThermalZone { path: PathBuf::from(format!("/sys/class/thermal/thermal_zone{}/temp", zone)) }),
+            SourceSpec::Hwmon(device, input) => Box::new(Hwmon { device: device.clone(), input: *input, path: None }),
+            SourceSpec::File(path, scale) => Box::new(FileSource { path: path.clone(), scale: *scale }),
+            SourceSpec::Command(command) => Box::new(CommandSource { command: command.clone() }),
+        }
+    }
+}
+
+impl Default for SourceSpec {
+    fn default() -> Self {
+        SourceSpec::ThermalZone(0)
+    }
+}
+
+impl FromStr for SourceSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (kind, args) = spec.trim().split_once(':').unwrap_or((spec.trim(), ""));
+        match kind {
+            "thermal_zone" => args.parse::<u32>()
+                .map(SourceSpec::ThermalZone)
+                .map_err(|_| "thermal_zone requires the number of the zone, e.g. thermal_zone:0".to_string()),
+            "hwmon" => {
+                let (device, input) = args.rsplit_once(':').unwrap_or((args, "1"));
+                let input = input.parse::<u32>().map_err(|_| "hwmon requires the device and the input number, e.g. hwmon:nvme:1".to_string())?;
+                if device.is_empty() {
+                    return Err("hwmon requires the device and the input number, e.g. hwmon:nvme:1".to_string());
+                }
+                Ok(SourceSpec::Hwmon(device.to_string(), input))
+            }
+            "file" => {
+                // The scale is optional, so only split it off if the last part is a number.
+                let (path, scale) = match args.rsplit_once(':') {
+                    Some((path, scale)) if scale.parse::<f32>().is_ok() => (path, scale.parse::<f32>().unwrap_or(1.0)),
+                    _ => (args, 1.0),
+                };
+                if path.is_empty() {
+                    return Err("file requires a path, e.g. file:/run/case_temp:0.001".to_string());
+                }
+                Ok(SourceSpec::File(PathBuf::from(path), scale))
+            }
+            "command" if !args.trim().is_empty() => Ok(SourceSpec::Command(args.trim().to_string())),
+            "command" => Err("command requires a command, e.g. command:vcgencmd measure_temp".to_string()),
+            _ => Err(format!("unknown temperature source '{}', must be either thermal_zone, hwmon, file or command", kind)),
+        }
+    }
+}
+
+impl fmt::Display for SourceSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceSpec::ThermalZone(zone) => write!(f, "thermal_zone:{}", zone),
+            SourceSpec::Hwmon(device, input) => write!(f, "hwmon:{}:{}", device, input),
+            SourceSpec::File(path, scale) => write!(f, "file:{}:{}", path.display(), scale),
+            SourceSpec::Command(command) => write!(f, "command:{}", command),
+        }
+    }
+}
+
+
+// Definition of a single sensor used for fan control.
+// The offset is added to every reading (e.g. to correct a sensor that reads too high).
+// The weight is used by the weighted average, min_temp and max_temp define the own curve of the sensor used by the normalized aggregation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SensorSpec {
+    pub name: String,
+    pub source: SourceSpec,
+    pub weight: f32,
+    pub offset: f32,
+    pub min_temp: f32,
+    pub max_temp: f32,
+}
+
+
+// How the readings of several sensors are combined into a single temperature.
+// max .......... The highest reading of all sensors.
+// weighted ..... The weighted average of all readings.
+// normalized ... Each reading is normalized to the own curve (min_temp to max_temp) of its sensor.
+//                The highest normalized value is then mapped to the global curve (min_temp to max_temp).
+//                E.g. a drive at the maximum temperature of its own curve results in the global max_temp, even if the drive runs much hotter than the CPU.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aggregation {
+    Max,
+    Weighted,
+    Normalized,
+}
+
+impl FromStr for Aggregation {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode.trim().to_lowercase().as_str() {
+            "max" => Ok(Aggregation::Max),
+            "weighted" => Ok(Aggregation::Weighted),
+            "normalized" => Ok(Aggregation::Normalized),
+            _ => Err(format!("unknown aggregation '{}', must be either max, weighted or normalized", mode)),
+        }
+    }
+}
+
+impl fmt::Display for Aggregation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Aggregation::Max => write!(f, "max"),
+            Aggregation::Weighted => write!(f, "weighted"),
+            Aggregation::Normalized => write!(f, "normalized"),
+        }
+    }
+}
+
+
+// Group of all sensors used for fan control.
+pub struct SensorGroup {
+    specs: Vec<SensorSpec>,
+    sources: Vec<Box<dyn TemperatureSource>>,
+    aggregation: Aggregation,
+    min_temp: f32,
+    max_temp: f32,
+    pub readings: Vec<Option<f32>>, // ... Latest reading of each sensor including the offset.
+}
+
+impl SensorGroup {
+
+    // Create the temperature sources of all sensors.
+    pub fn open(specs: &[SensorSpec], aggregation: Aggregation, min_temp: f32, max_temp: f32) -> SensorGroup {
+        SensorGroup {
+            specs: specs.to_vec(),
+            sources: specs.iter().map(|spec| spec.source.open()).collect(),
+            aggregation,
+            min_temp,
+            max_temp,
+            readings: vec![None; specs.len()],
+        }
+    }
+
+
+    // Names of all sensors.
+    pub fn names(&self) -> Vec<String> {
+        self.specs.iter().map(|spec| spec.name.clone()).collect()
+    }
+
+
+    // Describe the sensors for log messages.
+    pub fn describe(&self) -> String {
+        self.specs.iter().zip(&self.sources)
+            .map(|(spec, source)| format!("{} ({})", spec.name, source.name()))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+
+    // Describe the sensors without a valid reading for log messages.
+    pub fn failed(&self) -> String {
+        self.specs.iter().zip(&self.sources).zip(&self.readings)
+            .filter(|(_, reading)| reading.is_none())
+            .map(|((spec, source), _)| format!("{} ({})", spec.name, source.name()))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+
+    // Read all sensors and combine the readings into a single temperature.
+    // Sensors without a valid reading are left out. If no sensor has a valid reading, none is returned.
+    pub fn read(&mut self) -> Option<f32> {
+        for ((spec, source), reading) in self.specs.iter().zip(self.sources.iter_mut()).zip(self.readings.iter_mut()) {
+            *reading = source.read().map(|temp| temp + spec.offset);
+        }
+
+        let valid: Vec<(&SensorSpec, f32)> = self.specs.iter().zip(&self.readings)
+            .filter_map(|(spec, reading)| reading.map(|temp| (spec, temp)))
+            .collect();
+        if valid.is_empty() {
+            return None;
+        }
+
+        let temp = match self.aggregation {
+            Aggregation::Max => valid.iter().map(|&(_, temp)| temp).fold(f32::MIN, f32::max),
+            Aggregation::Weighted => {
+                let weight_sum: f32 = valid.iter().map(|&(spec, _)| spec.weight).sum();
+                if weight_sum > 0.0 {
+                    valid.iter().map(|&(spec, temp)| temp * spec.weight).sum::<f32>() / weight_sum
+                } else {
+                    valid.iter().map(|&(_, temp)| temp).sum::<f32>() / valid.len() as f32
+                }
+            }
+            Aggregation::Normalized => {
+                let normalized = valid.iter()
+                    .map(|&(spec, temp)| if spec.max_temp > spec.min_temp {
+                        (temp - spec.min_temp) / (spec.max_temp - spec.min_temp)
+                    } else if temp >= spec.max_temp {1.0} else {0.0})
+                    .fold(f32::MIN, f32::max);
+                self.min_temp + normalized * (self.max_temp - self.min_temp)
+            }
+        };
+        Some(round_temp(temp))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Source that always returns the same reading.
+    struct Fixed(Option<f32>);
+
+    impl TemperatureSource for Fixed {
+        fn read(&mut self) -> Option<f32> {
+            self.0
+        }
+
+        fn name(&self) -> String {
+            "fixed".to_string()
+        }
+    }
+
+    // Group of sensors with the readings, weights and own curves (min_temp, max_temp) given, and the global curve from 40 to 80 degree C.
+    fn group(aggregation: Aggregation, sensors: &[(Option<f32>, f32, f32, f32)]) -> SensorGroup {
+        SensorGroup {
+            specs: sensors.iter().enumerate().map(|(i, &(_, weight, min_temp, max_temp))| SensorSpec {
+                name: format!("sensor{}", i), source: SourceSpec::default(), weight, offset: 0.0, min_temp, max_temp,
+            }).collect(),
+            sources: sensors.iter().map(|&(reading, ..)| Box::new(Fixed(reading)) as Box<dyn TemperatureSource>).collect(),
+            aggregation,
+            min_temp: 40.0,
+            max_temp: 80.0,
+            readings: vec![None; sensors.len()],
+        }
+    }
+
+    #[test]
+    fn max_aggregation() {
+        let mut sensors = group(Aggregation::Max, &[(Some(50.0), 1.0, 0.0, 0.0), (Some(62.5), 1.0, 0.0, 0.0), (None, 1.0, 0.0, 0.0)]);
+        assert_eq!(sensors.read(), Some(62.5));
+        assert_eq!(sensors.readings, [Some(50.0), Some(62.5), None]);
+        assert_eq!(sensors.failed(), "sensor2 (fixed)");
+    }
+
+    #[test]
+    fn weighted_aggregation() {
+        let mut sensors = group(Aggregation::Weighted, &[(Some(50.0), 3.0, 0.0, 0.0), (Some(70.0), 1.0, 0.0, 0.0)]);
+        assert_eq!(sensors.read(), Some(55.0));
+
+        // A failed sensor is left out, including its weight.
+        let mut sensors = group(Aggregation::Weighted, &[(Some(50.0), 3.0, 0.0, 0.0), (None, 1.0, 0.0, 0.0), (Some(70.0), 1.0, 0.0, 0.0)]);
+        assert_eq!(sensors.read(), Some(55.0));
+
+        // Without weights, the plain average is used.
+        let mut sensors = group(Aggregation::Weighted, &[(Some(50.0), 0.0, 0.0, 0.0), (Some(70.0), 0.0, 0.0, 0.0)]);
+        assert_eq!(sensors.read(), Some(60.0));
+    }
+
+    #[test]
+    fn normalized_aggregation() {
+        // The CPU is at 25 percent of its curve, the drive at 75 percent of its own, which is 70 degree C on the global curve.
+        let mut sensors = group(Aggregation::Normalized, &[(Some(50.0), 1.0, 40.0, 80.0), (Some(55.0), 1.0, 40.0, 60.0)]);
+        assert_eq!(sensors.read(), Some(70.0));
+
+        let mut sensors = group(Aggregation::Normalized, &[(Some(50.0), 1.0, 40.0, 80.0), (None, 1.0, 40.0, 60.0)]);
+        assert_eq!(sensors.read(), Some(50.0));
+    }
+
+    #[test]
+    fn no_valid_reading() {
+        let mut sensors = group(Aggregation::Max, &[(None, 1.0, 0.0, 0.0), (None, 1.0, 0.0, 0.0)]);
+        assert_eq!(sensors.read(), None);
+        assert_eq!(sensors.failed(), "sensor0 (fixed), sensor1 (fixed)");
+    }
+
+    #[test]
+    fn hwmon_is_looked_up_again_after_a_failed_reading() {
+        let mut source = Hwmon { device: "rpi_fanctrl_test".to_string(), input: 1, path: Some(PathBuf::from("/nonexistent/temp1_input")) };
+        assert_eq!(source.read(), None);
+        assert_eq!(source.path, None);
+    }
+
+    #[test]
+    fn temperature_in_prefers_number_with_unit() {
+        assert_eq!(temperature_in("temp=48.3'C"), Some(48.3));
+        assert_eq!(temperature_in("Core 0: +45.0°C  (high = +80.0°C, crit = +100.0°C)"), Some(45.0));
+        assert_eq!(temperature_in("Core 0 Current: 51 C"), Some(51.0));
+        assert_eq!(temperature_in("Temperature: 38 Celsius"), Some(38.0));
+        assert_eq!(temperature_in("-5.5℃"), Some(-5.5));
+    }
+
+    #[test]
+    fn temperature_in_falls_back_to_last_number() {
+        assert_eq!(temperature_in("Temperature of nvme0: 38"), Some(38.0));
+        assert_eq!(temperature_in("42\n"), Some(42.0));
+        assert_eq!(temperature_in("no temperature"), None);
+    }
+
+    #[test]
+    fn command_source_reads_output() {
+        let mut source = CommandSource { command: "echo 'temp=48.3'\\''C'".to_string() };
+        assert_eq!(source.read(), Some(48.3));
+        let mut failing = CommandSource { command: "echo 40; exit 1".to_string() };
+        assert_eq!(failing.read(), None);
+    }
+
+    #[test]
+    fn command_source_is_killed_after_timeout() {
+        let mut source = CommandSource { command: "sleep 10; echo 40".to_string() };
+        let start = Instant::now();
+        assert_eq!(source.read(), None);
+        assert!(start.elapsed() < COMMAND_TIMEOUT + Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,186 @@
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::functions::{log_event, Level};
+
+
+// Settings of the stall detection.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StallSettings {
+    pub min_rpm: f32, // ........ Below this speed, a running fan is considered stalled.
+    pub max_rpm: f32, // ........ Speed of the fan at full power. Used to calculate the expected speed for the duty cycle (0 = unknown).
+    pub ratio: f32, // .......... A running fan is considered failing if its speed is below this share of the expected speed.
+    pub grace: Duration, // ..... How long the fan may be too slow before it is considered stalled.
+    pub kick: Duration, // ...... How long the fan is run at full power to get it spinning again.
+    pub retries: u32, // ........ How many kicks are tried before the fan is considered failed.
+    pub alert: String, // ....... Command that is run when the fan failed (empty = none).
+}
+
+
+// State of the fan as seen by the stall detection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum StallState {
+    Ok,
+    Slow(Instant), // ...... Too slow since the given time point, but still within the grace period.
+    Kicking(Instant), // ... Running at full power until the given time point.
+    Failed, // ............. All kicks failed. The fan is kept at full power until it recovers.
+}
+
+
+// Stall detection.
+// Compares the measured fan speed with the expected speed for the commanded duty cycle.
+// If the fan is too slow for longer than the grace period, it is kicked at full power. If all kicks fail, the alert command is run.
+pub struct StallDetector {
+    settings: StallSettings,
+    state: StallState,
+    kicks: u32,
+}
+
+impl StallDetector {
+
+    pub fn new(settings: StallSettings) -> StallDetector {
+        StallDetector { settings, state: StallState::Ok, kicks: 0 }
+    }
+
+
+    // Expected minimum speed for a duty cycle.
+    fn expected_rpm(&self, duty_cycle: f32) -> f32 {
+        (self.settings.max_rpm * duty_cycle * self.settings.ratio).max(self.settings.min_rpm)
+    }
+
+
+    // Check the measured fan speed against the commanded duty cycle (0.0 if the fan is supposed to be off).
+    // Returns the duty cycle that overrides the commanded one while the fan is kicked or failed, otherwise none.
+    pub fn update(&mut self, rpm: Option<f32>, duty_cycle: f32, log_option: bool) -> Option<f32> {
+
+        // Without a measurement or while the fan is supposed to be off, there is nothing to check.
+        let Some(rpm) = rpm else {return self.override_duty()};
+        if duty_cycle <= 0.0 && matches!(self.state, StallState::Ok | StallState::Slow(_)) {
+            self.state = StallState::Ok;
+            return None;
+        }
+
+        let too_slow = rpm < self.expected_rpm(duty_cycle.max(0.0));
+        self.state = match self.state {
+            StallState::Ok if too_slow => StallState::Slow(Instant::now()),
+            StallState::Ok => StallState::Ok,
+
+            // The fan recovered within the grace period.
+            StallState::Slow(_) if !too_slow => {
+                self.kicks = 0;
+                StallState::Ok
+            }
+
+            // The fan is too slow for longer than the grace period. Kick it or give up.
+            StallState::Slow(since) if since.elapsed() >= self.settings.grace => {
+                if self.kicks < self.settings.retries {
+                    self.kicks += 1;
+                    log_event(Level::Warn, &format!("Fan stalled ({:.0} RPM at {:.0} % duty cycle, expected at least {:.0} RPM). Kicking the fan at full power (attempt {} of {}).",
+                        rpm, duty_cycle * 100.0, self.expected_rpm(duty_cycle), self.kicks, self.settings.retries), log_option);
+                    StallState::Kicking(Instant::now() + self.settings.kick)
+                } else {
+                    log_event(Level::Error, &format!("Fan failed ({:.0} RPM at {:.0} % duty cycle) after {} kicks. Keeping the fan at full power.", rpm, duty_cycle * 100.0, self.kicks), log_option);
+                    run_alert(&self.settings.alert, rpm, duty_cycle, log_option);
+                    StallState::Failed
+                }
+            }
+            StallState::Slow(since) => StallState::Slow(since),
+
+            // After the kick, the grace period starts again so that the measured speed can settle.
+            StallState::Kicking(until) if Instant::now() >= until => StallState::Slow(Instant::now()),
+            StallState::Kicking(until) => StallState::Kicking(until),
+
+            // The fan is spinning again at full power.
+            StallState::Failed if rpm >= self.expected_rpm(1.0) => {
+                log_event(Level::Info, &format!("Fan recovered ({:.0} RPM).", rpm), log_option);
+                self.kicks = 0;
+                StallState::Ok
+            }
+            StallState::Failed => StallState::Failed,
+        };
+
+        self.override_duty()
+    }
+
+
+    // While the fan is kicked or failed, it is run at full power.
+    fn override_duty(&self) -> Option<f32> {
+        match self.state {
+            StallState::Kicking(_) | StallState::Failed => Some(1.0),
+            _ => None,
+        }
+    }
+}
+
+
+// Run the alert command in the background.
+// The measured speed and the commanded duty cycle are passed as environmental variables.
+fn run_alert(alert: &str, rpm: f32, duty_cycle: f32, log_option: bool) {
+    if alert.trim().is_empty() {
+        return;
+    }
+    match Command::new("sh").arg("-c").arg(alert)
+        .env("FAN_RPM", format!("{:.0}", rpm))
+        .env("FAN_DUTY_CYCLE", format!("{:.2}", duty_cycle))
+        .spawn() {
+        // Wait for the command in a separate thread so that the control loop is not blocked and no zombie process is left.
+        Ok(mut child) => {thread::spawn(move || child.wait());}
+        Err(e) => log_event(Level::Warn, &format!("Failed to run 'stall_alert': {}", e), log_option),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(grace: Duration) -> StallSettings {
+        StallSettings { min_rpm: 300.0, max_rpm: 3000.0, ratio: 0.5, grace, kick: Duration::ZERO, retries: 1, alert: String::new() }
+    }
+
+    #[test]
+    fn slow_fan_is_kicked_and_fails_after_the_retries() {
+        let mut stall = StallDetector::new(settings(Duration::ZERO));
+        assert_eq!(stall.update(Some(2000.0), 0.5, false), None);
+        assert_eq!(stall.state, StallState::Ok);
+
+        // Too slow: The grace period starts, then the fan is kicked.
+        assert_eq!(stall.update(Some(100.0), 0.5, false), None);
+        assert!(matches!(stall.state, StallState::Slow(_)));
+        assert_eq!(stall.update(Some(100.0), 0.5, false), Some(1.0));
+        assert!(matches!(stall.state, StallState::Kicking(_)));
+        assert_eq!(stall.kicks, 1);
+
+        // After the kick, the grace period starts again. With no retries left, the fan failed.
+        assert_eq!(stall.update(Some(100.0), 0.5, false), None);
+        assert!(matches!(stall.state, StallState::Slow(_)));
+        assert_eq!(stall.update(Some(100.0), 0.5, false), Some(1.0));
+        assert_eq!(stall.state, StallState::Failed);
+
+        // The fan is kept at full power, even without a measurement, until it recovers.
+        assert_eq!(stall.update(None, 0.5, false), Some(1.0));
+        assert_eq!(stall.update(Some(1000.0), 0.0, false), Some(1.0));
+        assert_eq!(stall.update(Some(2900.0), 0.5, false), None);
+        assert_eq!(stall.state, StallState::Ok);
+        assert_eq!(stall.kicks, 0);
+    }
+
+    #[test]
+    fn fan_recovers_within_the_grace_period() {
+        let mut stall = StallDetector::new(settings(Duration::from_secs(60)));
+        assert_eq!(stall.update(Some(500.0), 0.5, false), None);
+        assert!(matches!(stall.state, StallState::Slow(_)));
+        assert_eq!(stall.update(Some(500.0), 0.5, false), None);
+        assert!(matches!(stall.state, StallState::Slow(_)));
+        assert_eq!(stall.update(Some(800.0), 0.5, false), None);
+        assert_eq!(stall.state, StallState::Ok);
+    }
+
+    #[test]
+    fn fan_that_is_off_is_not_checked() {
+        let mut stall = StallDetector::new(settings(Duration::ZERO));
+        assert_eq!(stall.update(Some(100.0), 0.5, false), None);
+        assert_eq!(stall.update(Some(0.0), 0.0, false), None);
+        assert_eq!(stall.state, StallState::Ok);
+    }
+}
@@ -0,0 +1,48 @@
+use std::time::SystemTime;
+
+
+// Runtime state of the control loop.
+// Kept separately from the configuration (see Config), so that it survives a reload.
+pub struct RuntimeState {
+    pub i: u64, // ......................... Counts the total loops of the main function (i.e. +=1 with every loop cycle).
+                                          // Together with stat_cycle and temp_cycle used to determine when temperatures should be read and when statistics should be calculated.
+    pub i_stat: u64, // .................... Used to count how often statistics where calculated. Used to calculate the on time of the fan.
+    pub n_on_fan: f32, // .................. Used to count how often the fan was running when calculating statistics.
+                                          // Average fan speed is only calculated for the number of times the fan was running.
+    pub n_on_temp: f32, // ................. Used to count the number of valid temperature readings for statistics. Average is only calculated for the number of valid readings.
+    pub duty_cycle: f32, // ................ Calculated fan speed.
+    pub duty_cycle_old: f32, // ............ Fan speed of the previous cycle.
+    pub cpu_temp_min: Option<f32>,
+    pub cpu_temp_max: Option<f32>,
+    pub cpu_temp_avg: Option<f32>,
+    pub fan_state_stat: f32, // ............ Share of the statistics period the fan was running.
+    pub fan_state_prev: bool,
+    pub fan_speed_avg: Option<f32>,
+    pub fan_speed_min: Option<f32>,
+    pub fan_speed_max: Option<f32>,
+    pub stat_time_start: SystemTime, // .... Start of the statistics period.
+}
+
+impl RuntimeState {
+
+    // Initial state. The fan speed is set to the given duty cycle (the max duty cycle to initially keep the fan at full power).
+    pub fn new(duty_cycle: f32) -> RuntimeState {
+        RuntimeState {
+            i: 1,
+            i_stat: 1,
+            n_on_fan: 0.0,
+            n_on_temp: 0.0,
+            duty_cycle,
+            duty_cycle_old: duty_cycle,
+            cpu_temp_min: None,
+            cpu_temp_max: None,
+            cpu_temp_avg: None,
+            fan_state_stat: 1.0,
+            fan_state_prev: false,
+            fan_speed_avg: None,
+            fan_speed_min: None,
+            fan_speed_max: None,
+            stat_time_start: SystemTime::now(),
+        }
+    }
+}
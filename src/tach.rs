@@ -0,0 +1,73 @@
+use rppal::gpio::{Gpio, InputPin, Trigger};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::functions::{log_event, Level};
+
+
+// Minimum duration over which pulses are counted before the fan speed is recalculated.
+// Shorter windows contain too few pulses at low fan speeds for a stable value.
+const TACH_WINDOW: Duration = Duration::from_secs(1);
+
+
+// Tachometer.
+// The tach wire of the fan pulls the GPIO pin low a fixed number of times per revolution (pulses per revolution).
+// The pulses are counted by an interrupt callback of rppal, which runs in a separate thread.
+pub struct Tachometer {
+    _pin: InputPin, // ... Kept so that the interrupt stays registered.
+    pulses: Arc<AtomicU64>,
+    ppr: u32,
+    window_pulses: u64,
+    window_start: Instant,
+    rpm: Option<f32>,
+}
+
+impl Tachometer {
+
+    // Set up the tach pin with pull-up (the tach output of most fans is open collector) and count falling edges.
+    pub fn new(gpio_pin: u8, ppr: u32) -> Result<Tachometer, String> {
+        let gpio = Gpio::new().map_err(|e| format!("Failed to initialize GPIO: {}", e))?;
+        let mut pin = gpio.get(gpio_pin).map_err(|e| format!("Failed to access GPIO pin {}: {}", gpio_pin, e))?.into_input_pullup();
+
+        let pulses = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&pulses);
+        pin.set_async_interrupt(Trigger::FallingEdge, None, move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }).map_err(|e| format!("Failed to set interrupt on GPIO pin {}: {}", gpio_pin, e))?;
+
+        Ok(Tachometer { _pin: pin, pulses, ppr: ppr.max(1), window_pulses: 0, window_start: Instant::now(), rpm: None })
+    }
+
+
+    // Measured fan speed in revolutions per minute.
+    // The value is recalculated once the measuring window has passed, otherwise the previous value is returned.
+    // Returns none until the first window has passed.
+    pub fn rpm(&mut self) -> Option<f32> {
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= TACH_WINDOW {
+            let pulses = self.pulses.load(Ordering::Relaxed);
+            let revolutions = (pulses - self.window_pulses) as f32 / self.ppr as f32;
+            self.rpm = Some(revolutions * 60.0 / elapsed.as_secs_f32());
+            self.window_pulses = pulses;
+            self.window_start = Instant::now();
+        }
+        self.rpm
+    }
+}
+
+
+// Set up the tachometer if a tach pin is defined.
+// If the tachometer cannot be set up, the fan is still controlled but its speed is not measured.
+pub fn init_tach(tach_pin: Option<u8>, ppr: u32, log_option: bool) -> Option<Tachometer> {
+    let tach_pin = tach_pin?;
+    match Tachometer::new(tach_pin, ppr) {
+        Ok(tach) => {
+            log_event(Level::Info, &format!("Measuring fan speed on GPIO pin {} ({} pulses per revolution).", tach_pin, ppr), log_option);
+            Some(tach)
+        }
+        Err(e) => {
+            log_event(Level::Warn, &format!("{}. The fan speed is not measured.", e), log_option);
+            None
+        }
+    }
+}
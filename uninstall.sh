@@ -62,6 +62,19 @@ else
     echo "Empty directory $INSTALL_DIR has been removed."
 fi
 
+# Remove the directories of the statistics and log files (default locations).
+for DATA_DIR in "/var/lib/rpi_fanctrl" "/var/log/rpi_fanctrl"; do
+    if [ -d "$DATA_DIR" ]; then
+        read -p "Do you want to remove $DATA_DIR and all its contents (such as logs and statistics)? (y/n): " user_input
+        if [[ "$user_input" =~ ^[Yy]$ ]]; then
+            echo "Removing $DATA_DIR and all its contents..."
+            sudo rm -rf "$DATA_DIR"
+        else
+            echo "Directory $DATA_DIR was not removed."
+        fi
+    fi
+done
+
 # Remove the initial directory with all included files if it was not removed in course of installation.
 if [ "$SCRIPT_DIR_REMOVED" = "false" ]; then
     if [ -d "$SCRIPT_DIR" ] && [ "$(ls -A $SCRIPT_DIR)" ]; then